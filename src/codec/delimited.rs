@@ -0,0 +1,221 @@
+use io::AsyncRead;
+use codec::memchr;
+use bytes::{Buf, BytesMut, ByteBuf};
+use futures::{Async, Poll, Sink, Stream, StartSend};
+
+use std::io::{self, Write};
+
+/// A decoder that splits the bytes read into `BytesMut` values on an
+/// arbitrary delimiter byte sequence.
+///
+/// The delimiter is consumed and, unless configured otherwise, stripped from
+/// the yielded value. A final record that is not terminated by the delimiter
+/// is yielded when the upstream reaches EOF.
+pub struct Decoder<T> {
+    // I/O type
+    inner: T,
+
+    // Configuration values
+    builder: Builder,
+
+    // Buffer
+    buf: ByteBuf,
+
+    // Number of buffered bytes already searched for the delimiter
+    searched: usize,
+
+    // True once the upstream has been shutdown
+    eof: bool,
+}
+
+pub struct Builder {
+    // Byte sequence separating records
+    delimiter: Vec<u8>,
+
+    // Include the delimiter in the yielded value
+    keep_delimiter: bool,
+
+    // Maximum record length, excluding the delimiter
+    max_frame_len: usize,
+}
+
+// Number of bytes to reserve for each read
+const READ_SIZE: usize = 1_024;
+
+/*
+ *
+ * ===== impl Decoder =====
+ *
+ */
+
+impl<T> Decoder<T> {
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T> Decoder<T> {
+    // Split the next record off the buffer, if a full record has been
+    // buffered
+    fn decode_frame(&mut self) -> io::Result<Option<BytesMut>> {
+        let delim_len = self.builder.delimiter.len();
+
+        let pos = {
+            let buf = self.buf.bytes();
+
+            memchr::find(&self.builder.delimiter, &buf[self.searched..])
+                .map(|pos| pos + self.searched)
+        };
+
+        let pos = match pos {
+            Some(pos) => pos,
+            None => {
+                // The delimiter may be split across reads, so search the tail
+                // of the buffer again once more data arrives
+                self.searched = self.buf.len().saturating_sub(delim_len - 1);
+
+                if self.buf.len() > self.builder.max_frame_len + delim_len {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "frame size too big"));
+                }
+
+                return Ok(None);
+            }
+        };
+
+        if pos > self.builder.max_frame_len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "frame size too big"));
+        }
+
+        let mut frame = self.buf.drain_to(pos + delim_len);
+        self.searched = 0;
+
+        if !self.builder.keep_delimiter {
+            frame.truncate(pos);
+        }
+
+        Ok(Some(frame))
+    }
+}
+
+impl<T: AsyncRead> Stream for Decoder<T> {
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<BytesMut>, io::Error> {
+        loop {
+            if let Some(frame) = try!(self.decode_frame()) {
+                return Ok(Async::Ready(Some(frame)));
+            }
+
+            // The upstream has been shutdown, yield any unterminated record
+            if self.eof {
+                if self.buf.is_empty() {
+                    return Ok(Async::Ready(None));
+                }
+
+                let len = self.buf.len();
+                let frame = self.buf.drain_to(len);
+                self.searched = 0;
+
+                return Ok(Async::Ready(Some(frame)));
+            }
+
+            // Ensure the buffer has enough space
+            self.buf.reserve(READ_SIZE);
+
+            let read = try_ready!(self.inner.try_read_buf(&mut self.buf));
+
+            if read == 0 {
+                self.eof = true;
+            }
+        }
+    }
+}
+
+impl<T: Write> Write for Decoder<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: Sink> Sink for Decoder<T> {
+    type SinkItem = T::SinkItem;
+    type SinkError = T::SinkError;
+
+    fn start_send(&mut self, item: T::SinkItem)
+        -> StartSend<T::SinkItem, T::SinkError>
+    {
+        self.inner.start_send(item)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), T::SinkError> {
+        self.inner.poll_complete()
+    }
+}
+
+/*
+ *
+ * ===== impl Builder =====
+ *
+ */
+
+impl Builder {
+    pub fn new() -> Builder {
+        Builder {
+            // Default to null terminated records
+            delimiter: vec![0],
+
+            // Default to stripping the delimiter
+            keep_delimiter: false,
+
+            // Default max frame length of 8MB
+            max_frame_len: 8 * 1_024 * 1_024,
+        }
+    }
+
+    /// Sets the byte sequence separating records
+    ///
+    /// Defaults to a single null byte
+    pub fn set_delimiter(mut self, val: Vec<u8>) -> Self {
+        assert!(!val.is_empty(), "delimiter must not be empty");
+        self.delimiter = val;
+        self
+    }
+
+    /// Sets whether the delimiter is included at the end of yielded records
+    ///
+    /// Defaults to `false`
+    pub fn set_keep_delimiter(mut self, val: bool) -> Self {
+        self.keep_delimiter = val;
+        self
+    }
+
+    /// Sets the max record length, excluding the delimiter
+    pub fn set_max_frame_length(mut self, val: usize) -> Self {
+        self.max_frame_len = val;
+        self
+    }
+
+    /// Build the delimited decoder
+    pub fn decoder<T>(self, io: T) -> Decoder<T> {
+        Decoder {
+            inner: io,
+            builder: self,
+            buf: ByteBuf::new(),
+            searched: 0,
+            eof: false,
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use io::AsyncRead;
+use codec::Decode;
+use codec::length_delimited;
+use bytes::{BufMut, ByteBuf};
+use futures::{Async, Poll, Stream};
+
+use std::collections::HashMap;
+use std::io;
+
+/// Maps frame tags to the `Decode` implementations decoding their payloads.
+pub struct Registry<M> {
+    codecs: HashMap<u8, Box<Decode<Item = M> + Send>>,
+}
+
+/// A `Stream` decoding length delimited frames with the codec registered
+/// for their tag.
+///
+/// The first byte of every frame is a tag selecting a codec from a
+/// `Registry`. The codec receives the rest of the frame and has to decode a
+/// message from it. A frame with an unregistered tag, or which its codec
+/// cannot decode, is an `InvalidData` error.
+pub struct DispatchDecoder<T, M> {
+    // Length delimited frames
+    inner: length_delimited::Decoder<T>,
+
+    // Codecs by tag
+    registry: Registry<M>,
+}
+
+/*
+ *
+ * ===== impl Registry =====
+ *
+ */
+
+impl<M> Registry<M> {
+    pub fn new() -> Registry<M> {
+        Registry { codecs: HashMap::new() }
+    }
+
+    /// Registers the codec decoding the payload of frames tagged with `tag`
+    pub fn register<D>(mut self, tag: u8, codec: D) -> Self
+        where D: Decode<Item = M> + Send + 'static,
+    {
+        let codec: Box<Decode<Item = M> + Send> = Box::new(codec);
+        self.codecs.insert(tag, codec);
+        self
+    }
+
+    /// Dispatch the frames decoded by `frames` to the registered codecs
+    pub fn decoder<T>(self, frames: length_delimited::Decoder<T>) -> DispatchDecoder<T, M> {
+        DispatchDecoder {
+            inner: frames,
+            registry: self,
+        }
+    }
+}
+
+/*
+ *
+ * ===== impl DispatchDecoder =====
+ *
+ */
+
+impl<T, M> DispatchDecoder<T, M> {
+    pub fn get_ref(&self) -> &length_delimited::Decoder<T> {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut length_delimited::Decoder<T> {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> length_delimited::Decoder<T> {
+        self.inner
+    }
+}
+
+impl<T: AsyncRead, M> Stream for DispatchDecoder<T, M> {
+    type Item = M;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<M>, io::Error> {
+        let frame = match try_ready!(self.inner.poll()) {
+            Some(frame) => frame,
+            None => return Ok(Async::Ready(None)),
+        };
+
+        let (&tag, payload) = match frame.split_first() {
+            Some(v) => v,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidData, "missing frame tag")),
+        };
+
+        let codec = match self.registry.codecs.get_mut(&tag) {
+            Some(codec) => codec,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown frame tag")),
+        };
+
+        let mut buf = ByteBuf::with_capacity(payload.len());
+        buf.put_slice(payload);
+
+        match try!(codec.decode(&mut buf)) {
+            Some(msg) => Ok(Async::Ready(Some(msg))),
+            None => Err(io::Error::new(io::ErrorKind::InvalidData, "incomplete frame payload")),
+        }
+    }
+}
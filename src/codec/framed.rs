@@ -0,0 +1,209 @@
+use io::{AsyncRead, AsyncWrite};
+use bytes::{Buf, ByteBuf};
+use futures::{Async, AsyncSink, Poll, Sink, Stream, StartSend};
+
+use std::io;
+
+/// Decodes frames from the bytes buffered by a `FramedRead`.
+pub trait Decode {
+    /// The type of the decoded frames
+    type Item;
+
+    /// Decode a frame from the front of `buf`, consuming its bytes.
+    ///
+    /// Returns `Ok(None)` if `buf` does not yet hold a full frame, in which
+    /// case more data is read before `decode` is called again.
+    fn decode(&mut self, buf: &mut ByteBuf) -> io::Result<Option<Self::Item>>;
+}
+
+/// Encodes frames into the write buffer of a `FramedWrite`.
+pub trait Encode {
+    /// The type of the frames to encode
+    type Item;
+
+    /// Encode `item` at the end of `buf`.
+    ///
+    /// `buf` does not grow on its own, room for the encoded bytes has to be
+    /// reserved before writing them.
+    fn encode(&mut self, item: Self::Item, buf: &mut ByteBuf) -> io::Result<()>;
+}
+
+/// A `Stream` of frames read from an I/O object by a `Decode`.
+///
+/// `FramedRead` handles buffering the bytes read from the upstream, leaving
+/// the decoder to only split off frames. If the upstream is shutdown while
+/// bytes that are not part of a frame are buffered, an `UnexpectedEof` error
+/// is yielded.
+pub struct FramedRead<T, D> {
+    // I/O type
+    inner: T,
+
+    // Frame decoder
+    decoder: D,
+
+    // Buffer
+    buf: ByteBuf,
+
+    // True once the upstream has been shutdown
+    eof: bool,
+}
+
+/// A `Sink` of frames written to an I/O object by an `Encode`.
+///
+/// Frames are encoded into a shared write buffer, which is written to the
+/// upstream by `poll_complete`. Once the buffer grows past 8KB, `start_send`
+/// first tries to write it out, returning `NotReady` if it cannot.
+pub struct FramedWrite<T, E> {
+    // I/O type
+    inner: T,
+
+    // Frame encoder
+    encoder: E,
+
+    // Encoded bytes not yet written to the upstream
+    buf: ByteBuf,
+}
+
+// Number of bytes to reserve for each read
+const READ_SIZE: usize = 1_024;
+
+// Number of buffered bytes above which `FramedWrite` stops accepting frames
+// until the buffer has been written
+const BACKPRESSURE_BOUNDARY: usize = 8 * 1_024;
+
+/*
+ *
+ * ===== impl FramedRead =====
+ *
+ */
+
+impl<T, D: Decode> FramedRead<T, D> {
+    pub fn new(io: T, decoder: D) -> FramedRead<T, D> {
+        FramedRead {
+            inner: io,
+            decoder: decoder,
+            buf: ByteBuf::new(),
+            eof: false,
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn decoder(&self) -> &D {
+        &self.decoder
+    }
+
+    pub fn decoder_mut(&mut self) -> &mut D {
+        &mut self.decoder
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: AsyncRead, D: Decode> Stream for FramedRead<T, D> {
+    type Item = D::Item;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<D::Item>, io::Error> {
+        loop {
+            if let Some(frame) = try!(self.decoder.decode(&mut self.buf)) {
+                return Ok(Async::Ready(Some(frame)));
+            }
+
+            if self.eof {
+                if self.buf.is_empty() {
+                    return Ok(Async::Ready(None));
+                }
+
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "bytes remaining on stream"));
+            }
+
+            // Ensure the buffer has enough space
+            self.buf.reserve(READ_SIZE);
+
+            let read = try_ready!(self.inner.try_read_buf(&mut self.buf));
+
+            if read == 0 {
+                self.eof = true;
+            }
+        }
+    }
+}
+
+/*
+ *
+ * ===== impl FramedWrite =====
+ *
+ */
+
+impl<T, E: Encode> FramedWrite<T, E> {
+    pub fn new(io: T, encoder: E) -> FramedWrite<T, E> {
+        FramedWrite {
+            inner: io,
+            encoder: encoder,
+            buf: ByteBuf::new(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn encoder(&self) -> &E {
+        &self.encoder
+    }
+
+    pub fn encoder_mut(&mut self) -> &mut E {
+        &mut self.encoder
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: AsyncWrite, E: Encode> Sink for FramedWrite<T, E> {
+    type SinkItem = E::Item;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: E::Item) -> StartSend<E::Item, io::Error> {
+        if self.buf.len() >= BACKPRESSURE_BOUNDARY {
+            try!(self.poll_complete());
+
+            if self.buf.len() >= BACKPRESSURE_BOUNDARY {
+                return Ok(AsyncSink::NotReady(item));
+            }
+        }
+
+        try!(self.encoder.encode(item, &mut self.buf));
+
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        while self.buf.has_remaining() {
+            let n = try_ready!(self.inner.try_write_buf(&mut self.buf));
+
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::WriteZero, "zero-length write"));
+            }
+        }
+
+        // Reclaim the space taken by the written bytes
+        self.buf.clear();
+
+        self.inner.try_flush()
+    }
+}
@@ -1,10 +1,20 @@
-use io::{AsyncRead, AsyncWrite};
-use bytes::{Buf, IntoBuf, BufMut, BytesMut, ByteBuf, SliceBuf};
-use futures::{Async, AsyncSink, Poll, Sink, Stream, StartSend};
-use byteorder::{BigEndian, LittleEndian};
+use io::{try_write_bufs, AsyncRead, AsyncWrite, Shutdown};
+use codec::{memchr, Decode, Encode};
+use bytes::{Buf, IntoBuf, BufMut, Bytes, BytesMut, ByteBuf};
+use futures::{Async, AsyncSink, Future, Poll, Sink, Stream, StartSend};
+use futures::sync::oneshot;
+use futures::task::{self, Task};
+use byteorder::{BigEndian, LittleEndian, NativeEndian};
+use byteorder::ByteOrder as Endian;
+use tokio_core::reactor::{Handle, Timeout};
 
-use std::{cmp, mem};
+use std::{cmp, error, fmt, iter, mem, u64};
+use std::collections::{HashMap, VecDeque};
+use std::marker::PhantomData;
 use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
 /// A decoder that splits the bytes read into `BytesMut` values according to
 /// the value of the length field in the frame header.
@@ -20,11 +30,149 @@ pub struct Decoder<T> {
     // Configuration values
     builder: Builder,
 
-    // Buffer
-    buf: ByteBuf,
+    // Read buffer. Frames are split off the front, sharing its allocation
+    buf: BytesMut,
 
     // Read state
     state: ReadState,
+
+    // Memory budget charged for the payload of the current frame
+    charge: Option<Charge>,
+
+    // Content hash read from the most recent frame head
+    content_hash: Option<u64>,
+
+    // Tag read from the most recent frame head
+    tag: Option<u64>,
+
+    // Number of bytes consumed by the head of the current frame
+    head_wire_len: usize,
+
+    // Length field value of the previous frame, used for delta lengths
+    prev_len: u64,
+
+    // Nonce of the previous frame, used to reject replayed frames
+    last_nonce: Option<u64>,
+
+    // Length field bytes of the current frame, repeated after the payload
+    // when the length is bracketed
+    bracket: Vec<u8>,
+
+    // Total number of bytes consumed by the most recently decoded frame
+    wire_len: usize,
+
+    // Number of bytes read from the upstream by the most recent poll
+    last_read: usize,
+
+    // Number of those bytes read while waiting for a frame head
+    head_read: usize,
+
+    // Moving average of the wire length of decoded frames
+    frame_estimate: usize,
+
+    // Number of length fields checked against the other byte order, and how
+    // many of them held the smaller value in the configured byte order
+    order_checked: u64,
+    order_plausible: u64,
+
+    // Paces the bytes read from the upstream
+    rate: Option<ByteRate>,
+
+    // True when the inter-frame gap precedes the next frame head
+    gap_pending: bool,
+
+    // Head bytes of the current frame, kept when `keep_head` is set
+    head: BytesMut,
+    keep_head: bool,
+
+    // Decode from the buffered bytes only, without reading the upstream
+    buffered_only: bool,
+
+    // A rolled back frame, yielded again by the next poll
+    redeliver: Option<BytesMut>,
+
+    // Number of buffered bytes already searched for the initial skip marker,
+    // or for newlines when the length counts lines
+    searched: usize,
+
+    // Number of newlines found in the searched bytes
+    lines_seen: usize,
+}
+
+// Decoder state updated by each frame head, saved before decoding a head
+#[derive(Clone, Copy)]
+struct HeadState {
+    prev_len: u64,
+    last_nonce: Option<u64>,
+    tag: Option<u64>,
+    content_hash: Option<u64>,
+    order_checked: u64,
+    order_plausible: u64,
+}
+
+/// A cloneable stream of frames, created by `Decoder::into_shared_stream`.
+///
+/// Every clone pulls frames from the same decoder, and each frame is yielded
+/// to exactly one clone.
+pub struct SharedFrames<T> {
+    inner: Arc<Mutex<SharedDecoder<T>>>,
+
+    // Identifies this clone's slot in `waiters`
+    id: usize,
+}
+
+struct SharedDecoder<T> {
+    decoder: Decoder<T>,
+
+    // Task of each clone waiting for the decoder to make progress, keyed by
+    // clone id so that polling again replaces the clone's previous task
+    waiters: HashMap<usize, Task>,
+
+    // Id of the next clone
+    next_id: usize,
+}
+
+/// A frame yielded by `Decoder::poll_transactional` that may still be
+/// handed back to the decoder.
+pub struct Txn<'a, T: 'a> {
+    decoder: &'a mut Decoder<T>,
+}
+
+/// A stream yielding each frame along with the number of bytes it occupied
+/// on the wire, created by `Decoder::with_wire_len`.
+pub struct WithWireLen<T> {
+    inner: Decoder<T>,
+}
+
+/// A stream yielding each frame as immutable `Bytes`, created by
+/// `Builder::decoder_bytes`.
+///
+/// Cloning a yielded frame is cheap and does not copy the data, so it can be
+/// handed to several consumers.
+pub struct DecoderBytes<T> {
+    inner: Decoder<T>,
+}
+
+/// A stream yielding the tag of each frame along with its payload, created by
+/// `Decoder::into_tagged_frames`.
+pub struct TaggedFrames<T> {
+    inner: Decoder<T>,
+}
+
+/// A stream yielding the head of each frame along with its payload, created
+/// by `Decoder::into_frames_with_headers`.
+pub struct FramesWithHeaders<T> {
+    inner: Decoder<T>,
+}
+
+/// A stream parsing each frame into a typed message, created by
+/// `Decoder::map_frames`.
+pub struct MapFrames<S, F> {
+    inner: S,
+    f: F,
+
+    // Set once a frame fails to parse
+    done: bool,
 }
 
 pub struct Encoder<T, B: IntoBuf> {
@@ -36,8 +184,122 @@ pub struct Encoder<T, B: IntoBuf> {
 
     // Write state
     state: WriteState<B::Buf>,
+
+    // Number of padding bytes to write after the current payload
+    padding: usize,
+
+    // Length field repeated after the current payload
+    suffix: Option<ByteBuf>,
+
+    // Length field value of the previous frame, used for delta lengths
+    prev_len: u64,
+
+    // Nonce written in the head of the next frame
+    nonce: u64,
+
+    // Bytes written to the upstream since `poll_complete_counted` last
+    // completed
+    written: usize,
+
+    // Notified once the current frame, and any frames queued behind it, have
+    // been flushed
+    acks: Vec<oneshot::Sender<()>>,
+
+    // Encoded frames accepted while the current frame was being written
+    queued: VecDeque<u8>,
+
+    // Number of frames in `queued`
+    num_queued: usize,
+}
+
+/// A length delimited `Stream` and `Sink` over a single I/O object.
+///
+/// Frames are read using a `Decoder` and written using an `Encoder`, both
+/// configured by the same `Builder`.
+pub struct Framed<T, B: IntoBuf> {
+    inner: Decoder<Encoder<T, B>>,
+}
+
+/// Length delimited framing as a `codec::Decode` and `codec::Encode`, for use
+/// with `codec::FramedRead` and `codec::FramedWrite`. Created by
+/// `Builder::codec`.
+pub struct Codec<B: IntoBuf> {
+    decoder: Decoder<io::Empty>,
+    encoder: Encoder<io::Sink, B>,
+
+    // Number of bytes at the front of the `FramedRead` buffer that have been
+    // copied into the decoder's buffer
+    held: usize,
+}
+
+/// The read half of a `Framed`, see `Framed::split`.
+pub struct FramedRead<T, B: IntoBuf> {
+    inner: Decoder<Shared<T>>,
+    _marker: PhantomData<B>,
+}
+
+/// The write half of a `Framed`, see `Framed::split`.
+pub struct FramedWrite<T, B: IntoBuf> {
+    inner: Encoder<Shared<T>, B>,
+}
+
+// I/O shared between the two halves of a split `Framed`
+struct Shared<T> {
+    inner: Arc<Mutex<T>>,
+}
+
+/// A length delimited `Stream` and `Sink` that reconnects on transport
+/// errors, see `reconnecting`.
+pub struct Reconnecting<F, Fut, T, B: IntoBuf> {
+    // Establishes a new connection, including any handshake
+    connect: F,
+
+    // Configuration values for each connection
+    builder: Builder,
+
+    // Connection state
+    state: ConnectState<Fut, T, B>,
+}
+
+/// A `Sink` spreading frames across several writers in rotation, see
+/// `round_robin`.
+pub struct RoundRobin<T, B: IntoBuf> {
+    encoders: Vec<Encoder<T, B>>,
+
+    // Index of the encoder receiving the next frame
+    next: usize,
+
+    // Pass over encoders that are not ready rather than waiting for them
+    skip_busy: bool,
+}
+
+/// A future writing out an encoder's pending frames and flushing its
+/// upstream, created by `Encoder::flush`.
+pub struct Flush<E> {
+    // Taken once the future completes
+    inner: Option<E>,
+}
+
+/// A future framing every payload of a stream onto a writer, created by
+/// `write_stream`.
+pub struct WriteStream<S, W> {
+    // Taken once the future completes
+    payloads: Option<S>,
+    encoder: Option<Encoder<W, BytesMut>>,
+
+    // Payload the encoder was not ready to accept
+    buffered: Option<BytesMut>,
+
+    // True once the payload stream has ended
+    payloads_done: bool,
+}
+
+enum ConnectState<Fut, T, B: IntoBuf> {
+    Connecting(Fut),
+    Connected(Framed<T, B>),
 }
 
+#[derive(Clone, Debug)]
 pub struct Builder {
     // Maximum frame length
     max_frame_len: usize,
@@ -48,6 +310,209 @@ pub struct Builder {
     // Number of bytes in the header before the length field
     length_field_offset: usize,
 
+    // Bytes the encoder writes before the length field
+    length_field_prefix: Vec<u8>,
+
+    // Number of bytes in the header after the length field, replacing
+    // `length_field_offset` when set
+    length_field_from_end: Option<usize>,
+
+    // Adjust the length specified in the header field by this amount
+    length_adjustment: isize,
+
+    // Total number of bytes to skip before reading the payload, if not set,
+    // `length_field_len + length_field_offset`
+    num_skip: Option<usize>,
+
+    // Length field byte order (little or big endian)
+    length_field_order: ByteOrder,
+
+    // Bit offset into the length field and number of bits holding the
+    // length, when the length is a bitfield
+    length_bits: Option<(usize, usize)>,
+
+    // Bytes the encoder writes a bitfield length into
+    head_prefix: Vec<u8>,
+
+    // When set, the length field counts newline terminated lines rather than
+    // bytes
+    length_counts_lines: bool,
+
+    // When set, the head is the length as decimal text followed by this
+    // delimiter
+    length_ascii: Option<u8>,
+
+    // When set, the length field includes the `num_skip` header bytes
+    length_includes_header: bool,
+
+    // Memory budget shared with other decoders
+    memory_budget: Option<MemoryBudget>,
+
+    // Number of bytes of the payload hash following the length field
+    content_hash_len: usize,
+
+    // Offset and length of the nonce field in the frame head
+    nonce_field: Option<(usize, usize)>,
+
+    // Offset and length of the frame type tag in the frame head
+    tag_field: Option<(usize, usize)>,
+
+    // Flush the upstream after writing each frame
+    flush_between_frames: bool,
+
+    // Pad every payload to this many bytes
+    pad_to: Option<usize>,
+
+    // Byte used for padding
+    pad_byte: u8,
+
+    // The length field holds a signed delta from the previous frame's length
+    delta_length: bool,
+
+    // Treat EOF in the middle of a frame as the end of the stream
+    allow_trailing: bool,
+
+    // Accept frames with an empty payload
+    allow_empty_frames: bool,
+
+    // Repeat the length field after the payload
+    bracketed_length: bool,
+
+    // Encoder writes items as is, without a frame head
+    passthrough: bool,
+
+    // Encoder writes every frame with a single write
+    message_oriented: bool,
+
+    // Number of trailer bytes following the payload
+    trailer_len: usize,
+
+    // Computes the trailer of a payload
+    trailer: Option<Callback<FnMut(&[u8], &mut [u8]) + Send>>,
+
+    // The trailer is a CRC32 of the payload
+    crc32_trailer: bool,
+
+    // Byte order of the trailer, if not set, `length_field_order`
+    trailer_order: Option<ByteOrder>,
+
+    // Length field sentinel value and number of bytes of the extended length
+    // field that follows it
+    extended_length: Option<(u64, usize)>,
+
+    // Initial capacity of the decoder's read buffer
+    initial_buffer_capacity: usize,
+
+    // Size read reservations after the recently decoded frames
+    adaptive_buffer: bool,
+
+    // Maximum number of bytes read from the upstream per poll
+    read_chunk_limit: Option<usize>,
+
+    // Bytes before the first frame are discarded up to and including this
+    // marker
+    initial_skip_until: Option<Vec<u8>>,
+
+    // Bytes separating consecutive frames
+    inter_frame_gap: Vec<u8>,
+
+    // Number of frames the encoder accepts while a frame is being written
+    max_pending_frames: usize,
+
+    // Head layouts selected by a leading format version byte
+    formats: HashMap<u8, FormatSpec>,
+
+    // Notified of every read from or write to the upstream
+    on_io: Option<Callback<FnMut(IoEvent) + Send>>,
+
+    // Consulted when a frame head cannot be decoded
+    on_error: Option<Callback<FnMut(&io::Error) -> ErrorAction + Send>>,
+
+    // Notified of the bytes read by every decoder poll
+    on_progress: Option<Callback<FnMut(Progress) + Send>>,
+}
+
+// A user supplied callback, shared between clones of the `Builder`
+struct Callback<F: ?Sized> {
+    inner: Arc<Mutex<Box<F>>>,
+}
+
+/// A cap on the total payload bytes buffered across a set of decoders.
+///
+/// The budget is cheap to clone, all clones share the same counter. Each
+/// decoder charges the length of the frame it is reading against the budget
+/// and releases it once the frame has been yielded. When the budget is
+/// exhausted, decoders stop reading until another frame is released.
+///
+/// A frame larger than the entire budget is still read when no other frames
+/// are in flight, so that an oversized frame cannot stall the decoders forever.
+/// Use `Builder::set_max_frame_length` to bound individual frames.
+#[derive(Clone)]
+pub struct MemoryBudget {
+    inner: Arc<BudgetInner>,
+}
+
+struct BudgetInner {
+    // Maximum number of bytes
+    limit: usize,
+
+    // Number of bytes currently charged
+    used: AtomicUsize,
+
+    // Tasks waiting for budget to be released
+    waiters: Mutex<Vec<Task>>,
+}
+
+// Releases the charged bytes back to the budget when dropped
+struct Charge {
+    budget: MemoryBudget,
+    amount: usize,
+}
+
+// Token bucket over the bytes read from the upstream
+struct ByteRate {
+    bytes_per_sec: u64,
+
+    // Number of bytes that may be read right away
+    tokens: u64,
+
+    // Time up to which tokens have been added to the bucket
+    refilled: Instant,
+
+    // Used to wait for the bucket to refill
+    handle: Handle,
+    timeout: Option<Timeout>,
+}
+
+/// What a `Decoder` should do after failing to decode a frame head.
+///
+/// Returned by the callback passed to `Builder::on_error`.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum ErrorAction {
+    /// Yield the error and end the stream.
+    Abort,
+
+    /// Discard the first buffered byte and look for the next valid frame
+    /// head.
+    Skip,
+
+    /// Yield the error, leaving the decoder as is. Polling the decoder again
+    /// retries decoding the same head.
+    Retry,
+}
+
+/// The head layout of one frame format, registered with
+/// `Builder::register_format`.
+///
+/// Offsets are relative to the byte following the format version byte.
+#[derive(Debug, Clone, Copy)]
+pub struct FormatSpec {
+    // Number of bytes representing the field length
+    length_field_len: usize,
+
+    // Number of bytes in the header before the length field
+    length_field_offset: usize,
+
     // Adjust the length specified in the header field by this amount
     length_adjustment: isize,
 
@@ -59,6 +524,49 @@ pub struct Builder {
     length_field_order: ByteOrder,
 }
 
+/// A read from or write to the upstream, reported to the callback passed to
+/// `Builder::on_io`.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct IoEvent {
+    /// Whether the upstream was read from or written to.
+    pub kind: IoKind,
+
+    /// The number of bytes transferred.
+    pub bytes: usize,
+
+    /// Whether the upstream was not ready, in which case no bytes were
+    /// transferred.
+    pub would_block: bool,
+}
+
+/// The bytes read by a single `Decoder` poll, reported to the callback
+/// passed to `Builder::on_progress`.
+///
+/// Bytes are attributed to the phase the decoder was in when they were read,
+/// so a read returning the head along with part of the payload counts
+/// towards `head`.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct Progress {
+    /// The number of bytes read while waiting for a frame head.
+    pub head: usize,
+
+    /// The number of bytes read while waiting for a frame payload.
+    pub payload: usize,
+
+    /// Whether the poll completed, rather than returning `NotReady`.
+    pub ready: bool,
+}
+
+/// The direction of an `IoEvent`.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum IoKind {
+    /// A read from the upstream.
+    Read,
+
+    /// A write to the upstream.
+    Write,
+}
+
 /// An enumeration of valid byte orders
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
 pub enum ByteOrder {
@@ -67,18 +575,93 @@ pub enum ByteOrder {
 
     /// Little-endian byte order.
     LittleEndian,
+
+    /// Byte order of the host platform.
+    NativeEndian,
+}
+
+/// Errors produced while decoding length delimited frames.
+///
+/// The `Decoder` reports errors as `io::Error` values wrapping an `Error`,
+/// which can be recovered with `Error::from_io`.
+#[derive(Debug)]
+pub enum Error {
+    /// The frame length of `len` bytes exceeds the maximum of `max` bytes.
+    FrameTooLarge { len: u64, max: usize },
+
+    /// Applying the length adjustment to the frame length overflowed.
+    LengthOverflow,
+
+    /// The upstream was shutdown in the middle of a frame.
+    UnexpectedEof,
+
+    /// The upstream was shutdown in the middle of a frame payload of
+    /// `expected` bytes, after only `received` had been read.
+    TruncatedPayload { received: BytesMut, expected: usize },
+
+    /// The format version byte leading the frame head is not registered.
+    UnknownFormat(u8),
+
+    /// The frame nonce is not greater than the nonce of the previous frame,
+    /// indicating a replayed or reordered frame.
+    Replay { nonce: u64, last: u64 },
+
+    /// The length following the payload of a bracketed frame does not match
+    /// the length preceding it.
+    LengthMismatch,
+
+    /// The trailer following the payload does not match the one computed
+    /// from the payload.
+    TrailerMismatch,
+
+    /// An error from the upstream.
+    Io(io::Error),
+}
+
+/// Likely mistakes in a `Builder` configuration, reported by
+/// `Builder::validate` and refused by `Builder::try_decoder` and
+/// `Builder::try_encoder`.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum ConfigError {
+    /// Fewer bytes are skipped than are occupied by the head fields, so the
+    /// trailing `head_len - num_skip` head bytes are yielded as part of the
+    /// payload.
+    NumSkipWithinHead { num_skip: usize, head_len: usize },
+
+    /// The nonce field starts at `nonce_offset`, before the length field and
+    /// content hash end at `length_end`. The encoder rejects every frame.
+    NonceOverlapsLength { nonce_offset: usize, length_end: usize },
+}
+
+/// The phase of frame decoding a `Decoder` is currently in.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum DecoderState {
+    /// Waiting for the frame head to be fully buffered.
+    AwaitingHeader,
+
+    /// The frame head has been read and the payload of `len` bytes is being
+    /// buffered, `received` of which are already available.
+    ///
+    /// Both count lines rather than bytes when the length counts lines.
+    AwaitingPayload { len: usize, received: usize },
 }
 
 #[derive(Debug, Clone, Copy)]
 enum ReadState {
+    Banner,
     Head,
     Data(usize),
+    Done,
 }
 
 enum WriteState<B> {
     Ready,
-    Head { head: SliceBuf<[u8; 8]>, data: B },
+    Head { head: ByteBuf, data: B },
     Data(B),
+    Pad,
+    Suffix,
+    Queued,
+    Flush,
 }
 
 /*
@@ -103,404 +686,3967 @@ impl<T> Decoder<T> {
     pub fn into_inner(self) -> T {
         self.inner
     }
-}
 
-impl<T: AsyncRead> Decoder<T> {
-    fn read_head(&mut self) -> Poll<Option<usize>, io::Error> {
-        let head_len = self.builder.num_head_bytes();
-        let field_len = self.builder.length_field_len;
+    /// Returns a copy of the configuration the decoder was built with
+    ///
+    /// This is useful to build an encoder for the other direction.
+    pub fn builder(&self) -> Builder {
+        self.builder.clone()
+    }
 
-        loop {
-            if self.buf.len() >= head_len {
-                // Enough data has been buffered to process the head
-                
-                // Skip the required bytes
-                self.buf.advance(self.builder.length_field_offset);
-                
-                // match endianess
-                let n = match self.builder.length_field_order {
-                    ByteOrder::BigEndian => {
-                        self.buf.get_uint::<BigEndian>(field_len)
-                    }
-                    ByteOrder::LittleEndian => {
-                        self.buf.get_uint::<LittleEndian>(field_len)
-                    }
-                };
+    /// Sets the max frame length, keeping any buffered bytes
+    ///
+    /// The new limit applies to every frame head decoded from now on,
+    /// including one that is already buffered. A frame whose head has
+    /// already been decoded is read in full.
+    pub fn set_max_frame_length(&mut self, val: usize) {
+        self.builder.max_frame_len = val;
+    }
 
-                if n > self.builder.max_frame_len as u64 {
-                    return Err(io::Error::new(io::ErrorKind::InvalidData, "frame size too big"));
-                }
+    // Replace the I/O object, keeping the read state
+    fn map_inner<U, F>(self, f: F) -> Decoder<U>
+        where F: FnOnce(T) -> U,
+    {
+        Decoder {
+            inner: f(self.inner),
+            builder: self.builder,
+            buf: self.buf,
+            state: self.state,
+            charge: self.charge,
+            content_hash: self.content_hash,
+            tag: self.tag,
+            head_wire_len: self.head_wire_len,
+            prev_len: self.prev_len,
+            last_nonce: self.last_nonce,
+            bracket: self.bracket,
+            wire_len: self.wire_len,
+            last_read: self.last_read,
+            head_read: self.head_read,
+            frame_estimate: self.frame_estimate,
+            order_checked: self.order_checked,
+            order_plausible: self.order_plausible,
+            rate: self.rate,
+            gap_pending: self.gap_pending,
+            head: self.head,
+            keep_head: self.keep_head,
+            buffered_only: self.buffered_only,
+            redeliver: self.redeliver,
+            searched: self.searched,
+            lines_seen: self.lines_seen,
+        }
+    }
 
-                // The check above ensures there is no overflow
-                let n = n as usize;
+    /// Limit the rate at which bytes are read from the upstream
+    ///
+    /// Reads are paced by a token bucket holding up to a tenth of a second's
+    /// worth of bytes. Once it is empty, polling returns `NotReady` until a
+    /// timeout on `handle` fires and the bucket has refilled.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes_per_sec` is 0.
+    pub fn byte_rate_limit(mut self, bytes_per_sec: u64, handle: &Handle) -> Self {
+        assert!(bytes_per_sec > 0, "byte rate must be greater than 0");
 
-                // Adjust `n` with bounds checking
-                let n = if self.builder.length_adjustment < 0 {
-                    n.checked_sub(-self.builder.length_adjustment as usize)
-                } else {
-                    n.checked_add(self.builder.length_adjustment as usize)
-                };
+        let mut rate = ByteRate {
+            bytes_per_sec: bytes_per_sec,
+            tokens: 0,
+            refilled: Instant::now(),
+            handle: handle.clone(),
+            timeout: None,
+        };
 
-                // Error handling
-                let n = match n {
-                    Some(n) => n,
-                    None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "provided length would overflow after adjustment")),
-                };
+        rate.tokens = rate.capacity();
+        self.rate = Some(rate);
+        self
+    }
 
-                // TODO: Add a config setting to not consume the head
-                self.buf.drain_to(self.builder.num_skip());
+    /// Returns the payload hash carried in the head of the most recently
+    /// decoded frame, if `Builder::set_content_hash` is configured.
+    pub fn content_hash(&self) -> Option<u64> {
+        self.content_hash
+    }
 
-                // Ensure that the buffer has enough space to read the incoming
-                // payload
-                self.buf.reserve(n);
+    /// Yield each frame along with the total number of bytes consumed from
+    /// the wire for it, including the header and any skipped bytes.
+    pub fn with_wire_len(self) -> WithWireLen<T> {
+        WithWireLen { inner: self }
+    }
 
-                return Ok(Async::Ready(Some(n)));
-            }
+    /// Yield the head of each frame along with its payload
+    ///
+    /// The head holds every byte skipped before the payload, including the
+    /// length field and any extended length, so flags carried in the head can
+    /// be inspected alongside the payload.
+    pub fn into_frames_with_headers(mut self) -> FramesWithHeaders<T> {
+        self.keep_head = true;
+        FramesWithHeaders { inner: self }
+    }
 
-            // Ensure the buffer has enough space
-            let rem = field_len - self.buf.len();
-            self.buf.reserve(rem);
+    /// Yield the tag of each frame along with its payload
+    ///
+    /// # Panics
+    ///
+    /// Panics if `Builder::set_tag_field` is not configured.
+    pub fn into_tagged_frames(self) -> TaggedFrames<T> {
+        assert!(self.builder.tag_field.is_some(), "no tag field configured");
+        TaggedFrames { inner: self }
+    }
 
-            // Try reading the rest of the head
-            let read = try_ready!(self.inner.try_read_buf(&mut self.buf));
+    /// Parse each frame into a message with `f`
+    ///
+    /// An error returned by `f` is yielded as a stream error, after which the
+    /// stream ends.
+    pub fn map_frames<F, U>(self, f: F) -> MapFrames<Self, F>
+        where F: FnMut(BytesMut) -> io::Result<U>,
+    {
+        MapFrames {
+            inner: self,
+            f: f,
+            done: false,
+        }
+    }
 
-            // If 0 bytes have been read, then the upstream has been shutdown.
-            if read == 0 {
-                if self.buf.is_empty() {
-                    return Ok(Async::Ready(None));
-                } else {
-                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "eof"));
-                }
-            }
+    /// Convert into a stream that may be cloned so that several consumers can
+    /// pull frames concurrently. Each frame is delivered to exactly one of
+    /// the clones.
+    pub fn into_shared_stream(self) -> SharedFrames<T> {
+        SharedFrames {
+            inner: Arc::new(Mutex::new(SharedDecoder {
+                decoder: self,
+                waiters: HashMap::new(),
+                next_id: 1,
+            })),
+            id: 0,
         }
     }
 
-    fn read_data(&mut self, n: usize) -> Poll<Option<BytesMut>, io::Error> {
-        // At this point, the buffer has already had the required capacity
-        // reserved. All there is to do is read.
-        loop {
-            if self.buf.len() >= n {
-                let ret = self.buf.drain_to(n);
-                return Ok(Async::Ready(Some(ret)));
-            }
+    /// Returns the number of bytes read from the upstream that have not yet
+    /// been yielded as part of a frame.
+    pub fn buffered_len(&self) -> usize {
+        self.buf.len()
+    }
 
-            let read = try_ready!(self.inner.try_read_buf(&mut self.buf));
+    /// Returns the capacity of the read buffer, including space reserved for
+    /// the payload of the frame being read.
+    pub fn buffer_capacity(&self) -> usize {
+        self.buf.capacity()
+    }
 
-            // Same as `read_head` except that the upstream should never
-            // shutdown at this point, thus making a shutdown always an error.
-            if read == 0 {
-                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "eof"));
-            }
-        }
+    /// Discards all buffered bytes and waits for a new frame head, for
+    /// example to recover from a decode error.
+    ///
+    /// The next frame head is expected at the next byte read from the
+    /// upstream. Finding that point in the stream is up to the caller.
+    pub fn reset(&mut self) {
+        self.buf.clear();
+        self.searched = 0;
+        self.lines_seen = 0;
+        self.state = ReadState::Head;
+        self.charge = None;
+        self.gap_pending = false;
     }
-}
 
-impl<T: AsyncRead> Stream for Decoder<T> {
-    type Item = BytesMut;
-    type Error = io::Error;
+    /// Discards the first `offset` buffered bytes and waits for a new frame
+    /// head, starting at the byte that follows.
+    ///
+    /// Finding the offset of the next frame head is up to the caller.
+    pub fn resync_to(&mut self, offset: usize) {
+        let offset = cmp::min(offset, self.buf.len());
 
-    fn poll(&mut self) -> Poll<Option<BytesMut>, io::Error> {
+        self.buf.drain_to(offset);
+        self.searched = 0;
+        self.lines_seen = 0;
+        self.state = ReadState::Head;
+        self.charge = None;
+        self.gap_pending = false;
+    }
+
+    /// Aborts the frame whose payload is being read, returning the payload
+    /// bytes read so far.
+    ///
+    /// The buffer space reserved for the payload, and any memory budget
+    /// charged for it, is released. Unlike `reset`, bytes following the
+    /// payload are kept. Returns `None` if no payload is being read.
+    ///
+    /// The rest of the aborted payload is not skipped, so this is meant for
+    /// shutting down the connection rather than resuming decoding.
+    pub fn cancel(&mut self) -> Option<BytesMut> {
+        let n = match self.state {
+            ReadState::Data(n) => n,
+            _ => return None,
+        };
+
+        let n = cmp::min(n, self.buf.len());
+
+        // Copy the remaining bytes out so the reserved capacity is freed
+        let partial = BytesMut::from(&self.buf[..n]);
+        let mut buf = BytesMut::with_capacity(self.builder.initial_buffer_capacity);
+        buf.extend_from_slice(&self.buf[n..]);
+
+        self.buf = buf;
+        self.searched = 0;
+        self.lines_seen = 0;
+        self.bracket.clear();
+        self.state = ReadState::Head;
+        self.charge = None;
+
+        Some(partial)
+    }
+
+    /// Returns the number of bytes the most recent `poll` read from the
+    /// upstream.
+    ///
+    /// This is 0 when the poll was served entirely from buffered data.
+    pub fn last_poll_read_bytes(&self) -> usize {
+        self.last_read
+    }
+
+    /// Returns how consistently the configured byte order yields plausible
+    /// frame lengths, from 0.0 to 1.0
+    ///
+    /// A length field is plausible when reading it in the configured byte
+    /// order gives a value no larger than reading it in the other order, as
+    /// lengths tend to leave their high bytes zeroed. A confidence that stays
+    /// low over several frames suggests that the byte order is wrong, even
+    /// though the frames happened to decode. Bitfield lengths and length
+    /// fields wider than 8 bytes are not checked. This is 1.0 until a length
+    /// field has been checked.
+    pub fn endian_confidence(&self) -> f32 {
+        if self.order_checked == 0 {
+            return 1.0;
+        }
+
+        self.order_plausible as f32 / self.order_checked as f32
+    }
+
+    /// Returns the moving average of the number of bytes taken by recent
+    /// frames on the wire, if `Builder::set_adaptive_buffer` is enabled.
+    ///
+    /// This is 0 until the first frame has been decoded.
+    pub fn frame_size_estimate(&self) -> Option<usize> {
+        if self.builder.adaptive_buffer {
+            Some(self.frame_estimate)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the payload length of the frame currently being read, if the
+    /// head has already been decoded.
+    ///
+    /// The length is a number of lines when `set_length_counts_lines` is
+    /// enabled.
+    pub fn current_frame_len(&self) -> Option<usize> {
+        match self.state {
+            ReadState::Data(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    /// Returns the current decoding phase.
+    pub fn state(&self) -> DecoderState {
+        match self.state {
+            ReadState::Banner | ReadState::Head | ReadState::Done => DecoderState::AwaitingHeader,
+            ReadState::Data(n) if self.builder.length_counts_lines => {
+                DecoderState::AwaitingPayload {
+                    len: n,
+                    received: cmp::min(self.lines_seen, n),
+                }
+            }
+            ReadState::Data(n) => {
+                DecoderState::AwaitingPayload {
+                    len: n,
+                    received: cmp::min(self.buf.len(), n),
+                }
+            }
+        }
+    }
+}
+
+impl<T: AsyncRead> Decoder<T> {
+    /// Returns the next frame that is already fully buffered, without reading
+    /// from the upstream
+    ///
+    /// Returns `Ok(None)` when more bytes are needed to complete the next
+    /// frame, or the stream has ended. Unlike `poll`, this may be called
+    /// outside of a task.
+    pub fn try_next_frame(&mut self) -> io::Result<Option<BytesMut>> {
+        self.buffered_only = true;
+        let res = self.poll_frame();
+        self.buffered_only = false;
+
+        match try!(res) {
+            Async::Ready(frame) => Ok(frame),
+            Async::NotReady => Ok(None),
+        }
+    }
+
+    /// Poll for the payload length of the next frame, without reading its
+    /// payload
+    ///
+    /// Only the frame head is read and buffered. The frame is not consumed,
+    /// so the next `poll` yields it as usual. This allows deciding where to
+    /// route a frame before buffering its payload.
+    pub fn poll_head_len(&mut self) -> Poll<Option<usize>, io::Error> {
+        if let Some(ref frame) = self.redeliver {
+            return Ok(Async::Ready(Some(frame.len())));
+        }
+
+        self.last_read = 0;
+        self.head_read = 0;
+
+        self.poll_head()
+    }
+
+    /// Poll for the next frame, copying its payload into `scratch`
+    ///
+    /// Returns the part of `scratch` holding the payload. The payload is
+    /// split off the decoder's read buffer without allocating. A frame
+    /// larger than `scratch` is consumed and reported as a `FrameTooLarge`
+    /// error.
+    pub fn poll_into<'a>(&'a mut self, scratch: &'a mut [u8]) -> Poll<Option<&'a [u8]>, io::Error> {
+        let frame = match try_ready!(self.poll()) {
+            Some(frame) => frame,
+            None => return Ok(Async::Ready(None)),
+        };
+
+        if frame.len() > scratch.len() {
+            return Err(Error::FrameTooLarge {
+                len: frame.len() as u64,
+                max: scratch.len(),
+            }.into());
+        }
+
+        let dst = &mut scratch[..frame.len()];
+        dst.copy_from_slice(&frame);
+
+        Ok(Async::Ready(Some(dst)))
+    }
+
+    /// Poll for the next frame, along with a `Txn` deciding whether the
+    /// frame is consumed
+    ///
+    /// Committing the `Txn`, or dropping it, consumes the frame. Rolling it
+    /// back with the frame makes the next poll yield that frame again.
+    pub fn poll_transactional<'a>(&'a mut self) -> Poll<Option<(BytesMut, Txn<'a, T>)>, io::Error> {
+        let frame = match try_ready!(self.poll()) {
+            Some(frame) => frame,
+            None => return Ok(Async::Ready(None)),
+        };
+
+        let txn = Txn {
+            decoder: self,
+        };
+
+        Ok(Async::Ready(Some((frame, txn))))
+    }
+
+    // Discard everything up to and including the initial skip marker
+    fn read_banner(&mut self) -> Poll<Option<()>, io::Error> {
         loop {
-            match self.state {
-                ReadState::Head => {
-                    match try_ready!(self.read_head()) {
-                        Some(n) => self.state = ReadState::Data(n),
-                        None => return Ok(Async::Ready(None)),
+            let marker_len = self.builder.initial_skip_until.as_ref().map(|m| m.len()).unwrap_or(0);
+
+            let pos = match self.builder.initial_skip_until {
+                Some(ref marker) if !marker.is_empty() => {
+                    memchr::find(marker, &self.buf[self.searched..])
+                        .map(|pos| self.searched + pos + marker_len)
+                }
+                _ => Some(0),
+            };
+
+            if let Some(pos) = pos {
+                self.buf.drain_to(pos);
+                self.searched = 0;
+                return Ok(Async::Ready(Some(())));
+            }
+
+            // The marker may start in the last bytes searched
+            self.searched = self.buf.len().saturating_sub(marker_len - 1);
+
+            if self.buf.len() >= self.builder.max_frame_len {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "initial skip marker not found"));
+            }
+
+            self.buf.reserve(BANNER_READ_SIZE);
+
+            let read = try_ready!(self.read_upstream());
+
+            if read == 0 {
+                if self.buf.is_empty() {
+                    return Ok(Async::Ready(None));
+                } else {
+                    return Err(Error::UnexpectedEof.into());
+                }
+            }
+        }
+    }
+
+    fn read_head(&mut self) -> Poll<Option<usize>, io::Error> {
+        loop {
+            // Decoding the head updates the state carried across frames. A
+            // head that fails to decode is retried from the saved state.
+            let saved = self.head_state();
+
+            match self.decode_head() {
+                Ok(Some(n)) => return Ok(Async::Ready(Some(n))),
+                Ok(None) => {}
+                Err(e) => {
+                    self.restore_head_state(saved);
+
+                    match self.on_error(&e) {
+                        ErrorAction::Abort => {
+                            self.state = ReadState::Done;
+                            return Err(e);
+                        }
+                        ErrorAction::Skip => {
+                            // Resync by shifting the head by one byte
+                            self.buf.drain_to(1);
+                            continue;
+                        }
+                        ErrorAction::Retry => return Err(e),
+                    }
+                }
+            }
+
+            // Ensure the buffer has enough space for the rest of the head,
+            // which may be longer than the length field
+            let mut rem = self.head_len().saturating_sub(self.buf.len());
+
+            if self.gap_pending {
+                rem += self.builder.inter_frame_gap.len();
+            }
+
+            // Make room for the typical frame, so that it may be read at once
+            if self.builder.adaptive_buffer {
+                rem = cmp::max(rem, self.frame_estimate.saturating_sub(self.buf.len()));
+            }
+
+            self.buf.reserve(rem);
+
+            // Try reading the rest of the head
+            let read = try_ready!(self.read_upstream());
+
+            // If 0 bytes have been read, then the upstream has been shutdown.
+            if read == 0 {
+                if self.buf.is_empty() || self.builder.allow_trailing {
+                    return Ok(Async::Ready(None));
+                } else {
+                    return Err(Error::UnexpectedEof.into());
+                }
+            }
+        }
+    }
+
+    fn head_state(&self) -> HeadState {
+        HeadState {
+            prev_len: self.prev_len,
+            last_nonce: self.last_nonce,
+            tag: self.tag,
+            content_hash: self.content_hash,
+            order_checked: self.order_checked,
+            order_plausible: self.order_plausible,
+        }
+    }
+
+    fn restore_head_state(&mut self, state: HeadState) {
+        self.prev_len = state.prev_len;
+        self.last_nonce = state.last_nonce;
+        self.tag = state.tag;
+        self.content_hash = state.content_hash;
+        self.order_checked = state.order_checked;
+        self.order_plausible = state.order_plausible;
+    }
+
+    // Read from the upstream into the buffer, staying within the per poll
+    // read limit
+    fn read_upstream(&mut self) -> Poll<usize, io::Error> {
+        if self.buffered_only {
+            return Ok(Async::NotReady);
+        }
+
+        let mut max = None;
+
+        if let Some(limit) = self.builder.read_chunk_limit {
+            if self.last_read >= limit {
+                // Yield to other tasks, and have this one polled again
+                task::park().unpark();
+                return Ok(Async::NotReady);
+            }
+
+            max = Some((limit - self.last_read) as u64);
+        }
+
+        if let Some(ref mut rate) = self.rate {
+            let available = try_ready!(rate.poll_tokens());
+            max = Some(cmp::min(max.unwrap_or(available), available));
+        }
+
+        let res = match max {
+            Some(max) => (&mut self.inner).take(max).try_read_buf(&mut self.buf),
+            None => self.inner.try_read_buf(&mut self.buf),
+        };
+
+        self.builder.report_io(IoKind::Read, &res);
+
+        let read = try_ready!(res);
+        self.last_read += read;
+
+        if let Some(ref mut rate) = self.rate {
+            rate.tokens -= read as u64;
+        }
+
+        match self.state {
+            ReadState::Data(..) => {}
+            _ => self.head_read += read,
+        }
+
+        Ok(Async::Ready(read))
+    }
+
+    // Ask the `on_error` callback how to handle a framing error. Without a
+    // callback, the error is yielded and the decoder left as is.
+    fn on_error(&self, err: &io::Error) -> ErrorAction {
+        match self.builder.on_error {
+            Some(ref f) => (&mut *f.inner.lock().unwrap())(err),
+            None => ErrorAction::Retry,
+        }
+    }
+
+    // Charge the payload of the current frame against the memory budget, if
+    // one is configured. Returns `NotReady` when the budget is exhausted.
+    fn charge_budget(&mut self, n: usize) -> Poll<(), io::Error> {
+        // The length of a lines frame is not measured in bytes
+        if self.charge.is_some() || self.builder.length_counts_lines {
+            return Ok(Async::Ready(()));
+        }
+
+        if let Some(ref budget) = self.builder.memory_budget {
+            // Outside of a task, there is no task to notify on release
+            let acquired = if self.buffered_only {
+                budget.acquire(n)
+            } else {
+                budget.try_acquire(n)
+            };
+
+            if !acquired {
+                return Ok(Async::NotReady);
+            }
+
+            self.charge = Some(Charge {
+                budget: budget.clone(),
+                amount: n,
+            });
+        }
+
+        Ok(Async::Ready(()))
+    }
+
+    fn read_data(&mut self, n: usize) -> Poll<Option<BytesMut>, io::Error> {
+        if self.builder.length_counts_lines {
+            return self.read_lines(n);
+        }
+
+        // Padded frames always occupy `pad_to` bytes on the wire
+        let wire_n = self.builder.pad_to.unwrap_or(n);
+
+        // Bracketed frames repeat the length field after the payload, which
+        // is followed by the trailer
+        let suffix_n = self.bracket.len() + self.builder.trailer_len;
+
+        // At this point, the buffer has already had the required capacity
+        // reserved. All there is to do is read.
+        loop {
+            if self.buf.len() >= wire_n + suffix_n {
+                let mut ret = self.buf.drain_to(wire_n);
+                ret.truncate(n);
+
+                if !self.bracket.is_empty() && self.buf.drain_to(self.bracket.len())[..] != self.bracket[..] {
+                    // Drop the trailer too, it belongs to the rejected frame
+                    let trailer_len = self.builder.trailer_len;
+                    self.buf.drain_to(trailer_len);
+
+                    self.discard_frame();
+                    return Err(Error::LengthMismatch.into());
+                }
+
+                if self.builder.trailer_len > 0 {
+                    let trailer = self.buf.drain_to(self.builder.trailer_len);
+
+                    if let Some(expect) = self.builder.compute_trailer(&ret) {
+                        if trailer[..] != expect[..] {
+                            self.discard_frame();
+                            return Err(Error::TrailerMismatch.into());
+                        }
                     }
                 }
-                ReadState::Data(n) => {
-                    let data = try_ready!(self.read_data(n));
-                    self.state = ReadState::Head;
-                    return Ok(Async::Ready(data));
+
+                return Ok(Async::Ready(Some(ret)));
+            }
+
+            let read = try_ready!(self.read_upstream());
+
+            // Same as `read_head` except that the upstream should never
+            // shutdown at this point, thus making a shutdown an error unless
+            // trailing bytes are allowed. The error hands back the partial
+            // payload.
+            if read == 0 {
+                if self.builder.allow_trailing {
+                    return Ok(Async::Ready(None));
+                }
+
+                let received = cmp::min(self.buf.len(), n);
+
+                return Err(Error::TruncatedPayload {
+                    received: self.buf.drain_to(received),
+                    expected: n,
+                }.into());
+            }
+        }
+    }
+
+    // Move on to the next frame head after a frame was consumed from the
+    // buffer but rejected
+    fn discard_frame(&mut self) {
+        self.gap_pending = !self.builder.inter_frame_gap.is_empty();
+        self.charge = None;
+        self.state = ReadState::Head;
+    }
+
+    // Returns the position just past the `n`th buffered newline, only
+    // searching the bytes buffered since the last call
+    fn nth_line_end(&mut self, n: usize) -> Option<usize> {
+        if n == 0 {
+            return Some(0);
+        }
+
+        while let Some(i) = memchr::memchr(b'\n', &self.buf[self.searched..]) {
+            self.searched += i + 1;
+            self.lines_seen += 1;
+
+            if self.lines_seen == n {
+                return Some(self.searched);
+            }
+        }
+
+        self.searched = self.buf.len();
+        None
+    }
+
+    // Read until `n` newline characters have been buffered, yielding
+    // everything up to and including the last one.
+    fn read_lines(&mut self, n: usize) -> Poll<Option<BytesMut>, io::Error> {
+        loop {
+            if let Some(pos) = self.nth_line_end(n) {
+                let ret = self.buf.drain_to(pos);
+                self.searched = 0;
+                self.lines_seen = 0;
+                return Ok(Async::Ready(Some(ret)));
+            }
+
+            if self.buf.len() >= self.builder.max_frame_len {
+                return Err(Error::FrameTooLarge {
+                    len: self.buf.len() as u64,
+                    max: self.builder.max_frame_len,
+                }.into());
+            }
+
+            self.buf.reserve(LINES_READ_SIZE);
+
+            let read = try_ready!(self.read_upstream());
+
+            if read == 0 {
+                return self.trailing_eof();
+            }
+        }
+    }
+}
+
+impl<T> Decoder<T> {
+    // Number of bytes in the head of the buffered frame. This depends on the
+    // value of the length field when an extended length is configured.
+    fn head_len(&self) -> usize {
+        if self.builder.length_ascii.is_some() {
+            // The head ends at a delimiter that has not been buffered yet
+            return self.buf.len() + 1;
+        }
+
+        if !self.builder.formats.is_empty() {
+            // The version byte selects the layout of the rest of the head
+            return match self.buf.first() {
+                Some(version) => {
+                    self.builder.formats.get(version)
+                        .map(|spec| 1 + spec.num_head_bytes())
+                        .unwrap_or(1)
+                }
+                None => 1,
+            };
+        }
+
+        let head_len = self.builder.num_head_bytes();
+
+        if let Some((sentinel, ext_len)) = self.builder.extended_length {
+            let offset = self.builder.length_field_offset;
+            let field_len = self.builder.length_field_len;
+
+            if self.buf.len() >= offset + field_len {
+                let n = self.builder.read_length(&self.buf[offset..]);
+
+                if n == sentinel {
+                    return head_len + ext_len;
+                }
+            }
+        }
+
+        head_len
+    }
+
+    // Parse and consume the frame head from the buffer, returning the length
+    // of the payload, or `None` if the head has not been fully buffered.
+    fn decode_head(&mut self) -> io::Result<Option<usize>> {
+        if self.gap_pending {
+            let gap_len = self.builder.inter_frame_gap.len();
+
+            if self.buf.len() < gap_len {
+                return Ok(None);
+            }
+
+            if self.buf[..gap_len] != self.builder.inter_frame_gap[..] {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid inter-frame gap"));
+            }
+
+            self.buf.drain_to(gap_len);
+            self.gap_pending = false;
+        }
+
+        if let Some(delimiter) = self.builder.length_ascii {
+            return self.decode_ascii_head(delimiter);
+        }
+
+        if !self.builder.formats.is_empty() {
+            return self.decode_format_head();
+        }
+
+        let head_len = self.head_len();
+        let field_len = self.builder.length_field_len;
+
+        if self.buf.len() < head_len {
+            return Ok(None);
+        }
+
+        // Enough data has been buffered to process the head
+        let buffered = self.buf.len();
+        let order = self.builder.length_field_order;
+
+        // Number of extended length bytes in this head
+        let mut ext = 0;
+
+        let n = {
+            let head = &self.buf[..];
+
+            // Skip the required bytes
+            let mut pos = self.builder.length_field_offset;
+
+            let mut n = self.builder.read_length(&head[pos..]);
+
+            if self.builder.length_bits.is_none() && field_len > 1 && field_len <= 8 {
+                let field = &head[pos..pos + field_len];
+                let other = cmp::min(BigEndian::read_uint(field, field_len),
+                                     LittleEndian::read_uint(field, field_len));
+
+                self.order_checked += 1;
+
+                if n <= other {
+                    self.order_plausible += 1;
+                }
+            }
+
+            pos += field_len;
+
+            // The real length follows the length field when it holds the
+            // sentinel value
+            if let Some((sentinel, ext_len)) = self.builder.extended_length {
+                if n == sentinel {
+                    n = order.read_uint(&head[pos..], ext_len);
+                    pos += ext_len;
+                    ext = ext_len;
+                }
+            }
+
+            // The content hash immediately follows the length field
+            if self.builder.content_hash_len > 0 {
+                let hash = order.read_uint(&head[pos..], self.builder.content_hash_len);
+                self.content_hash = Some(hash);
+            }
+
+            // Reject frames whose nonce does not increase
+            if let Some((offset, len)) = self.builder.nonce_field {
+                let nonce = order.read_uint(&head[offset + ext..], len);
+
+                if let Some(last) = self.last_nonce {
+                    if nonce <= last {
+                        return Err(Error::Replay { nonce: nonce, last: last }.into());
+                    }
                 }
+
+                self.last_nonce = Some(nonce);
+            }
+
+            if let Some((offset, len)) = self.builder.tag_field {
+                self.tag = Some(order.read_uint(&head[offset..], len));
+            }
+
+            n
+        };
+
+        // Apply the signed delta to the previous frame's length
+        let n = if self.builder.delta_length {
+            let delta = sign_extend(n, field_len);
+            let n = self.prev_len as i64 + delta;
+
+            if n < 0 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "negative frame length"));
+            }
+
+            self.prev_len = n as u64;
+            n as u64
+        } else {
+            n
+        };
+
+        if n > self.builder.max_frame_len as u64 {
+            return Err(Error::FrameTooLarge {
+                len: n,
+                max: self.builder.max_frame_len,
+            }.into());
+        }
+
+        // Checked separately from the max frame length, as a 4 byte length
+        // may not fit on 32 bit targets
+        let n = try!(to_usize(n));
+
+        // Adjust `n` with bounds checking
+        let n = match adjust_len(n, self.builder.length_adjustment) {
+            Some(n) => n,
+            None => return Err(Error::LengthOverflow.into()),
+        };
+
+        // A positive adjustment may push the payload over the limit
+        if n > self.builder.max_frame_len {
+            return Err(Error::FrameTooLarge {
+                len: n as u64,
+                max: self.builder.max_frame_len,
+            }.into());
+        }
+
+        // Total number of head bytes to skip
+        let num_skip = self.builder.num_skip() + ext;
+
+        // Remove the header bytes from the length if they are counted
+        let n = if self.builder.length_includes_header {
+            match n.checked_sub(num_skip) {
+                Some(n) => n,
+                None => return Err(io::Error::new(io::ErrorKind::InvalidData, "frame length smaller than header")),
+            }
+        } else {
+            n
+        };
+
+        if let Some(size) = self.builder.pad_to {
+            if n > size {
+                return Err(Error::FrameTooLarge {
+                    len: n as u64,
+                    max: size,
+                }.into());
+            }
+        }
+
+        // Keep the length field to check it against the suffix
+        if self.builder.bracketed_length {
+            let start = self.builder.length_field_offset;
+
+            self.bracket.clear();
+            self.bracket.extend_from_slice(&self.buf[start..start + field_len + ext]);
+        }
+
+        // TODO: Add a config setting to not consume the head
+        let head = self.buf.drain_to(num_skip);
+
+        if self.keep_head {
+            self.head = head;
+        }
+
+        // Track the number of bytes consumed from the wire by the head
+        self.head_wire_len = buffered - self.buf.len();
+
+        // Ensure that the buffer has enough space to read the incoming
+        // payload. When counting lines, the payload size in bytes is
+        // not known up front.
+        if !self.builder.length_counts_lines {
+            self.buf.reserve(self.builder.pad_to.unwrap_or(n) + self.bracket.len() + self.builder.trailer_len);
+        }
+
+        Ok(Some(n))
+    }
+
+    // Parse and consume a frame head holding the length as decimal text
+    fn decode_ascii_head(&mut self, delimiter: u8) -> io::Result<Option<usize>> {
+        let max = self.builder.max_frame_len;
+        let mut n: u64 = 0;
+
+        let pos = match self.buf.iter().position(|&b| b == delimiter) {
+            Some(pos) => pos,
+            None => self.buf.len(),
+        };
+
+        for &b in &self.buf[..pos] {
+            if b < b'0' || b > b'9' {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid length digit"));
+            }
+
+            n = match n.checked_mul(10).and_then(|n| n.checked_add((b - b'0') as u64)) {
+                Some(n) => n,
+                None => return Err(Error::LengthOverflow.into()),
+            };
+
+            // Fail as soon as the length is too large, rather than waiting
+            // for the delimiter
+            if n > max as u64 {
+                return Err(Error::FrameTooLarge {
+                    len: n,
+                    max: max,
+                }.into());
+            }
+        }
+
+        if pos == self.buf.len() {
+            return Ok(None);
+        }
+
+        if pos == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "missing length digits"));
+        }
+
+        let n = match adjust_len(try!(to_usize(n)), self.builder.length_adjustment) {
+            Some(n) => n,
+            None => return Err(Error::LengthOverflow.into()),
+        };
+
+        if n > max {
+            return Err(Error::FrameTooLarge {
+                len: n as u64,
+                max: max,
+            }.into());
+        }
+
+        // Skip the digits along with the delimiter
+        let head = self.buf.drain_to(pos + 1);
+        self.head_wire_len = pos + 1;
+
+        if self.keep_head {
+            self.head = head;
+        }
+
+        if !self.builder.length_counts_lines {
+            self.buf.reserve(self.builder.pad_to.unwrap_or(n) + self.builder.trailer_len);
+        }
+
+        Ok(Some(n))
+    }
+
+    // Parse and consume a frame head laid out according to the format
+    // registered for its leading version byte
+    fn decode_format_head(&mut self) -> io::Result<Option<usize>> {
+        let version = match self.buf.first() {
+            Some(&version) => version,
+            None => return Ok(None),
+        };
+
+        let spec = match self.builder.formats.get(&version) {
+            Some(spec) => *spec,
+            None => return Err(Error::UnknownFormat(version).into()),
+        };
+
+        if self.buf.len() < 1 + spec.num_head_bytes() {
+            return Ok(None);
+        }
+
+        let pos = 1 + spec.length_field_offset;
+        let order = spec.length_field_order;
+        let n = order.read_wide_uint(&self.buf[pos..], spec.length_field_len);
+
+        if n > self.builder.max_frame_len as u64 {
+            return Err(Error::FrameTooLarge {
+                len: n,
+                max: self.builder.max_frame_len,
+            }.into());
+        }
+
+        let n = try!(to_usize(n));
+
+        let n = match adjust_len(n, spec.length_adjustment) {
+            Some(n) => n,
+            None => return Err(Error::LengthOverflow.into()),
+        };
+
+        if n > self.builder.max_frame_len {
+            return Err(Error::FrameTooLarge {
+                len: n as u64,
+                max: self.builder.max_frame_len,
+            }.into());
+        }
+
+        // Skip the version byte along with the rest of the head
+        let num_skip = 1 + spec.num_skip();
+
+        let head = self.buf.drain_to(num_skip);
+        self.head_wire_len = num_skip;
+
+        if self.keep_head {
+            self.head = head;
+        }
+
+        if !self.builder.length_counts_lines {
+            self.buf.reserve(self.builder.pad_to.unwrap_or(n) + self.builder.trailer_len);
+        }
+
+        Ok(Some(n))
+    }
+
+    // The upstream has shutdown in the middle of a frame
+    fn trailing_eof(&self) -> Poll<Option<BytesMut>, io::Error> {
+        if self.builder.allow_trailing {
+            Ok(Async::Ready(None))
+        } else {
+            Err(Error::UnexpectedEof.into())
+        }
+    }
+}
+
+impl<T: AsyncRead> Stream for Decoder<T> {
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<BytesMut>, io::Error> {
+        self.last_read = 0;
+        self.head_read = 0;
+
+        let res = self.poll_frame();
+
+        if let Some(ref f) = self.builder.on_progress {
+            let progress = Progress {
+                head: self.head_read,
+                payload: self.last_read - self.head_read,
+                ready: match res {
+                    Ok(Async::NotReady) => false,
+                    _ => true,
+                },
+            };
+
+            (&mut *f.inner.lock().unwrap())(progress);
+        }
+
+        res
+    }
+}
+
+impl<T: AsyncRead> Decoder<T> {
+    fn poll_frame(&mut self) -> Poll<Option<BytesMut>, io::Error> {
+        if let Some(frame) = self.redeliver.take() {
+            return Ok(Async::Ready(Some(frame)));
+        }
+
+        let n = match try_ready!(self.poll_head()) {
+            Some(n) => n,
+            None => return Ok(Async::Ready(None)),
+        };
+
+        try_ready!(self.charge_budget(n));
+        let data = try_ready!(self.read_data(n));
+
+        if let Some(ref data) = data {
+            self.gap_pending = !self.builder.inter_frame_gap.is_empty();
+
+            let payload_len = self.builder.pad_to.unwrap_or(data.len());
+            self.wire_len = self.head_wire_len + payload_len + self.bracket.len() + self.builder.trailer_len;
+
+            if self.builder.adaptive_buffer {
+                self.frame_estimate = if self.frame_estimate == 0 {
+                    self.wire_len
+                } else {
+                    (self.frame_estimate * 7 + self.wire_len) / 8
+                };
+            }
+        }
+
+        self.charge = None;
+        self.state = ReadState::Head;
+        Ok(Async::Ready(data))
+    }
+
+    // Read up to the end of the next frame head, returning the payload
+    // length. The length is kept in the read state until the payload is read.
+    fn poll_head(&mut self) -> Poll<Option<usize>, io::Error> {
+        loop {
+            match self.state {
+                ReadState::Banner => {
+                    match try_ready!(self.read_banner()) {
+                        Some(()) => self.state = ReadState::Head,
+                        None => return Ok(Async::Ready(None)),
+                    }
+                }
+                ReadState::Head => {
+                    match try_ready!(self.read_head()) {
+                        Some(0) if !self.builder.allow_empty_frames => {
+                            return Err(io::Error::new(io::ErrorKind::InvalidData, "empty frame"));
+                        }
+                        Some(n) => self.state = ReadState::Data(n),
+                        None => return Ok(Async::Ready(None)),
+                    }
+                }
+                ReadState::Data(n) => return Ok(Async::Ready(Some(n))),
+                ReadState::Done => return Ok(Async::Ready(None)),
+            }
+        }
+    }
+}
+
+impl<'a, T> Txn<'a, T> {
+    /// Consume the frame
+    pub fn commit(self) {
+    }
+
+    /// Hand the frame back to the decoder, to be yielded again by the next
+    /// poll
+    ///
+    /// The frame is yielded as given, so it should be the one returned by
+    /// `poll_transactional`, unmodified.
+    pub fn rollback(self, frame: BytesMut) {
+        self.decoder.redeliver = Some(frame);
+    }
+}
+
+impl<T> WithWireLen<T> {
+    pub fn get_ref(&self) -> &Decoder<T> {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut Decoder<T> {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> Decoder<T> {
+        self.inner
+    }
+}
+
+impl<T: AsyncRead> Stream for WithWireLen<T> {
+    type Item = (usize, BytesMut);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<(usize, BytesMut)>, io::Error> {
+        let frame = try_ready!(self.inner.poll());
+        Ok(Async::Ready(frame.map(|frame| (self.inner.wire_len, frame))))
+    }
+}
+
+impl<T> DecoderBytes<T> {
+    pub fn get_ref(&self) -> &Decoder<T> {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut Decoder<T> {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> Decoder<T> {
+        self.inner
+    }
+}
+
+impl<T: AsyncRead> Stream for DecoderBytes<T> {
+    type Item = Bytes;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, io::Error> {
+        let frame = try_ready!(self.inner.poll());
+        Ok(Async::Ready(frame.map(BytesMut::freeze)))
+    }
+}
+
+impl<T> TaggedFrames<T> {
+    pub fn get_ref(&self) -> &Decoder<T> {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut Decoder<T> {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> Decoder<T> {
+        self.inner
+    }
+}
+
+impl<T: AsyncRead> Stream for TaggedFrames<T> {
+    type Item = (u64, BytesMut);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<(u64, BytesMut)>, io::Error> {
+        let frame = try_ready!(self.inner.poll());
+        let tag = self.inner.tag.unwrap_or(0);
+
+        Ok(Async::Ready(frame.map(|frame| (tag, frame))))
+    }
+}
+
+impl<T> FramesWithHeaders<T> {
+    pub fn get_ref(&self) -> &Decoder<T> {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut Decoder<T> {
+        &mut self.inner
+    }
+
+    pub fn into_inner(mut self) -> Decoder<T> {
+        self.inner.keep_head = false;
+        self.inner
+    }
+}
+
+impl<T: AsyncRead> Stream for FramesWithHeaders<T> {
+    type Item = (BytesMut, BytesMut);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<(BytesMut, BytesMut)>, io::Error> {
+        let frame = try_ready!(self.inner.poll());
+        Ok(Async::Ready(frame.map(|frame| (mem::replace(&mut self.inner.head, BytesMut::new()), frame))))
+    }
+}
+
+impl<S, F> MapFrames<S, F> {
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S, F, U> Stream for MapFrames<S, F>
+    where S: Stream<Item = BytesMut, Error = io::Error>,
+          F: FnMut(BytesMut) -> io::Result<U>,
+{
+    type Item = U;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<U>, io::Error> {
+        if self.done {
+            return Ok(Async::Ready(None));
+        }
+
+        let frame = match try_ready!(self.inner.poll()) {
+            Some(frame) => frame,
+            None => return Ok(Async::Ready(None)),
+        };
+
+        match (self.f)(frame) {
+            Ok(msg) => Ok(Async::Ready(Some(msg))),
+            Err(e) => {
+                self.done = true;
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<T> Clone for SharedFrames<T> {
+    fn clone(&self) -> SharedFrames<T> {
+        let id = {
+            let mut shared = self.inner.lock().unwrap();
+            shared.next_id += 1;
+            shared.next_id - 1
+        };
+
+        SharedFrames {
+            inner: self.inner.clone(),
+            id: id,
+        }
+    }
+}
+
+impl<T> Drop for SharedFrames<T> {
+    fn drop(&mut self) {
+        if let Ok(mut shared) = self.inner.lock() {
+            shared.waiters.remove(&self.id);
+
+            // This clone may have been the one registered with the upstream
+            for (_, task) in shared.waiters.drain() {
+                task.unpark();
+            }
+        }
+    }
+}
+
+impl<T: AsyncRead> Stream for SharedFrames<T> {
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<BytesMut>, io::Error> {
+        let mut shared = self.inner.lock().unwrap();
+
+        match shared.decoder.poll() {
+            Ok(Async::NotReady) => {
+                // Only the most recent task is registered with the upstream,
+                // so remember this one to be notified once another clone
+                // makes progress.
+                shared.waiters.insert(self.id, task::park());
+                Ok(Async::NotReady)
+            }
+            ret => {
+                // Let the waiting clones poll again, whichever polls next
+                // registers itself with the upstream.
+                for (_, task) in shared.waiters.drain() {
+                    task.unpark();
+                }
+
+                ret
+            }
+        }
+    }
+}
+
+impl<T: Write> Write for Decoder<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: Sink> Sink for Decoder<T> {
+    type SinkItem = T::SinkItem;
+    type SinkError = T::SinkError;
+
+    fn start_send(&mut self, item: T::SinkItem)
+        -> StartSend<T::SinkItem, T::SinkError>
+    {
+        self.inner.start_send(item)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), T::SinkError> {
+        self.inner.poll_complete()
+    }
+}
+
+/*
+ *
+ * ===== impl Encoder =====
+ *
+ */
+
+impl<T, B: IntoBuf> Encoder<T, B> {
+    pub fn default(io: T) -> Encoder<T, B> {
+        Builder::new().encoder(io)
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// Returns a copy of the configuration the encoder was built with
+    ///
+    /// This is useful to build a decoder for the other direction.
+    pub fn builder(&self) -> Builder {
+        self.builder.clone()
+    }
+
+    /// Write out all pending frames, then flush the upstream
+    ///
+    /// Completing `send`, or `poll_complete`, only means the frames have
+    /// been written to the upstream. A buffered upstream may still hold
+    /// them, unless `set_flush_between_frames` is enabled. The returned
+    /// future resolves to the encoder once the upstream has been flushed.
+    pub fn flush(self) -> Flush<Self> {
+        Flush { inner: Some(self) }
+    }
+
+    /// Returns true if no frame is being written or flushed
+    ///
+    /// `start_send` accepts the next frame right away when this returns
+    /// true. It may also accept frames while this returns false, queueing
+    /// them behind the current frame up to `set_max_pending_frames`.
+    pub fn is_ready(&self) -> bool {
+        match self.state {
+            WriteState::Ready => true,
+            _ => false,
+        }
+    }
+
+    /// Returns the number of bytes accepted by `start_send` that have not
+    /// been written to the upstream yet
+    ///
+    /// This covers the head, payload, padding and suffix of the frame being
+    /// written, along with any queued frames.
+    pub fn pending_write_bytes(&self) -> usize {
+        let frame = match self.state {
+            WriteState::Head { ref head, ref data } => head.remaining() + data.remaining(),
+            WriteState::Data(ref data) => data.remaining(),
+            _ => 0,
+        };
+
+        let suffix = self.suffix.as_ref().map(|s| s.remaining()).unwrap_or(0);
+
+        frame + self.padding + suffix + self.queued.len()
+    }
+
+    /// Returns the number of bytes accepted by `start_send` that have not
+    /// been written to the upstream yet
+    ///
+    /// This is the same as `pending_write_bytes`.
+    pub fn pending_bytes(&self) -> usize {
+        self.pending_write_bytes()
+    }
+
+    // Replace the I/O object, keeping the write state
+    fn map_inner<U, F>(self, f: F) -> Encoder<U, B>
+        where F: FnOnce(T) -> U,
+    {
+        Encoder {
+            inner: f(self.inner),
+            builder: self.builder,
+            state: self.state,
+            padding: self.padding,
+            suffix: self.suffix,
+            prev_len: self.prev_len,
+            nonce: self.nonce,
+            written: self.written,
+            acks: self.acks,
+            queued: self.queued,
+            num_queued: self.num_queued,
+        }
+    }
+}
+
+impl<T: AsyncWrite, B: IntoBuf> Encoder<T, B> {
+    fn set_head(&mut self, mut buf: B::Buf) -> io::Result<()> {
+        let (mut head, padding) = try!(self.encode_head(&buf));
+
+        self.suffix = self.encode_suffix(&head, buf.bytes());
+        self.padding = padding;
+
+        if self.builder.message_oriented {
+            head = self.assemble_frame(head, &mut buf);
+        }
+
+        self.state = WriteState::Head { head: head, data: buf };
+        Ok(())
+    }
+
+    // Copy the whole frame into a single buffer, leaving `buf`, the padding
+    // and the suffix empty
+    fn assemble_frame(&mut self, head: ByteBuf, buf: &mut B::Buf) -> ByteBuf {
+        let suffix = self.suffix.take();
+        let suffix_len = suffix.as_ref().map(|s| s.remaining()).unwrap_or(0);
+
+        let len = head.remaining() + buf.remaining() + self.padding + suffix_len;
+        let mut frame = ByteBuf::with_capacity(len);
+
+        frame.put_slice(head.bytes());
+
+        while buf.has_remaining() {
+            let n = {
+                let bytes = buf.bytes();
+                frame.put_slice(bytes);
+                bytes.len()
+            };
+
+            buf.advance(n);
+        }
+
+        for _ in 0..self.padding {
+            frame.put_u8(self.builder.pad_byte);
+        }
+
+        if let Some(suffix) = suffix {
+            frame.put_slice(suffix.bytes());
+        }
+
+        self.padding = 0;
+        frame
+    }
+
+    // Encode a frame behind the frame currently being written
+    fn queue_frame(&mut self, mut buf: B::Buf) -> io::Result<()> {
+        let (head, padding) = try!(self.encode_head(&buf));
+        let suffix = self.encode_suffix(&head, buf.bytes());
+
+        self.queued.extend(head.bytes());
+
+        while buf.has_remaining() {
+            let n = {
+                let bytes = buf.bytes();
+                self.queued.extend(bytes);
+                bytes.len()
+            };
+
+            buf.advance(n);
+        }
+
+        self.queued.extend(iter::repeat(self.builder.pad_byte).take(padding));
+
+        if let Some(suffix) = suffix {
+            self.queued.extend(suffix.bytes());
+        }
+
+        self.num_queued += 1;
+
+        Ok(())
+    }
+
+    // Encode what follows the payload: a copy of the length field from the
+    // start of the head, if the length is bracketed, the trailer and the
+    // inter-frame gap
+    fn encode_suffix(&self, head: &ByteBuf, data: &[u8]) -> Option<ByteBuf> {
+        if self.builder.passthrough {
+            return None;
+        }
+
+        let start = self.builder.length_field_offset;
+        let mut len = 0;
+
+        if self.builder.bracketed_length {
+            len = self.builder.length_field_len;
+
+            // Include the extended length, if the head holds one
+            if let Some((sentinel, ext_len)) = self.builder.extended_length {
+                if self.builder.read_length(&head.bytes()[start..]) == sentinel {
+                    len += ext_len;
+                }
+            }
+        }
+
+        let gap = &self.builder.inter_frame_gap;
+
+        if len == 0 && self.builder.trailer_len == 0 && gap.is_empty() {
+            return None;
+        }
+
+        let mut suffix = ByteBuf::with_capacity(len + self.builder.trailer_len + gap.len());
+        suffix.put_slice(&head.bytes()[start..start + len]);
+
+        if self.builder.trailer_len > 0 {
+            let trailer = self.builder.compute_trailer(data)
+                .unwrap_or_else(|| vec![0; self.builder.trailer_len]);
+
+            suffix.put_slice(&trailer);
+        }
+
+        suffix.put_slice(gap);
+
+        Some(suffix)
+    }
+
+    // Encode the head of a frame, returning it along with the number of
+    // padding bytes following the payload
+    fn encode_head(&mut self, buf: &B::Buf) -> io::Result<(ByteBuf, usize)> {
+        let n = buf.remaining();
+
+        if n > self.builder.max_frame_len {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "frame too big"));
+        }
+
+        if n == 0 && !self.builder.allow_empty_frames {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "empty frame"));
+        }
+
+        // The item is already framed
+        if self.builder.passthrough {
+            return Ok((ByteBuf::new(), 0));
+        }
+
+        let padding = match self.builder.pad_to {
+            Some(size) if n > size => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "frame larger than padded size"));
+            }
+            Some(size) => size - n,
+            None => 0,
+        };
+
+        // The length is written as decimal text followed by the delimiter
+        if let Some(delimiter) = self.builder.length_ascii {
+            let digits = n.to_string();
+
+            let mut head = ByteBuf::with_capacity(digits.len() + 1);
+            head.put_slice(digits.as_bytes());
+            head.put_u8(delimiter);
+
+            return Ok((head, padding));
+        }
+
+        let mut n = if self.builder.length_includes_header {
+            n + self.builder.num_skip()
+        } else {
+            n
+        };
+
+        // Decide on the extended length field first, as the header bytes
+        // counted in the length include it
+        let ext = match self.builder.extended_length {
+            Some((sentinel, ext_len)) if try!(self.length_field(n)) >= sentinel => ext_len,
+            _ => 0,
+        };
+
+        if self.builder.length_includes_header {
+            n += ext;
+        }
+
+        let field = try!(self.length_field(n));
+
+        let mut head = ByteBuf::with_capacity(self.builder.num_head_bytes() + ext);
+        let order = self.builder.length_field_order;
+
+        // Write the bytes before the length field, zero past the prefix
+        for i in 0..self.builder.length_field_offset {
+            head.put_u8(self.builder.length_field_prefix.get(i).cloned().unwrap_or(0));
+        }
+
+        match self.builder.extended_length {
+            // The length does not fit in the length field, write the sentinel
+            // followed by the extended length
+            Some((sentinel, ext_len)) if ext > 0 => {
+                try!(self.builder.put_length(&mut head, sentinel));
+                order.put_uint(&mut head, field, ext_len);
+            }
+            _ => {
+                try!(self.builder.put_length(&mut head, field));
+            }
+        }
+
+        if self.builder.content_hash_len > 0 {
+            let hash = content_hash(buf.bytes(), self.builder.content_hash_len);
+            order.put_uint(&mut head, hash, self.builder.content_hash_len);
+        }
+
+        if let Some((offset, len)) = self.builder.nonce_field {
+            // The nonce offset is within the head without the extended length,
+            // which shifts the nonce along with the rest of the head
+            let pos = head.remaining() - ext;
+
+            if offset < pos {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "nonce field overlaps the length field"));
+            }
+
+            if len < 8 && self.nonce >> (len * 8) != 0 {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "nonce field exhausted"));
+            }
+
+            for _ in pos..offset {
+                head.put_u8(0);
+            }
+
+            order.put_uint(&mut head, self.nonce, len);
+            self.nonce += 1;
+        }
+
+        // Any remaining head bytes skipped by the decoder are written as zeros
+        while head.remaining() < self.builder.num_skip() + ext {
+            head.put_u8(0);
+        }
+
+        // The head can no longer be rejected, the next delta is relative to
+        // this frame
+        if self.builder.delta_length {
+            self.prev_len = n as u64;
+        }
+
+        Ok((head, padding))
+    }
+
+    // Value of the length field for a frame of length `n`, encoded as a
+    // signed delta from the previous frame's length when configured
+    fn length_field(&self, n: usize) -> io::Result<u64> {
+        if !self.builder.delta_length {
+            return Ok(n as u64);
+        }
+
+        let field_len = self.builder.length_field_len;
+        let delta = n as i64 - self.prev_len as i64;
+
+        if sign_extend(truncate(delta as u64, field_len), field_len) != delta {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "length delta does not fit in the length field"));
+        }
+
+        Ok(truncate(delta as u64, field_len))
+    }
+
+    // Write a frame head. This function will be called as part of
+    // `FramedIo::flush`. The head is written together with the start of the
+    // payload, so that the upstream may write both in a single call.
+    fn write_head(&mut self) -> Poll<(), io::Error> {
+        // Loop as long as the upstream is ready
+        loop {
+            // Get a reference to the buffers.
+            let (head, data) = match self.state {
+                WriteState::Head { ref mut head, ref mut data } => (head, data),
+                _ => unreachable!(),
+            };
+
+            // If there is no more data to write, then the frame head has been
+            // fully written, so return Ok.
+            if !head.has_remaining() {
+                return Ok(Async::Ready(()));
+            };
+
+            // Write the data to the upstream. In the write case, 0 does not
+            // mean that the upstream has shutdown, so there is no need to
+            // check.
+            let res = try_write_bufs(&mut self.inner, &[head.bytes(), data.bytes()]);
+            self.builder.report_io(IoKind::Write, &res);
+
+            let n = try_ready!(res);
+
+            // Advance past the written bytes, which may extend into the
+            // payload
+            let head_n = cmp::min(n, head.remaining());
+            head.advance(head_n);
+            data.advance(n - head_n);
+
+            self.written += n;
+        }
+    }
+
+    // Write a frame payload. This function will be called as part of
+    // `FramedIo::flush`. This fn is very similar to `write_head`
+    fn write_data(&mut self) -> Poll<(), io::Error> {
+        // Loop as long as the upstream is ready
+        loop {
+            // Get a reference to the buffer.
+            let buf = match self.state {
+                WriteState::Data(ref mut buf) => buf,
+                _ => unreachable!(),
+            };
+
+            // If there is no more data to write, then the frame payload has been
+            // fully written, so return Okl
+            if !buf.has_remaining() {
+                return Ok(Async::Ready(()));
+            };
+
+            // Write the data to the upstream. In the write case, 0 does not
+            // mean that the upstream has shutdown, so there is no need to
+            // check.
+            let res = self.inner.try_write_buf(buf);
+            self.builder.report_io(IoKind::Write, &res);
+
+            self.written += try_ready!(res);
+        }
+    }
+}
+
+impl<T: AsyncWrite, B: IntoBuf> Encoder<T, B> {
+    /// Like `poll_complete`, but on completion returns the number of bytes
+    /// written to the upstream, including frame heads and payloads.
+    ///
+    /// The count covers everything written since the previous call to this
+    /// function completed, so bytes written by calls that returned
+    /// `NotReady` are included once the flush completes.
+    pub fn poll_complete_counted(&mut self) -> Poll<usize, io::Error> {
+        try_ready!(self.poll_complete());
+        Ok(Async::Ready(mem::replace(&mut self.written, 0)))
+    }
+
+    /// Finish writing any in-flight frame, then shutdown the upstream.
+    ///
+    /// This only flushes the upstream, see `AsyncWrite::shutdown`. Use
+    /// `shutdown_write` to also close the write side of a TCP stream.
+    pub fn shutdown(&mut self) -> Poll<(), io::Error> {
+        try_ready!(self.poll_complete());
+        self.inner.shutdown()
+    }
+
+    /// Finish writing any in-flight frame, then shutdown the write side of
+    /// the upstream, so that the peer reads EOF after the last frame.
+    pub fn shutdown_write(&mut self) -> Poll<(), io::Error>
+        where T: Shutdown,
+    {
+        try_ready!(self.poll_complete());
+        self.inner.try_shutdown()
+    }
+
+    /// Start sending a frame, returning a receiver that completes once the
+    /// frame has been fully written and the upstream flushed.
+    ///
+    /// The frame is written as part of `poll_complete`, which must still be
+    /// called to drive it. A frame queued behind the current one is
+    /// acknowledged once the whole batch has been flushed. If the encoder
+    /// cannot accept the frame, `Ok(Err(item))` is returned and the frame is
+    /// not sent.
+    pub fn send_acked(&mut self, item: B) -> io::Result<Result<oneshot::Receiver<()>, B>> {
+        match try!(self.start_send(item)) {
+            AsyncSink::Ready => {
+                let (tx, rx) = oneshot::channel();
+                self.acks.push(tx);
+                Ok(Ok(rx))
+            }
+            AsyncSink::NotReady(item) => Ok(Err(item)),
+        }
+    }
+
+    // Write the frames queued behind the previous frame, coalescing them into
+    // as few writes as the upstream allows
+    fn write_queued(&mut self) -> Poll<(), io::Error> {
+        while !self.queued.is_empty() {
+            let res = {
+                let (a, b) = self.queued.as_slices();
+                try_write_bufs(&mut self.inner, &[a, b])
+            };
+            self.builder.report_io(IoKind::Write, &res);
+
+            let n = try_ready!(res);
+
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::WriteZero, "zero-length write"));
+            }
+
+            self.queued.drain(..n);
+            self.written += n;
+        }
+
+        self.num_queued = 0;
+        Ok(Async::Ready(()))
+    }
+
+    // Write the length field repeated after a bracketed frame
+    fn write_suffix(&mut self) -> Poll<(), io::Error> {
+        loop {
+            let suffix = match self.suffix {
+                Some(ref mut suffix) => suffix,
+                None => return Ok(Async::Ready(())),
+            };
+
+            if !suffix.has_remaining() {
+                return Ok(Async::Ready(()));
+            }
+
+            let res = self.inner.try_write_buf(suffix);
+            self.builder.report_io(IoKind::Write, &res);
+
+            self.written += try_ready!(res);
+        }
+    }
+
+    // Write the padding following a frame payload
+    fn write_padding(&mut self) -> Poll<(), io::Error> {
+        let fill = [self.builder.pad_byte; 64];
+
+        while self.padding > 0 {
+            let len = cmp::min(self.padding, fill.len());
+            let res = self.inner.try_write(&fill[..len]);
+            self.builder.report_io(IoKind::Write, &res);
+
+            let n = try_ready!(res);
+
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::WriteZero, "zero-length write"));
+            }
+
+            self.padding -= n;
+            self.written += n;
+        }
+
+        Ok(Async::Ready(()))
+    }
+}
+
+impl<T: AsyncWrite, B: IntoBuf> Sink for Encoder<T, B> {
+    type SinkItem = B;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: B)
+        -> StartSend<B, io::Error>
+    {
+        if !try!(self.poll_complete()).is_ready() {
+            // Queue the frame behind the current one if there is room. Once
+            // the frames are being flushed, the queue is no longer written.
+            let writing = match self.state {
+                WriteState::Flush => false,
+                _ => true,
+            };
+
+            if writing && self.num_queued < self.builder.max_pending_frames && !self.builder.message_oriented {
+                try!(self.queue_frame(item.into_buf()));
+                return Ok(AsyncSink::Ready);
+            }
+
+            return Ok(AsyncSink::NotReady(item));
+        }
+
+        // Convert the value to a buffer
+        try!(self.set_head(item.into_buf()));
+
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        loop {
+            match self.state {
+                // If in the ready state, then all data has been fully flushed
+                // and there is nothing more to do
+                WriteState::Ready => return Ok(Async::Ready(())),
+
+                // Currently writing the frame head
+                WriteState::Head { .. } => {
+                    // Write the frame head, returning if `write_head` returns
+                    // an error or `NotReady`
+                    try_ready!(self.write_head());
+
+                    // The head has been fully written to the upstream, transition to
+                    // writing the payload
+                    match mem::replace(&mut self.state, WriteState::Ready) {
+                        WriteState::Head { data, .. } => {
+                            self.state = WriteState::Data(data);
+                        }
+                        _ => unreachable!(),
+                    }
+                }
+
+                // Currently writing the frame payload
+                WriteState::Data(..) => {
+                    // Write the frame payload, returning if `write_data` returns
+                    // an error or `NotReady`
+                    try_ready!(self.write_data());
+
+                    // The payload has been fully written to the upstream,
+                    // transition to padding the frame
+                    self.state = WriteState::Pad;
+                }
+
+                // Currently writing the padding following the payload
+                WriteState::Pad => {
+                    try_ready!(self.write_padding());
+                    self.state = WriteState::Suffix;
+                }
+
+                // Currently writing the length following the padding
+                WriteState::Suffix => {
+                    try_ready!(self.write_suffix());
+
+                    self.suffix = None;
+                    self.state = WriteState::Queued;
+                }
+
+                // Currently writing the frames queued behind the payload
+                WriteState::Queued => {
+                    try_ready!(self.write_queued());
+
+                    // The frames have been fully written to the upstream,
+                    // transition to flushing or ready.
+                    if self.builder.flush_between_frames || !self.acks.is_empty() {
+                        self.state = WriteState::Flush;
+                    } else {
+                        self.state = WriteState::Ready;
+                    }
+                }
+
+                // Flushing the completed frame through the upstream
+                WriteState::Flush => {
+                    try_ready!(self.inner.try_flush());
+                    self.state = WriteState::Ready;
+
+                    for ack in self.acks.drain(..) {
+                        // The receiver may have been dropped
+                        let _ = ack.send(());
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<T: Read, B: IntoBuf> Read for Encoder<T, B> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<T: Stream, B: IntoBuf> Stream for Encoder<T, B> {
+    type Item = T::Item;
+    type Error = T::Error;
+
+    fn poll(&mut self) -> Poll<Option<T::Item>, T::Error> {
+        self.inner.poll()
+    }
+}
+
+/*
+ *
+ * ===== impl Framed =====
+ *
+ */
+
+impl<T, B: IntoBuf> Framed<T, B> {
+    pub fn default(io: T) -> Framed<T, B> {
+        Builder::new().framed(io)
+    }
+
+    pub fn get_ref(&self) -> &T {
+        self.inner.get_ref().get_ref()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut().get_mut()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner().into_inner()
+    }
+
+    /// Split into a read half and a write half which may be used from
+    /// different tasks.
+    ///
+    /// The halves share the I/O object, so neither provides `into_inner`.
+    /// Use `Framed::unsplit` to recombine them and recover the I/O object.
+    pub fn split(self) -> (FramedRead<T, B>, FramedWrite<T, B>) {
+        let mut encoder = None;
+        let decoder = self.inner.map_inner(|e| encoder = Some(e));
+
+        let mut io = None;
+        let encoder = encoder.unwrap().map_inner(|i| io = Some(i));
+
+        let shared = Arc::new(Mutex::new(io.unwrap()));
+
+        let read = FramedRead {
+            inner: decoder.map_inner(|()| Shared { inner: shared.clone() }),
+            _marker: PhantomData,
+        };
+
+        let write = FramedWrite {
+            inner: encoder.map_inner(|()| Shared { inner: shared }),
+        };
+
+        (read, write)
+    }
+
+    /// Recombine the halves produced by `split`
+    ///
+    /// # Panics
+    ///
+    /// Panics if the halves did not originate from the same `Framed`.
+    pub fn unsplit(read: FramedRead<T, B>, write: FramedWrite<T, B>) -> Framed<T, B> {
+        {
+            let a = &*read.inner.get_ref().inner as *const Mutex<T>;
+            let b = &*write.inner.get_ref().inner as *const Mutex<T>;
+            assert!(a == b, "halves do not belong to the same `Framed`");
+        }
+
+        // Drop the read half's handle so that the write half holds the only
+        // reference to the I/O object
+        let decoder = read.inner.map_inner(|_| ());
+
+        let encoder = write.inner.map_inner(|shared| {
+            match Arc::try_unwrap(shared.inner) {
+                Ok(mutex) => mutex.into_inner().unwrap(),
+                Err(_) => unreachable!(),
+            }
+        });
+
+        Framed {
+            inner: decoder.map_inner(|()| encoder),
+        }
+    }
+}
+
+impl<T: AsyncRead, B: IntoBuf> Stream for Framed<T, B> {
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<BytesMut>, io::Error> {
+        self.inner.poll()
+    }
+}
+
+impl<T: AsyncWrite, B: IntoBuf> Sink for Framed<T, B> {
+    type SinkItem = B;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: B) -> StartSend<B, io::Error> {
+        self.inner.start_send(item)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        self.inner.poll_complete()
+    }
+}
+
+/*
+ *
+ * ===== impl FramedRead / FramedWrite =====
+ *
+ */
+
+impl<T: AsyncRead, B: IntoBuf> Stream for FramedRead<T, B> {
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<BytesMut>, io::Error> {
+        self.inner.poll()
+    }
+}
+
+impl<T: AsyncWrite, B: IntoBuf> Sink for FramedWrite<T, B> {
+    type SinkItem = B;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: B) -> StartSend<B, io::Error> {
+        self.inner.start_send(item)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        self.inner.poll_complete()
+    }
+}
+
+impl<T: Read> Read for Shared<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.lock().unwrap().read(buf)
+    }
+}
+
+impl<T: Write> Write for Shared<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.lock().unwrap().flush()
+    }
+}
+
+/*
+ *
+ * ===== impl Codec =====
+ *
+ */
+
+impl<B: IntoBuf> Decode for Codec<B> {
+    type Item = BytesMut;
+
+    fn decode(&mut self, buf: &mut ByteBuf) -> io::Result<Option<BytesMut>> {
+        // Hand the decoder the bytes read since the last call. They are kept
+        // in `buf` until the frame has been decoded, so that a partial frame
+        // is still buffered if the upstream is shutdown.
+        self.decoder.buf.extend_from_slice(&buf.bytes()[self.held..]);
+        self.held = buf.len();
+
+        let res = self.decoder.try_next_frame();
+
+        match res {
+            Ok(None) => {}
+            _ => {
+                let consumed = self.held - self.decoder.buf.len();
+                buf.drain_to(consumed);
+                self.held -= consumed;
+            }
+        }
+
+        res
+    }
+}
+
+impl<B: IntoBuf> Encode for Codec<B> {
+    type Item = B;
+
+    fn encode(&mut self, item: B, buf: &mut ByteBuf) -> io::Result<()> {
+        try!(self.encoder.queue_frame(item.into_buf()));
+
+        let queued = &mut self.encoder.queued;
+
+        {
+            let (a, b) = queued.as_slices();
+            buf.reserve(a.len() + b.len());
+            buf.put_slice(a);
+            buf.put_slice(b);
+        }
+
+        queued.clear();
+        self.encoder.num_queued = 0;
+
+        Ok(())
+    }
+}
+
+/*
+ *
+ * ===== impl Reconnecting =====
+ *
+ */
+
+/// Frame the connection returned by `connect`, reconnecting whenever it fails
+///
+/// `connect` is called up front and again after every transport error. The
+/// future it returns should establish the connection and run any handshake.
+/// Framing then resumes on the new connection with fresh decoder and encoder
+/// state, so frames partially read or not yet flushed at the time of the
+/// disconnect are lost unless the caller buffers and resends them.
+///
+/// Framing errors, such as a frame exceeding the max frame length, and errors
+/// returned by the connect future are yielded as is.
+pub fn reconnecting<F, Fut, T, B>(mut connect: F, builder: Builder) -> Reconnecting<F, Fut, T, B>
+    where F: FnMut() -> Fut,
+          Fut: Future<Item = T, Error = io::Error>,
+          B: IntoBuf,
+{
+    let fut = connect();
+
+    Reconnecting {
+        connect: connect,
+        builder: builder,
+        state: ConnectState::Connecting(fut),
+    }
+}
+
+impl<F, Fut, T, B> Reconnecting<F, Fut, T, B>
+    where F: FnMut() -> Fut,
+          Fut: Future<Item = T, Error = io::Error>,
+          B: IntoBuf,
+{
+    // Drive the pending connection, if any, to completion
+    fn poll_connect(&mut self) -> Poll<(), io::Error> {
+        let io = match self.state {
+            ConnectState::Connecting(ref mut fut) => try_ready!(fut.poll()),
+            ConnectState::Connected(_) => return Ok(Async::Ready(())),
+        };
+
+        self.state = ConnectState::Connected(self.builder.clone().framed(io));
+        Ok(Async::Ready(()))
+    }
+
+    // Drop the current connection and start establishing a new one
+    fn reconnect(&mut self) {
+        self.state = ConnectState::Connecting((self.connect)());
+    }
+
+    fn framed(&mut self) -> &mut Framed<T, B> {
+        match self.state {
+            ConnectState::Connected(ref mut framed) => framed,
+            ConnectState::Connecting(_) => panic!("not connected"),
+        }
+    }
+}
+
+impl<F, Fut, T, B> Stream for Reconnecting<F, Fut, T, B>
+    where F: FnMut() -> Fut,
+          Fut: Future<Item = T, Error = io::Error>,
+          T: AsyncRead,
+          B: IntoBuf,
+{
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<BytesMut>, io::Error> {
+        loop {
+            try_ready!(self.poll_connect());
+
+            match self.framed().poll() {
+                Err(ref e) if is_transport_error(e) => self.reconnect(),
+                res => return res,
+            }
+        }
+    }
+}
+
+impl<F, Fut, T, B> Sink for Reconnecting<F, Fut, T, B>
+    where F: FnMut() -> Fut,
+          Fut: Future<Item = T, Error = io::Error>,
+          T: AsyncWrite,
+          B: IntoBuf,
+{
+    type SinkItem = B;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: B) -> StartSend<B, io::Error> {
+        if !try!(self.poll_connect()).is_ready() {
+            return Ok(AsyncSink::NotReady(item));
+        }
+
+        match self.framed().start_send(item) {
+            // The frame is lost along with the connection
+            Err(ref e) if is_transport_error(e) => {
+                self.reconnect();
+                Ok(AsyncSink::Ready)
+            }
+            res => res,
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        loop {
+            try_ready!(self.poll_connect());
+
+            match self.framed().poll_complete() {
+                Err(ref e) if is_transport_error(e) => self.reconnect(),
+                res => return res,
+            }
+        }
+    }
+}
+
+/*
+ *
+ * ===== impl RoundRobin =====
+ *
+ */
+
+/// Encode frames onto `writers`, sending each frame to the next writer in
+/// rotation
+///
+/// By default, a frame waits for its writer to be ready, keeping the
+/// rotation strict. See `RoundRobin::set_skip_busy` to pass over busy
+/// writers instead.
+///
+/// # Panics
+///
+/// Panics if `writers` is empty.
+pub fn round_robin<T, B>(writers: Vec<T>, builder: Builder) -> RoundRobin<T, B>
+    where B: IntoBuf,
+{
+    assert!(!writers.is_empty(), "no writers");
+
+    RoundRobin {
+        encoders: writers.into_iter().map(|io| builder.clone().encoder(io)).collect(),
+        next: 0,
+        skip_busy: false,
+    }
+}
+
+impl<T, B: IntoBuf> RoundRobin<T, B> {
+    /// Sets whether a frame skips over writers that are not ready
+    ///
+    /// When set, the frame goes to the first ready writer starting from the
+    /// next one in rotation, and `start_send` only returns `NotReady` if no
+    /// writer is ready.
+    pub fn set_skip_busy(mut self, val: bool) -> Self {
+        self.skip_busy = val;
+        self
+    }
+
+    pub fn into_inner(self) -> Vec<T> {
+        self.encoders.into_iter().map(|encoder| encoder.into_inner()).collect()
+    }
+}
+
+impl<T: AsyncWrite, B: IntoBuf> Sink for RoundRobin<T, B> {
+    type SinkItem = B;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, mut item: B) -> StartSend<B, io::Error> {
+        let len = self.encoders.len();
+        let tries = if self.skip_busy { len } else { 1 };
+
+        for i in 0..tries {
+            let idx = (self.next + i) % len;
+
+            match try!(self.encoders[idx].start_send(item)) {
+                AsyncSink::Ready => {
+                    self.next = (idx + 1) % len;
+                    return Ok(AsyncSink::Ready);
+                }
+                AsyncSink::NotReady(ret) => item = ret,
+            }
+        }
+
+        Ok(AsyncSink::NotReady(item))
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        let mut ready = true;
+
+        // Drive every encoder, even once one of them is not ready
+        for encoder in &mut self.encoders {
+            if !try!(encoder.poll_complete()).is_ready() {
+                ready = false;
+            }
+        }
+
+        if ready {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+/*
+ *
+ * ===== impl WriteStream =====
+ *
+ */
+
+/// Frame every payload yielded by `payloads` onto `writer`
+///
+/// Payloads are only pulled from the stream as fast as the writer accepts
+/// frames. Once the stream ends and the last frame has been flushed, the
+/// future resolves to the stream and the writer.
+pub fn write_stream<S, W>(payloads: S, writer: W, builder: Builder) -> WriteStream<S, W>
+    where S: Stream<Item = BytesMut, Error = io::Error>,
+          W: AsyncWrite,
+{
+    WriteStream {
+        payloads: Some(payloads),
+        encoder: Some(builder.encoder(writer)),
+        buffered: None,
+        payloads_done: false,
+    }
+}
+
+impl<S, W> WriteStream<S, W>
+    where S: Stream<Item = BytesMut, Error = io::Error>,
+          W: AsyncWrite,
+{
+    // Hand a payload to the encoder, keeping it if the encoder is busy
+    fn try_start_send(&mut self, item: BytesMut) -> Poll<(), io::Error> {
+        let encoder = self.encoder.as_mut().expect("polled after completion");
+
+        if let AsyncSink::NotReady(item) = try!(encoder.start_send(item)) {
+            self.buffered = Some(item);
+            return Ok(Async::NotReady);
+        }
+
+        Ok(Async::Ready(()))
+    }
+}
+
+impl<S, W> Future for WriteStream<S, W>
+    where S: Stream<Item = BytesMut, Error = io::Error>,
+          W: AsyncWrite,
+{
+    type Item = (S, W);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(S, W), io::Error> {
+        if let Some(item) = self.buffered.take() {
+            try_ready!(self.try_start_send(item));
+        }
+
+        while !self.payloads_done {
+            let item = {
+                let payloads = self.payloads.as_mut().expect("polled after completion");
+                try!(payloads.poll())
+            };
+
+            match item {
+                Async::Ready(Some(item)) => try_ready!(self.try_start_send(item)),
+                Async::Ready(None) => self.payloads_done = true,
+                Async::NotReady => {
+                    // Write out the frames accepted so far while waiting
+                    try!(self.encoder.as_mut().unwrap().poll_complete());
+                    return Ok(Async::NotReady);
+                }
+            }
+        }
+
+        try_ready!(self.encoder.as_mut().expect("polled after completion").poll_complete());
+
+        let payloads = self.payloads.take().unwrap();
+        let writer = self.encoder.take().unwrap().into_inner();
+
+        Ok(Async::Ready((payloads, writer)))
+    }
+}
+
+impl<T: AsyncWrite, B: IntoBuf> Future for Flush<Encoder<T, B>> {
+    type Item = Encoder<T, B>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Encoder<T, B>, io::Error> {
+        {
+            let encoder = self.inner.as_mut().expect("polled after completion");
+
+            try_ready!(encoder.poll_complete());
+            try_ready!(encoder.inner.try_flush());
+        }
+
+        Ok(Async::Ready(self.inner.take().unwrap()))
+    }
+}
+
+/*
+ *
+ * ===== impl Builder =====
+ *
+ */
+
+impl Builder {
+    pub fn new() -> Builder {
+        Builder {
+            // Default max frame length of 8MB
+            max_frame_len: 8 * 1_024 * 1_024,
+
+            // Default byte length of 4
+            length_field_len: 4,
+
+            // Default to the header field being at the start of the header.
+            length_field_offset: 0,
+            length_field_prefix: vec![],
+            length_field_from_end: None,
+
+            length_adjustment: 0,
+
+            // Total number of bytes to skip before reading the payload, if not set,
+            // `length_field_len + length_field_offset`
+            num_skip: None,
+
+            // Default to reading the length field in network (big) endian.
+            length_field_order: ByteOrder::BigEndian,
+
+            // Default to the length field using whole bytes
+            length_bits: None,
+            head_prefix: vec![],
+
+            // Default to the length field counting bytes
+            length_counts_lines: false,
+
+            // Default to a binary length field
+            length_ascii: None,
+
+            // Default to the length field only covering the payload
+            length_includes_header: false,
+
+            // Default to no memory budget
+            memory_budget: None,
+
+            // Default to no content hash
+            content_hash_len: 0,
+
+            // Default to no nonce
+            nonce_field: None,
+
+            // Default to no tag
+            tag_field: None,
+
+            // Default to leaving flushing to the upstream
+            flush_between_frames: false,
+
+            // Default to no padding
+            pad_to: None,
+            pad_byte: 0,
+
+            // Default to absolute lengths
+            delta_length: false,
+
+            // Default to EOF in the middle of a frame being an error
+            allow_trailing: false,
+
+            // Default to empty frames being valid, e.g. as keep-alives
+            allow_empty_frames: true,
+
+            // Default to the length field only preceding the payload
+            bracketed_length: false,
+
+            // Default to encoding a head for every frame
+            passthrough: false,
+
+            // Default to writing the head and payload separately
+            message_oriented: false,
+
+            // Default to no trailer
+            trailer_len: 0,
+            trailer: None,
+            crc32_trailer: false,
+
+            // Default to the trailer using the length field byte order
+            trailer_order: None,
+
+            // Default to no extended length field
+            extended_length: None,
+
+            // Read buffer grows as needed
+            initial_buffer_capacity: 0,
+
+            // Default to reserving room for the frame head only
+            adaptive_buffer: false,
+
+            // Default to reading as long as the upstream is ready
+            read_chunk_limit: None,
+
+            // Default to framing from the first byte
+            initial_skip_until: None,
+
+            // Default to frames following each other directly
+            inter_frame_gap: vec![],
+
+            // Default to accepting a frame only once the previous one has
+            // been written
+            max_pending_frames: 0,
+
+            // No format version byte
+            formats: HashMap::new(),
+
+            // No I/O callback
+            on_io: None,
+
+            // No error callback
+            on_error: None,
+
+            // No progress callback
+            on_progress: None,
+        }
+    }
+
+    /// Sets the max frame length
+    pub fn set_max_frame_length(mut self, val: usize) -> Self {
+        self.max_frame_len = val;
+        self
+    }
+
+    /// Sets the number of bytes used to represent the length field
+    ///
+    /// Length fields of up to 16 bytes are supported. Only the low 8 bytes of
+    /// a wider field are significant, the remaining bytes are written as zero
+    /// and a nonzero value is decoded as a frame exceeding the max frame
+    /// length.
+    pub fn set_length_field_length(mut self, val: usize) -> Self {
+        assert!(val > 0 && val <= 16, "invalid length field length");
+        self.length_field_len = val;
+        self
+    }
+
+    /// Sets the number of bytes in the header before the length field
+    ///
+    /// Unless `set_length_field_prefix` is set, the encoder writes these
+    /// bytes, and any other skipped header bytes not holding a field, as
+    /// zeros. This replaces any position set with
+    /// `set_length_field_from_end`.
+    pub fn set_length_field_offset(mut self, val: usize) -> Self {
+        self.length_field_offset = val;
+        self.length_field_from_end = None;
+        self
+    }
+
+    /// Sets the bytes the encoder writes before the length field
+    ///
+    /// Only the first `length_field_offset` bytes of `val` are written, and
+    /// any bytes past its end are written as zeros. The decoder skips these
+    /// bytes regardless of their value.
+    pub fn set_length_field_prefix(mut self, val: &[u8]) -> Self {
+        self.length_field_prefix = val.to_vec();
+        self
+    }
+
+    /// Sets the number of bytes in the header after the length field
+    ///
+    /// This positions the length field relative to the end of a fixed size
+    /// header, whose length is set with `set_num_skip`, instead of relative to
+    /// its start. It replaces any offset set with `set_length_field_offset`,
+    /// and is not supported with bitfield lengths.
+    ///
+    /// # Panics
+    ///
+    /// Building a decoder or encoder panics if `num_skip` is not set, or is
+    /// too small to hold the length field and the `val` bytes following it.
+    pub fn set_length_field_from_end(mut self, val: usize) -> Self {
+        self.length_field_from_end = Some(val);
+        self
+    }
+
+    /// Delta between the payload length specified in the header and the real
+    /// payload length
+    pub fn set_length_adjustment(mut self, val: isize) -> Self {
+        self.length_adjustment = val;
+        self
+    }
+
+    /// Sets the number of bytes to skip before reading the payload
+    ///
+    /// Defaults to `length_field_len + length_field_offset`. When set below
+    /// that, the head bytes past `val`, such as the length field, are not
+    /// skipped and are yielded at the start of the payload. The length field
+    /// then has to count them, for example with a length adjustment. This is
+    /// occasionally intended, but is usually a mistake, and is reported by
+    /// `validate`.
+    pub fn set_num_skip(mut self, val: usize) -> Self {
+        self.num_skip = Some(val);
+        self
+    }
+
+    /// Sets the expected byte order 
+    ///
+    /// Defaults to `ByteOrder::BigEndian`
+    pub fn set_byte_order(mut self, val: ByteOrder) -> Self {
+        self.length_field_order = val;
+        self
+    }
+
+    /// Sets whether the length field counts newline terminated lines instead
+    /// of bytes
+    ///
+    /// When enabled, the decoder reads until it has seen `n` newline
+    /// characters and yields everything up to and including the last one as
+    /// the payload. Defaults to `false`.
+    pub fn set_length_counts_lines(mut self, val: bool) -> Self {
+        self.length_counts_lines = val;
+        self
+    }
+
+    /// Sets the frame head to the length as ASCII decimal digits, followed by
+    /// `delimiter`
+    ///
+    /// For example, with a `b':'` delimiter, `12:abcdefghijkl` is a frame
+    /// holding a 12 byte payload. The decoder fails with an `InvalidData`
+    /// error when anything other than a digit precedes the delimiter. The
+    /// length adjustment and max frame length still apply, while the binary
+    /// length field settings and the bracketed length are not used.
+    pub fn set_length_ascii(mut self, delimiter: u8) -> Self {
+        self.length_ascii = Some(delimiter);
+        self
+    }
+
+    /// Sets whether the length field covers the header as well as the payload
+    ///
+    /// When enabled, the decoder subtracts the `num_skip` header bytes from
+    /// the length before reading the payload and the encoder adds them when
+    /// writing the length. Defaults to `false`.
+    pub fn set_length_includes_header(mut self, val: bool) -> Self {
+        self.length_includes_header = val;
+        self
+    }
+
+    /// Sets a memory budget shared with other decoders
+    ///
+    /// The decoder will not read the payload of a frame until its length
+    /// fits in the budget.
+    pub fn set_memory_budget(mut self, budget: MemoryBudget) -> Self {
+        self.memory_budget = Some(budget);
+        self
+    }
+
+    /// Sets the number of bytes of payload hash carried in the header
+    ///
+    /// The encoder writes a hash of the payload, truncated to `val` bytes,
+    /// directly after the length field. The decoder reads it back and
+    /// exposes it via `Decoder::content_hash`, allowing receivers to
+    /// deduplicate identical payloads. Defaults to 0 (no hash).
+    pub fn set_content_hash(mut self, val: usize) -> Self {
+        assert!(val <= 8, "invalid content hash length");
+        self.content_hash_len = val;
+        self
+    }
+
+    /// Sets the position of a per-frame nonce of `len` bytes, `offset` bytes
+    /// from the start of the frame head
+    ///
+    /// The encoder writes a nonce that increases by one with every frame, and
+    /// the decoder rejects any frame whose nonce is not strictly greater than
+    /// the previous frame's with a `Replay` error. The nonce must follow the
+    /// length field and content hash, if any. `offset` does not count an
+    /// extended length field, which moves the nonce further into the head
+    /// when it is used.
+    pub fn set_nonce_field(mut self, offset: usize, len: usize) -> Self {
+        assert!(len > 0 && len <= 8, "invalid nonce field length");
+        self.nonce_field = Some((offset, len));
+        self
+    }
+
+    /// Sets the position of a frame type tag of `len` bytes, `offset` bytes
+    /// from the start of the frame head
+    ///
+    /// The decoder reads the tag of every frame, which is yielded along with
+    /// the payload by `Decoder::into_tagged_frames`. This allows control and
+    /// data frames to be interleaved on one connection. The tag is read in
+    /// the length field byte order.
+    pub fn set_tag_field(mut self, offset: usize, len: usize) -> Self {
+        assert!(len > 0 && len <= 8, "invalid tag field length");
+        self.tag_field = Some((offset, len));
+        self
+    }
+
+    /// Sets whether the encoder flushes the upstream after each frame
+    ///
+    /// This ensures frames are not held indefinitely in a lower buffer.
+    /// Defaults to `false`.
+    pub fn set_flush_between_frames(mut self, val: bool) -> Self {
+        self.flush_between_frames = val;
+        self
+    }
+
+    /// Pads every payload on the wire to `val` bytes
+    ///
+    /// The header carries the true payload length, which the decoder uses to
+    /// strip the padding. Payloads larger than `val` are rejected. Both sides
+    /// of the connection must be configured with the same value.
+    pub fn set_pad_to(mut self, val: usize) -> Self {
+        self.pad_to = Some(val);
+        self
+    }
+
+    /// Sets the byte used to pad payloads
+    ///
+    /// Defaults to `0`
+    pub fn set_pad_byte(mut self, val: u8) -> Self {
+        self.pad_byte = val;
+        self
+    }
+
+    /// Sets whether the length field holds a signed delta from the previous
+    /// frame's length
+    ///
+    /// The delta is a two's complement integer of `length_field_len` bytes.
+    /// The first frame is relative to a length of 0. Defaults to `false`.
+    pub fn set_delta_length(mut self, val: bool) -> Self {
+        self.delta_length = val;
+        self
+    }
+
+    /// Sets whether a partial frame at EOF is allowed
+    ///
+    /// By default, the upstream shutting down after part of a frame has been
+    /// read is an `UnexpectedEof` error. When enabled, the partial frame is
+    /// discarded and the stream ends cleanly instead.
+    pub fn set_allow_trailing(mut self, val: bool) -> Self {
+        self.allow_trailing = val;
+        self
+    }
+
+    /// Sets whether frames with an empty payload are allowed
+    ///
+    /// Empty frames are allowed by default, as some protocols use them as
+    /// keep-alives. When disallowed, the decoder yields an `InvalidData` error
+    /// for a frame whose length decodes to zero and the encoder rejects empty
+    /// items with an `InvalidInput` error.
+    pub fn set_allow_empty_frames(mut self, val: bool) -> Self {
+        self.allow_empty_frames = val;
+        self
+    }
+
+    /// Sets the length field to a bitfield of `bit_len` bits starting
+    /// `bit_offset` bits into the header byte at `byte_offset`
+    ///
+    /// Bits are numbered from the most significant bit of each byte, and the
+    /// bitfield may straddle byte boundaries. The length is read most
+    /// significant bit first, regardless of the byte order. This replaces the
+    /// length field offset and length with the bytes spanned by the bitfield.
+    pub fn set_bitfield_length(mut self, byte_offset: usize, bit_offset: usize, bit_len: usize) -> Self {
+        assert!(bit_len > 0 && bit_len <= 64, "invalid bitfield length");
+
+        let start = byte_offset * 8 + bit_offset;
+
+        self.length_field_offset = start / 8;
+        self.length_field_len = (start % 8 + bit_len + 7) / 8;
+        self.length_bits = Some((start % 8, bit_len));
+        self
+    }
+
+    /// Sets the length field to `len_bits` bits starting `offset_bits` bits
+    /// into the header
+    ///
+    /// Equivalent to `set_bitfield_length(0, offset_bits, len_bits)`. The
+    /// bits surrounding the length are set with `set_head_prefix`.
+    pub fn set_length_field_bits(self, offset_bits: usize, len_bits: usize) -> Self {
+        self.set_bitfield_length(0, offset_bits, len_bits)
+    }
+
+    /// Sets the bytes the encoder writes a bitfield length into
+    ///
+    /// The bits of the length field outside of the bitfield keep their value
+    /// from `val`, and are zero past its end. Only used with
+    /// `set_bitfield_length`.
+    pub fn set_head_prefix(mut self, val: &[u8]) -> Self {
+        self.head_prefix = val.to_vec();
+        self
+    }
+
+    /// Sets whether the length field is repeated after the payload
+    ///
+    /// When enabled, the encoder writes the length field, including any
+    /// extended length, again after the payload and padding, allowing the
+    /// frames to be scanned backwards. The decoder checks that the trailing
+    /// copy matches, failing with a `LengthMismatch` error otherwise. Not
+    /// supported when the length counts lines.
+    pub fn set_bracketed_length(mut self, val: bool) -> Self {
+        self.bracketed_length = val;
+        self
+    }
+
+    /// Sets whether the encoder writes items without a frame head
+    ///
+    /// This is useful when forwarding buffers that are already length
+    /// delimited. Items are still limited to `max_frame_length` bytes and go
+    /// through the encoder's flush handling, but no head, padding or suffix
+    /// is added. The decoder is unaffected.
+    pub fn set_passthrough(mut self, val: bool) -> Self {
+        self.passthrough = val;
+        self
+    }
+
+    /// Sets whether the encoder writes every frame with a single write
+    ///
+    /// This is needed when each write to the upstream is sent as a separate
+    /// message, such as a datagram. The head, payload, padding and suffix are
+    /// copied into one buffer instead of being written as they are. Frames
+    /// are not queued behind the one being written, regardless of
+    /// `set_max_pending_frames`.
+    pub fn set_message_oriented(mut self, val: bool) -> Self {
+        self.message_oriented = val;
+        self
+    }
+
+    /// Sets the number of trailer bytes following the payload
+    ///
+    /// The trailer comes after the payload, padding and bracketed length.
+    /// Unless `on_trailer` is set, the decoder discards the trailer and the
+    /// encoder fills it with zeros. Not supported when the length counts
+    /// lines.
+    pub fn set_trailer_len(mut self, val: usize) -> Self {
+        self.trailer_len = val;
+        self
+    }
+
+    /// Sets a callback computing the trailer of a payload
+    ///
+    /// The callback receives the payload, without any padding, and fills in
+    /// the `trailer_len` byte trailer. The encoder writes the computed
+    /// trailer, and the decoder fails with a `TrailerMismatch` error when the
+    /// received trailer differs from it.
+    pub fn on_trailer<F>(mut self, f: F) -> Self
+        where F: FnMut(&[u8], &mut [u8]) + Send + 'static,
+    {
+        let f: Box<FnMut(&[u8], &mut [u8]) + Send> = Box::new(f);
+        self.trailer = Some(Callback::new(f));
+        self.crc32_trailer = false;
+        self
+    }
+
+    /// Sets whether frames end with a CRC32 of the payload
+    ///
+    /// This is a 4 byte trailer, computed on encode and validated on decode,
+    /// written in the trailer byte order.
+    pub fn set_crc32_trailer(mut self, val: bool) -> Self {
+        self.trailer_len = if val { 4 } else { 0 };
+        self.trailer = None;
+        self.crc32_trailer = val;
+        self
+    }
+
+    /// Sets the byte order of the trailer
+    ///
+    /// This applies to trailers computed by the builder, such as the CRC32
+    /// trailer, allowing a protocol to mix byte orders. Defaults to the byte
+    /// order of the length field.
+    pub fn set_trailer_byte_order(mut self, val: ByteOrder) -> Self {
+        self.trailer_order = Some(val);
+        self
+    }
+
+    /// Sets a length field value signaling that the real length follows in
+    /// an extended field of `extended_len` bytes
+    ///
+    /// The encoder uses the extended field for lengths greater than or equal
+    /// to `sentinel`. The extended field bytes are skipped in addition to
+    /// `num_skip`.
+    pub fn set_extended_length(mut self, sentinel: u64, extended_len: usize) -> Self {
+        assert!(extended_len > 0 && extended_len <= 8, "invalid extended length field length");
+        self.extended_length = Some((sentinel, extended_len));
+        self
+    }
+
+    /// Sets the initial capacity of the decoder's read buffer
+    ///
+    /// Preallocating room for the typical frame avoids growing the buffer
+    /// while the first frames are read. Defaults to 0.
+    pub fn set_initial_buffer_capacity(mut self, val: usize) -> Self {
+        self.initial_buffer_capacity = val;
+        self
+    }
+
+    /// Sets whether the decoder sizes its reads after recent frames
+    ///
+    /// The decoder keeps a moving average of the number of bytes taken by
+    /// each frame on the wire, and reserves room for that many bytes when
+    /// reading a frame head. Connections carrying large frames then read them
+    /// with fewer calls, without small frames reserving as much. Defaults to
+    /// `false`.
+    pub fn set_adaptive_buffer(mut self, val: bool) -> Self {
+        self.adaptive_buffer = val;
+        self
+    }
+
+    /// Sets the maximum number of bytes the decoder reads from the upstream
+    /// in a single `poll`
+    ///
+    /// Once the limit is reached, `poll` returns `NotReady` and schedules the
+    /// task to be polled again, yielding to other tasks rather than reading
+    /// everything the upstream has available. This trades latency for
+    /// fairness. Defaults to no limit.
+    pub fn set_read_chunk_limit(mut self, val: usize) -> Self {
+        assert!(val > 0, "invalid read chunk limit");
+        self.read_chunk_limit = Some(val);
+        self
+    }
+
+    /// Sets a marker ending a banner that precedes the first frame
+    ///
+    /// The decoder discards everything up to and including the first
+    /// occurrence of `marker`, then starts framing from the byte after it.
+    /// A banner longer than the max frame length is an error.
+    pub fn set_initial_skip_until(mut self, marker: Vec<u8>) -> Self {
+        self.initial_skip_until = Some(marker);
+        self
+    }
+
+    /// Sets a byte sequence separating consecutive frames on the wire
+    ///
+    /// The encoder writes `gap` after every frame, following any padding,
+    /// bracketed length and trailer. The decoder skips `gap` before reading
+    /// each frame head other than the first, failing with an `InvalidData`
+    /// error if different bytes are found there. The upstream may end either
+    /// before or after the gap of the last frame.
+    pub fn set_inter_frame_gap(mut self, gap: Vec<u8>) -> Self {
+        self.inter_frame_gap = gap;
+        self
+    }
+
+    /// Sets the number of frames the encoder accepts while a previous frame
+    /// is still being written
+    ///
+    /// Queued frames are encoded up front and written together once the
+    /// current frame completes, reducing the number of writes under bursty
+    /// load. When the queue is full, `start_send` returns `NotReady`. With
+    /// flushing between frames enabled, the upstream is flushed once per
+    /// batch. Defaults to 0.
+    pub fn set_max_pending_frames(mut self, val: usize) -> Self {
+        self.max_pending_frames = val;
+        self
+    }
+
+    /// Registers the head layout used by frames whose first byte is `version`
+    ///
+    /// Once a format is registered, every frame head starts with a format
+    /// version byte, and the rest of the head is decoded according to the
+    /// matching `FormatSpec`. A frame with an unregistered version is an
+    /// `UnknownFormat` error. The other head options, such as content hashes
+    /// and extended lengths, do not apply to versioned heads. Only decoding
+    /// is supported.
+    pub fn register_format(mut self, version: u8, spec: FormatSpec) -> Self {
+        self.formats.insert(version, spec);
+        self
+    }
+
+    /// Sets a callback notified of every read from or write to the upstream
+    ///
+    /// The callback receives an `IoEvent` for each read or write attempted by
+    /// the decoder or encoder, including those that would block. Failed
+    /// attempts are not reported.
+    pub fn on_io<F>(mut self, f: F) -> Self
+        where F: FnMut(IoEvent) + Send + 'static,
+    {
+        let f: Box<FnMut(IoEvent) + Send> = Box::new(f);
+        self.on_io = Some(Callback::new(f));
+        self
+    }
+
+    /// Sets a callback consulted when a frame head cannot be decoded
+    ///
+    /// The callback receives the framing error, such as a frame exceeding
+    /// the maximum length, and decides whether the decoder aborts, skips
+    /// ahead a byte at a time until a valid head is found, or yields the error
+    /// and retries on the next poll. Errors from the upstream are always
+    /// yielded as is.
+    pub fn on_error<F>(mut self, f: F) -> Self
+        where F: FnMut(&io::Error) -> ErrorAction + Send + 'static,
+    {
+        let f: Box<FnMut(&io::Error) -> ErrorAction + Send> = Box::new(f);
+        self.on_error = Some(Callback::new(f));
+        self
+    }
+
+    /// Sets a callback notified of the bytes read by every decoder poll
+    ///
+    /// The callback receives a `Progress` at the end of each poll, telling a
+    /// poll that returned `NotReady` after buffering part of a frame apart
+    /// from one that read nothing at all.
+    pub fn on_progress<F>(mut self, f: F) -> Self
+        where F: FnMut(Progress) + Send + 'static,
+    {
+        let f: Box<FnMut(Progress) + Send> = Box::new(f);
+        self.on_progress = Some(Callback::new(f));
+        self
+    }
+
+    /// Build the length delimted decoder
+    pub fn decoder<T>(self, io: T) -> Decoder<T> {
+        let buf = BytesMut::with_capacity(self.initial_buffer_capacity);
+        self.decoder_with_buffer(io, buf)
+    }
+
+    /// Build a length delimited decoder reading into `buf`
+    ///
+    /// This allows the read buffer to come from a pool. Bytes already in
+    /// `buf` are decoded before anything is read from `io`, and frames are
+    /// split off the front of it, sharing its allocation.
+    pub fn decoder_with_buffer<T>(mut self, io: T, buf: BytesMut) -> Decoder<T> {
+        self.resolve_length_field_offset();
+
+        let state = if self.initial_skip_until.is_some() {
+            ReadState::Banner
+        } else {
+            ReadState::Head
+        };
+
+        Decoder {
+            inner: io,
+            buf: buf,
+            builder: self,
+            state: state,
+            charge: None,
+            content_hash: None,
+            tag: None,
+            head_wire_len: 0,
+            prev_len: 0,
+            last_nonce: None,
+            bracket: vec![],
+            wire_len: 0,
+            last_read: 0,
+            head_read: 0,
+            frame_estimate: 0,
+            order_checked: 0,
+            order_plausible: 0,
+            rate: None,
+            gap_pending: false,
+            head: BytesMut::new(),
+            keep_head: false,
+            buffered_only: false,
+            redeliver: None,
+            searched: 0,
+            lines_seen: 0,
+        }
+    }
+
+    /// Build the length delimited decoder, refusing a configuration that
+    /// fails `validate`
+    pub fn try_decoder<T>(mut self, io: T) -> Result<Decoder<T>, ConfigError> {
+        self.resolve_length_field_offset();
+        try!(self.validate());
+
+        Ok(self.decoder(io))
+    }
+
+    /// Build a length delimited decoder yielding frames as immutable `Bytes`
+    pub fn decoder_bytes<T>(self, io: T) -> DecoderBytes<T> {
+        DecoderBytes { inner: self.decoder(io) }
+    }
+
+    /// Decode every frame in `src`
+    ///
+    /// This runs the decoder synchronously over an in-memory buffer, which is
+    /// useful for tests and batch processing. A partial frame at the end of
+    /// `src` is an error, unless `set_allow_trailing` is enabled.
+    ///
+    /// Frames are only decoded from the bytes of `src`, so read pacing does
+    /// not apply. A frame that cannot be decoded without waiting, such as
+    /// one exceeding the memory budget, is treated as a partial frame.
+    pub fn decode_all<B: Buf>(&self, mut src: B) -> io::Result<Vec<BytesMut>> {
+        let mut buf = BytesMut::with_capacity(src.remaining());
+
+        while src.has_remaining() {
+            let n = {
+                let bytes = src.bytes();
+                buf.extend_from_slice(bytes);
+                bytes.len()
+            };
+
+            src.advance(n);
+        }
+
+        let mut decoder = self.clone().decoder_with_buffer(io::empty(), buf);
+        let mut frames = vec![];
+
+        while let Some(frame) = try!(decoder.try_next_frame()) {
+            frames.push(frame);
+        }
+
+        let partial = match decoder.state() {
+            DecoderState::AwaitingHeader => decoder.buffered_len() > 0,
+            DecoderState::AwaitingPayload { .. } => true,
+        };
+
+        if partial && !self.allow_trailing {
+            return Err(Error::UnexpectedEof.into());
+        }
+
+        Ok(frames)
+    }
+
+    pub fn encoder<T, B: IntoBuf>(mut self, io: T) -> Encoder<T, B> {
+        self.resolve_length_field_offset();
+
+        Encoder {
+            inner: io,
+            builder: self,
+            state: WriteState::Ready,
+            padding: 0,
+            suffix: None,
+            prev_len: 0,
+            nonce: 0,
+            written: 0,
+            acks: vec![],
+            queued: VecDeque::new(),
+            num_queued: 0,
+        }
+    }
+
+    /// Build the length delimited encoder, refusing a configuration that
+    /// fails `validate`
+    pub fn try_encoder<T, B: IntoBuf>(mut self, io: T) -> Result<Encoder<T, B>, ConfigError> {
+        self.resolve_length_field_offset();
+        try!(self.validate());
+
+        Ok(self.encoder(io))
+    }
+
+    /// Build length delimited framing for `codec::FramedRead` and
+    /// `codec::FramedWrite`
+    ///
+    /// Options pacing reads from the upstream do not apply, as the bytes are
+    /// read by the `FramedRead`.
+    pub fn codec<B: IntoBuf>(self) -> Codec<B> {
+        Codec {
+            decoder: self.clone().decoder(io::empty()),
+            encoder: self.encoder(io::sink()),
+            held: 0,
+        }
+    }
+
+    /// Build a length delimited decoder and encoder over a single I/O object
+    ///
+    /// Any `io::Read + io::Write` type may be used, such as a
+    /// `tokio_core::net::TcpStream`. A `WouldBlock` error from the I/O object
+    /// is reported as `NotReady`, leaving the socket's readiness to wake the
+    /// task up.
+    pub fn framed<T, B: IntoBuf>(self, io: T) -> Framed<T, B> {
+        let encoder = self.clone().encoder(io);
+
+        Framed {
+            inner: self.decoder(encoder),
+        }
+    }
+
+    // Report the outcome of a read from or write to the upstream to the
+    // `on_io` callback
+    fn report_io(&self, kind: IoKind, res: &Poll<usize, io::Error>) {
+        let f = match self.on_io {
+            Some(ref f) => f,
+            None => return,
+        };
+
+        let event = match *res {
+            Ok(Async::Ready(n)) => IoEvent { kind: kind, bytes: n, would_block: false },
+            Ok(Async::NotReady) => IoEvent { kind: kind, bytes: 0, would_block: true },
+            Err(_) => return,
+        };
+
+        (&mut *f.inner.lock().unwrap())(event);
+    }
+
+    // Read the value of the length field at the start of `buf`
+    fn read_length(&self, buf: &[u8]) -> u64 {
+        match self.length_bits {
+            Some((offset, len)) => read_bits(buf, offset, len),
+            None => self.length_field_order.read_wide_uint(buf, self.length_field_len),
+        }
+    }
+
+    // Write the length field holding `n`
+    fn put_length<B: BufMut>(&self, buf: &mut B, n: u64) -> io::Result<()> {
+        let (offset, len) = match self.length_bits {
+            Some(bits) => bits,
+            None => {
+                self.length_field_order.put_wide_uint(buf, n, self.length_field_len);
+                return Ok(());
+            }
+        };
+
+        if len < 64 && n >> len != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "frame length does not fit in the length bitfield"));
+        }
+
+        let mut field = vec![0; self.length_field_len];
+
+        for (dst, src) in field.iter_mut().zip(&self.head_prefix) {
+            *dst = *src;
+        }
+
+        put_bits(&mut field, offset, len, n);
+        buf.put_slice(&field);
+
+        Ok(())
+    }
+
+    /// Check the configuration for likely mistakes
+    ///
+    /// `decoder` and `encoder` accept configurations failing validation, as
+    /// they still work as documented and are occasionally intended.
+    /// `try_decoder` and `try_encoder` refuse them.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let head_len = self.num_field_bytes();
+
+        if let Some(num_skip) = self.num_skip {
+            if num_skip < head_len {
+                return Err(ConfigError::NumSkipWithinHead {
+                    num_skip: num_skip,
+                    head_len: head_len,
+                });
+            }
+        }
+
+        if let Some((offset, _)) = self.nonce_field {
+            let length_end = self.length_field_offset + self.length_field_len + self.content_hash_len;
+
+            if offset < length_end {
+                return Err(ConfigError::NonceOverlapsLength {
+                    nonce_offset: offset,
+                    length_end: length_end,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    // Compute the length field offset from its distance to the end of the
+    // header, if it was set that way
+    fn resolve_length_field_offset(&mut self) {
+        let from_end = match self.length_field_from_end {
+            Some(from_end) => from_end,
+            None => return,
+        };
+
+        let head_len = self.num_skip.expect("length field positioned from the end of the header requires num_skip");
+        let field_end = from_end + self.length_field_len;
+
+        assert!(field_end <= head_len, "length field does not fit in the header");
+        self.length_field_offset = head_len - field_end;
+    }
+
+    /// Number of header bytes to read
+    fn num_head_bytes(&self) -> usize {
+        cmp::max(self.num_field_bytes(), self.num_skip.unwrap_or(0))
+    }
+
+    fn num_skip(&self) -> usize {
+        self.num_skip.unwrap_or(self.num_field_bytes())
+    }
+
+    // Number of head bytes up to the end of the last field
+    fn num_field_bytes(&self) -> usize {
+        let num = self.length_field_offset + self.length_field_len + self.content_hash_len;
+        let nonce_end = self.nonce_field.map(|(offset, len)| offset + len).unwrap_or(0);
+        let tag_end = self.tag_field.map(|(offset, len)| offset + len).unwrap_or(0);
+
+        cmp::max(num, cmp::max(nonce_end, tag_end))
+    }
+
+    // Compute the trailer of `payload`, if a trailer callback is set
+    fn compute_trailer(&self, payload: &[u8]) -> Option<Vec<u8>> {
+        if self.crc32_trailer {
+            let order = self.trailer_order.unwrap_or(self.length_field_order);
+
+            let mut trailer = Vec::with_capacity(4);
+            order.put_uint(&mut trailer, crc32(payload) as u64, 4);
+            return Some(trailer);
+        }
+
+        self.trailer.as_ref().map(|f| {
+            let mut trailer = vec![0; self.trailer_len];
+            (&mut *f.inner.lock().unwrap())(payload, &mut trailer);
+            trailer
+        })
+    }
+}
+
+/*
+ *
+ * ===== impl FormatSpec =====
+ *
+ */
+
+impl FormatSpec {
+    /// Returns a spec with a 4 byte big endian length field directly
+    /// following the version byte
+    pub fn new() -> FormatSpec {
+        FormatSpec {
+            length_field_len: 4,
+            length_field_offset: 0,
+            length_adjustment: 0,
+            num_skip: None,
+            length_field_order: ByteOrder::BigEndian,
+        }
+    }
+
+    /// Sets the number of bytes used to represent the length field
+    pub fn set_length_field_length(mut self, val: usize) -> Self {
+        assert!(val > 0 && val <= 16, "invalid length field length");
+        self.length_field_len = val;
+        self
+    }
+
+    /// Sets the number of bytes between the version byte and the length
+    /// field
+    pub fn set_length_field_offset(mut self, val: usize) -> Self {
+        self.length_field_offset = val;
+        self
+    }
+
+    /// Delta between the payload length specified in the header and the real
+    /// payload length
+    pub fn set_length_adjustment(mut self, val: isize) -> Self {
+        self.length_adjustment = val;
+        self
+    }
+
+    /// Sets the number of bytes following the version byte to skip before
+    /// reading the payload
+    pub fn set_num_skip(mut self, val: usize) -> Self {
+        self.num_skip = Some(val);
+        self
+    }
+
+    /// Sets the byte order of the length field
+    pub fn set_byte_order(mut self, val: ByteOrder) -> Self {
+        self.length_field_order = val;
+        self
+    }
+
+    fn num_head_bytes(&self) -> usize {
+        let num = self.length_field_offset + self.length_field_len;
+        cmp::max(num, self.num_skip.unwrap_or(0))
+    }
+
+    fn num_skip(&self) -> usize {
+        self.num_skip.unwrap_or(self.length_field_offset + self.length_field_len)
+    }
+}
+
+/*
+ *
+ * ===== impl Callback =====
+ *
+ */
+
+impl<F: ?Sized> Callback<F> {
+    fn new(f: Box<F>) -> Callback<F> {
+        Callback { inner: Arc::new(Mutex::new(f)) }
+    }
+}
+
+impl<F: ?Sized> Clone for Callback<F> {
+    fn clone(&self) -> Callback<F> {
+        Callback { inner: self.inner.clone() }
+    }
+}
+
+impl<F: ?Sized> fmt::Debug for Callback<F> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str("Callback")
+    }
+}
+
+/*
+ *
+ * ===== testing =====
+ *
+ */
+
+/// Helpers for testing length delimited configurations.
+#[cfg(feature = "testing")]
+pub mod testing {
+    use super::Builder;
+    use futures::{Future, Sink, Stream};
+    use std::io;
+
+    /// Encode `payloads` into an in-memory buffer and decode them again using
+    /// the same configuration, asserting the decoded frames equal the input.
+    ///
+    /// # Panics
+    ///
+    /// Panics if encoding or decoding fails, or the frames do not round trip.
+    pub fn roundtrip(builder: &Builder, payloads: &[&[u8]]) {
+        let mut encoder = builder.clone().encoder(vec![]);
+
+        for &payload in payloads {
+            encoder = encoder.send(payload).wait().unwrap();
+        }
+
+        let wire = encoder.into_inner();
+        let decoder = builder.clone().decoder(io::Cursor::new(wire));
+
+        let frames: Vec<_> = decoder.wait()
+            .map(|frame| frame.unwrap())
+            .collect();
+
+        assert_eq!(frames.len(), payloads.len(), "number of frames differs");
+
+        for (frame, &payload) in frames.iter().zip(payloads) {
+            assert_eq!(&frame[..], payload);
+        }
+    }
+}
+
+/*
+ *
+ * ===== impl Error =====
+ *
+ */
+
+impl Error {
+    /// Returns the `Error` wrapped by an `io::Error` produced by the decoder,
+    /// if there is one.
+    pub fn from_io(err: &io::Error) -> Option<&Error> {
+        err.get_ref().and_then(|e| e.downcast_ref::<Error>())
+    }
+
+    fn kind(&self) -> io::ErrorKind {
+        match *self {
+            Error::FrameTooLarge { .. } => io::ErrorKind::InvalidData,
+            Error::LengthOverflow => io::ErrorKind::InvalidInput,
+            Error::UnexpectedEof => io::ErrorKind::UnexpectedEof,
+            Error::TruncatedPayload { .. } => io::ErrorKind::UnexpectedEof,
+            Error::UnknownFormat(..) => io::ErrorKind::InvalidData,
+            Error::Replay { .. } => io::ErrorKind::InvalidData,
+            Error::LengthMismatch => io::ErrorKind::InvalidData,
+            Error::TrailerMismatch => io::ErrorKind::InvalidData,
+            Error::Io(ref e) => e.kind(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::FrameTooLarge { len, max } => {
+                write!(fmt, "frame size too big; len={}; max={}", len, max)
             }
+            Error::UnknownFormat(version) => {
+                write!(fmt, "unknown frame format; version={}", version)
+            }
+            Error::Replay { nonce, last } => {
+                write!(fmt, "replayed frame; nonce={}; last={}", nonce, last)
+            }
+            Error::TruncatedPayload { ref received, expected } => {
+                write!(fmt, "eof in frame payload; received={}; expected={}", received.len(), expected)
+            }
+            Error::Io(ref e) => fmt::Display::fmt(e, fmt),
+            _ => fmt.write_str(error::Error::description(self)),
         }
     }
 }
 
-impl<T: Write> Write for Decoder<T> {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.inner.write(buf)
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::FrameTooLarge { .. } => "frame size too big",
+            Error::LengthOverflow => "provided length would overflow after adjustment",
+            Error::UnexpectedEof => "eof",
+            Error::TruncatedPayload { .. } => "eof in frame payload",
+            Error::UnknownFormat(..) => "unknown frame format",
+            Error::Replay { .. } => "replayed frame",
+            Error::LengthMismatch => "suffix length does not match prefix length",
+            Error::TrailerMismatch => "frame trailer does not match payload",
+            Error::Io(ref e) => e.description(),
+        }
     }
 
-    fn flush(&mut self) -> io::Result<()> {
-        self.inner.flush()
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            Error::Io(ref e) => Some(e),
+            _ => None,
+        }
     }
 }
 
-impl<T: Sink> Sink for Decoder<T> {
-    type SinkItem = T::SinkItem;
-    type SinkError = T::SinkError;
-
-    fn start_send(&mut self, item: T::SinkItem)
-        -> StartSend<T::SinkItem, T::SinkError>
-    {
-        self.inner.start_send(item)
+impl From<io::Error> for Error {
+    fn from(src: io::Error) -> Error {
+        Error::Io(src)
     }
+}
 
-    fn poll_complete(&mut self) -> Poll<(), T::SinkError> {
-        self.inner.poll_complete()
+impl From<Error> for io::Error {
+    fn from(src: Error) -> io::Error {
+        match src {
+            Error::Io(e) => e,
+            e => io::Error::new(e.kind(), e),
+        }
     }
 }
 
 /*
  *
- * ===== impl Encoder =====
+ * ===== impl ConfigError =====
  *
  */
 
-impl<T, B: IntoBuf> Encoder<T, B> {
-    pub fn default(io: T) -> Encoder<T, B> {
-        Builder::new().encoder(io)
+impl fmt::Display for ConfigError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::NumSkipWithinHead { num_skip, head_len } => {
+                write!(fmt, "num_skip smaller than the frame head; num_skip={}; head_len={}", num_skip, head_len)
+            }
+            ConfigError::NonceOverlapsLength { nonce_offset, length_end } => {
+                write!(fmt, "nonce field overlaps the length field; nonce_offset={}; length_end={}", nonce_offset, length_end)
+            }
+        }
     }
+}
 
-    pub fn get_ref(&self) -> &T {
-        &self.inner
+impl error::Error for ConfigError {
+    fn description(&self) -> &str {
+        match *self {
+            ConfigError::NumSkipWithinHead { .. } => "num_skip smaller than the frame head",
+            ConfigError::NonceOverlapsLength { .. } => "nonce field overlaps the length field",
+        }
     }
+}
 
-    pub fn get_mut(&mut self) -> &mut T {
-        &mut self.inner
-    }
+/*
+ *
+ * ===== impl ByteOrder =====
+ *
+ */
 
-    pub fn into_inner(self) -> T {
-        self.inner
+impl ByteOrder {
+    fn read_uint(&self, buf: &[u8], nbytes: usize) -> u64 {
+        match *self {
+            ByteOrder::BigEndian => BigEndian::read_uint(buf, nbytes),
+            ByteOrder::LittleEndian => LittleEndian::read_uint(buf, nbytes),
+            ByteOrder::NativeEndian => NativeEndian::read_uint(buf, nbytes),
+        }
     }
-}
 
-impl<T: AsyncWrite, B: IntoBuf> Encoder<T, B> {
-    fn set_head(&mut self, buf: B::Buf) -> io::Result<()> {
-        let mut head = SliceBuf::new([0; 8]);
-        let n = buf.remaining();
+    fn put_uint<B: BufMut>(&self, buf: &mut B, n: u64, nbytes: usize) {
+        match *self {
+            ByteOrder::BigEndian => buf.put_uint::<BigEndian>(n, nbytes),
+            ByteOrder::LittleEndian => buf.put_uint::<LittleEndian>(n, nbytes),
+            ByteOrder::NativeEndian => buf.put_uint::<NativeEndian>(n, nbytes),
+        }
+    }
 
-        if n > self.builder.max_frame_len {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput, "frame too big"));
+    // Read an integer field of up to 16 bytes. A value that does not fit in
+    // a `u64` saturates to `u64::MAX`.
+    fn read_wide_uint(&self, buf: &[u8], nbytes: usize) -> u64 {
+        if nbytes <= 8 {
+            return self.read_uint(buf, nbytes);
         }
 
-        if self.builder.length_field_order == ByteOrder::BigEndian {
-            head.put_uint::<BigEndian>(n as u64, self.builder.length_field_len);
+        let ext = nbytes - 8;
+
+        let (high, low) = if self.is_big_endian() {
+            (&buf[..ext], &buf[ext..nbytes])
         } else {
-            head.put_uint::<LittleEndian>(n as u64, self.builder.length_field_len);
+            (&buf[8..nbytes], &buf[..8])
+        };
+
+        if high.iter().any(|&b| b != 0) {
+            return u64::MAX;
         }
 
-        self.state = WriteState::Head { head: head, data: buf };
-        Ok(())
+        self.read_uint(low, 8)
     }
 
-    // Write a frame head. This function will be called as part of
-    // `FramedIo::flush`.
-    fn write_head(&mut self) -> Poll<(), io::Error> {
-        // Loop as long as the upstream is ready
-        loop {
-            // Get a reference to the buffer.
-            let buf = match self.state {
-                WriteState::Head { ref mut head, .. } => head,
-                _ => unreachable!(),
-            };
-
-            // If there is no more data to write, then the frame head has been
-            // fully written, so return Ok.
-            if !buf.has_remaining() {
-                return Ok(Async::Ready(()));
-            };
+    // Write an integer field of up to 16 bytes, zero filling the bytes above
+    // the low 8
+    fn put_wide_uint<B: BufMut>(&self, buf: &mut B, n: u64, nbytes: usize) {
+        if nbytes <= 8 {
+            return self.put_uint(buf, n, nbytes);
+        }
 
-            // Write the data to the upstream. In the write case, 0 does not
-            // mean that the upstream has shutdown, so there is no need to
-            // check.
-            try_ready!(self.inner.try_write_buf(buf));
+        if self.is_big_endian() {
+            self.put_uint(buf, 0, nbytes - 8);
+            self.put_uint(buf, n, 8);
+        } else {
+            self.put_uint(buf, n, 8);
+            self.put_uint(buf, 0, nbytes - 8);
         }
     }
 
-    // Write a frame payload. This function will be called as part of
-    // `FramedIo::flush`. This fn is very similar to `write_head`
-    fn write_data(&mut self) -> Poll<(), io::Error> {
-        // Loop as long as the upstream is ready
-        loop {
-            // Get a reference to the buffer.
-            let buf = match self.state {
-                WriteState::Data(ref mut buf) => buf,
-                _ => unreachable!(),
-            };
+    fn is_big_endian(&self) -> bool {
+        match *self {
+            ByteOrder::BigEndian => true,
+            ByteOrder::LittleEndian => false,
+            ByteOrder::NativeEndian => cfg!(target_endian = "big"),
+        }
+    }
+}
 
-            // If there is no more data to write, then the frame payload has been
-            // fully written, so return Okl
-            if !buf.has_remaining() {
-                return Ok(Async::Ready(()));
-            };
+/*
+ *
+ * ===== impl MemoryBudget =====
+ *
+ */
 
-            // Write the data to the upstream. In the write case, 0 does not
-            // mean that the upstream has shutdown, so there is no need to
-            // check.
-            try_ready!(self.inner.try_write_buf(buf));
+impl MemoryBudget {
+    /// Create a new budget allowing `limit` bytes to be buffered
+    pub fn new(limit: usize) -> MemoryBudget {
+        MemoryBudget {
+            inner: Arc::new(BudgetInner {
+                limit: limit,
+                used: AtomicUsize::new(0),
+                waiters: Mutex::new(vec![]),
+            }),
         }
     }
-}
 
-impl<T: AsyncWrite, B: IntoBuf> Sink for Encoder<T, B> {
-    type SinkItem = B;
-    type SinkError = io::Error;
+    /// Returns the maximum number of bytes
+    pub fn limit(&self) -> usize {
+        self.inner.limit
+    }
 
-    fn start_send(&mut self, item: B)
-        -> StartSend<B, io::Error>
-    {
-        if !try!(self.poll_complete()).is_ready() {
-            return Ok(AsyncSink::NotReady(item));
+    /// Returns the number of bytes currently charged against the budget
+    pub fn used(&self) -> usize {
+        self.inner.used.load(Ordering::SeqCst)
+    }
+
+    // Try to charge `n` bytes, registering the current task to be notified
+    // on release if the budget is exhausted.
+    fn try_acquire(&self, n: usize) -> bool {
+        if self.acquire(n) {
+            return true;
         }
 
-        // Convert the value to a buffer
-        try!(self.set_head(item.into_buf()));
+        self.inner.waiters.lock().unwrap().push(task::park());
 
-        Ok(AsyncSink::Ready)
+        // The budget may have been released before the task was registered
+        self.acquire(n)
     }
 
-    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+    fn acquire(&self, n: usize) -> bool {
+        let mut used = self.inner.used.load(Ordering::SeqCst);
+
         loop {
-            match self.state {
-                // If in the ready state, then all data has been fully flushed
-                // and there is nothing more to do
-                WriteState::Ready => return Ok(Async::Ready(())),
+            if used != 0 && used.saturating_add(n) > self.inner.limit {
+                return false;
+            }
 
-                // Currently writing the frame head
-                WriteState::Head { .. } => {
-                    // Write the frame head, returning if `write_head` returns
-                    // an error or `NotReady`
-                    try_ready!(self.write_head());
+            let prev = self.inner.used.compare_and_swap(used, used + n, Ordering::SeqCst);
 
-                    // The head has been fully written to the upstream, transition to
-                    // writing the payload
-                    match mem::replace(&mut self.state, WriteState::Ready) {
-                        WriteState::Head { data, .. } => {
-                            self.state = WriteState::Data(data);
-                        }
-                        _ => unreachable!(),
-                    }
-                }
+            if prev == used {
+                return true;
+            }
 
-                // Currently writing the frame payload
-                WriteState::Data(..) => {
-                    // Write the frame payload, returning if `write_data` returns
-                    // an error or `NotReady`
-                    try_ready!(self.write_data());
+            used = prev;
+        }
+    }
 
-                    // The payload has been fully written to the upstream,
-                    // transition to ready.
-                    self.state = WriteState::Ready;
-                }
-            }
+    fn release(&self, n: usize) {
+        self.inner.used.fetch_sub(n, Ordering::SeqCst);
+
+        let waiters = mem::replace(&mut *self.inner.waiters.lock().unwrap(), vec![]);
+
+        for task in waiters {
+            task.unpark();
         }
     }
 }
 
-impl<T: Read, B: IntoBuf> Read for Encoder<T, B> {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.inner.read(buf)
+impl fmt::Debug for MemoryBudget {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("MemoryBudget")
+            .field("limit", &self.limit())
+            .field("used", &self.used())
+            .finish()
     }
 }
 
-impl<T: Stream, B: IntoBuf> Stream for Encoder<T, B> {
-    type Item = T::Item;
-    type Error = T::Error;
-
-    fn poll(&mut self) -> Poll<Option<T::Item>, T::Error> {
-        self.inner.poll()
+impl Drop for Charge {
+    fn drop(&mut self) {
+        self.budget.release(self.amount);
     }
 }
 
 /*
  *
- * ===== impl Builder =====
+ * ===== impl ByteRate =====
  *
  */
 
-impl Builder {
-    pub fn new() -> Builder {
-        Builder {
-            // Default max frame length of 8MB
-            max_frame_len: 8 * 1_024 * 1_024,
+impl ByteRate {
+    // Maximum number of tokens held by the bucket
+    fn capacity(&self) -> u64 {
+        cmp::max(self.bytes_per_sec / 10, 1)
+    }
 
-            // Default byte length of 4
-            length_field_len: 4,
+    // Returns the number of bytes that may be read, or waits for the bucket
+    // to refill when it is empty
+    fn poll_tokens(&mut self) -> Poll<u64, io::Error> {
+        loop {
+            self.refill();
 
-            // Default to the header field being at the start of the header.
-            length_field_offset: 0,
+            if self.tokens > 0 {
+                self.timeout = None;
+                return Ok(Async::Ready(self.tokens));
+            }
 
-            length_adjustment: 0,
+            // Wait until the bucket is full again
+            let wait = nanos_to_duration(self.capacity() * 1_000_000_000 / self.bytes_per_sec);
+            let at = self.refilled + wait;
 
-            // Total number of bytes to skip before reading the payload, if not set,
-            // `length_field_len + length_field_offset`
-            num_skip: None,
+            match self.timeout {
+                Some(ref mut timeout) => timeout.reset(at),
+                None => self.timeout = Some(try!(Timeout::new_at(at, &self.handle))),
+            }
 
-            // Default to reading the length field in network (big) endian.
-            length_field_order: ByteOrder::BigEndian,
+            if let Some(ref mut timeout) = self.timeout {
+                try_ready!(timeout.poll());
+            }
         }
     }
 
-    /// Sets the max frame length
-    pub fn set_max_frame_length(mut self, val: usize) -> Self {
-        self.max_frame_len = val;
-        self
-    }
+    // Add the tokens accumulated since the last refill
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = duration_to_nanos(now - self.refilled);
+        let added = elapsed.saturating_mul(self.bytes_per_sec) / 1_000_000_000;
 
-    /// Sets the number of bytes used to represent the length field
-    pub fn set_length_field_length(mut self, val: usize) -> Self {
-        assert!(val > 0 && val <= 8, "invalid length field length");
-        self.length_field_len = val;
-        self
+        if self.tokens + added >= self.capacity() {
+            self.tokens = self.capacity();
+            self.refilled = now;
+        } else if added > 0 {
+            // Keep the remainder towards the next token
+            self.tokens += added;
+            self.refilled = self.refilled + nanos_to_duration(added * 1_000_000_000 / self.bytes_per_sec);
+        }
     }
+}
 
-    /// Sets the number of bytes in the header before the length field
-    pub fn set_length_field_offset(mut self, val: usize) -> Self {
-        self.length_field_offset = val;
-        self
+fn duration_to_nanos(dur: Duration) -> u64 {
+    dur.as_secs().saturating_mul(1_000_000_000).saturating_add(dur.subsec_nanos() as u64)
+}
+
+fn nanos_to_duration(nanos: u64) -> Duration {
+    Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32)
+}
+
+/// FNV-1a hash of `data`, truncated to `nbytes`
+fn content_hash(data: &[u8], nbytes: usize) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+
+    for &b in data {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
     }
 
-    /// Delta between the payload length specified in the header and the real
-    /// payload length
-    pub fn set_length_adjustment(mut self, val: isize) -> Self {
-        self.length_adjustment = val;
-        self
+    truncate(hash, nbytes)
+}
+
+/// CRC-32 (IEEE) checksum of `data`
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+
+    for &b in data {
+        crc ^= b as u32;
+
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb88320
+            } else {
+                crc >> 1
+            };
+        }
     }
 
-    /// Sets the number of bytes to skip before reading the payload
-    ///
-    /// Defaults to `length_field_len + length_field_offset`
-    pub fn set_num_skip(mut self, val: usize) -> Self {
-        self.num_skip = Some(val);
-        self
+    !crc
+}
+
+/// Returns true if `err` was caused by the connection rather than by the
+/// frames sent over it
+fn is_transport_error(err: &io::Error) -> bool {
+    match err.kind() {
+        io::ErrorKind::InvalidData | io::ErrorKind::InvalidInput => false,
+        _ => true,
     }
+}
 
-    /// Sets the expected byte order 
-    ///
-    /// Defaults to `ByteOrder::BigEndian`
-    pub fn set_byte_order(mut self, val: ByteOrder) -> Self {
-        self.length_field_order = val;
-        self
+/// Convert a frame length read from the wire to `usize`, failing if it does
+/// not fit on the target
+fn to_usize(n: u64) -> io::Result<usize> {
+    if n as usize as u64 != n {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "frame length does not fit in usize"));
     }
 
-    /// Build the length delimted decoder
-    pub fn decoder<T>(self, io: T) -> Decoder<T> {
-        Decoder {
-            inner: io,
-            builder: self,
-            buf: ByteBuf::new(),
-            state: ReadState::Head,
-        }
+    Ok(n as usize)
+}
+
+/// Apply a length adjustment to `n`, returning `None` on overflow
+fn adjust_len(n: usize, adjustment: isize) -> Option<usize> {
+    if adjustment < 0 {
+        n.checked_sub(-adjustment as usize)
+    } else {
+        n.checked_add(adjustment as usize)
     }
+}
 
-    pub fn encoder<T, B: IntoBuf>(self, io: T) -> Encoder<T, B> {
-        Encoder {
-            inner: io,
-            builder: self,
-            state: WriteState::Ready,
-        }
+/// Interpret the low `nbytes` bytes of `n` as a two's complement integer
+fn sign_extend(n: u64, nbytes: usize) -> i64 {
+    let shift = 64 - cmp::min(nbytes, 8) * 8;
+    ((n << shift) as i64) >> shift
+}
+
+/// Keep only the low `nbytes` bytes of `n`
+fn truncate(n: u64, nbytes: usize) -> u64 {
+    if nbytes < 8 {
+        n & ((1 << (nbytes * 8)) - 1)
+    } else {
+        n
     }
+}
 
-    /// Number of header bytes to read
-    fn num_head_bytes(&self) -> usize {
-        let num = self.length_field_offset + self.length_field_len;
-        cmp::max(num, self.num_skip.unwrap_or(0))
+/// Read `len` bits starting `offset` bits into `buf`, most significant bit
+/// first
+fn read_bits(buf: &[u8], offset: usize, len: usize) -> u64 {
+    let mut n = 0;
+
+    for pos in offset..offset + len {
+        let bit = (buf[pos / 8] >> (7 - pos % 8)) & 1;
+        n = (n << 1) | bit as u64;
     }
 
-    fn num_skip(&self) -> usize {
-        self.num_skip.unwrap_or(self.length_field_offset + self.length_field_len)
+    n
+}
+
+/// Write the low `len` bits of `n` starting `offset` bits into `buf`, leaving
+/// the surrounding bits untouched
+fn put_bits(buf: &mut [u8], offset: usize, len: usize, n: u64) {
+    for i in 0..len {
+        let pos = offset + i;
+        let mask = 0x80 >> (pos % 8);
+
+        if (n >> (len - 1 - i)) & 1 == 1 {
+            buf[pos / 8] |= mask;
+        } else {
+            buf[pos / 8] &= !mask;
+        }
     }
 }
+
+// Number of bytes to reserve for each read when the payload size is not known
+const LINES_READ_SIZE: usize = 1_024;
+
+// Number of bytes to reserve for each read while looking for the initial skip
+// marker
+const BANNER_READ_SIZE: usize = 1_024;
+
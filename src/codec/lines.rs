@@ -0,0 +1,329 @@
+use io::{AsyncRead, AsyncWrite};
+use codec::memchr;
+use bytes::{Buf, IntoBuf, BytesMut, ByteBuf};
+use futures::{Async, AsyncSink, Poll, Sink, Stream, StartSend};
+
+use std::io::{self, Read, Write};
+
+/// A decoder that splits the bytes read into `BytesMut` values on `\n`.
+///
+/// The newline is not included in the yielded line. If enabled, a `\r`
+/// directly preceding the newline is also stripped. A final line that is not
+/// terminated by a newline is yielded when the upstream reaches EOF.
+pub struct Decoder<T> {
+    // I/O type
+    inner: T,
+
+    // Configuration values
+    builder: Builder,
+
+    // Buffer
+    buf: ByteBuf,
+
+    // Number of buffered bytes already searched for a newline
+    searched: usize,
+
+    // True once the upstream has been shutdown
+    eof: bool,
+}
+
+/// An encoder that writes each buffer followed by `\n`.
+pub struct Encoder<T, B: IntoBuf> {
+    // I/O type
+    inner: T,
+
+    // Write state
+    state: WriteState<B::Buf>,
+}
+
+pub struct Builder {
+    // Maximum line length, excluding the line ending
+    max_line_len: usize,
+
+    // Strip a `\r` preceding the `\n`
+    strip_cr: bool,
+}
+
+enum WriteState<B> {
+    Ready,
+    Data(B),
+    Newline,
+}
+
+// Number of bytes to reserve for each read
+const READ_SIZE: usize = 1_024;
+
+/*
+ *
+ * ===== impl Decoder =====
+ *
+ */
+
+impl<T> Decoder<T> {
+    pub fn default(io: T) -> Decoder<T> {
+        Builder::new().decoder(io)
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T> Decoder<T> {
+    // Split the next line off the buffer, if a full line has been buffered
+    fn decode_line(&mut self) -> io::Result<Option<BytesMut>> {
+        let pos = memchr::memchr(b'\n', &self.buf.bytes()[self.searched..])
+            .map(|pos| pos + self.searched);
+
+        let pos = match pos {
+            Some(pos) => pos,
+            None => {
+                self.searched = self.buf.len();
+
+                // A trailing `\r` may still be stripped once the newline
+                // arrives
+                let mut len = self.buf.len();
+
+                if self.builder.strip_cr && self.buf.bytes().last() == Some(&b'\r') {
+                    len -= 1;
+                }
+
+                if len > self.builder.max_line_len {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"));
+                }
+
+                return Ok(None);
+            }
+        };
+
+        let mut line = self.buf.drain_to(pos + 1);
+        self.searched = 0;
+
+        // Strip the line ending
+        line.truncate(pos);
+
+        self.finish_line(line).map(Some)
+    }
+
+    // Strip the `\r` ending the line, if enabled, and check its length
+    fn finish_line(&self, mut line: BytesMut) -> io::Result<BytesMut> {
+        if self.builder.strip_cr && line.last() == Some(&b'\r') {
+            let len = line.len();
+            line.truncate(len - 1);
+        }
+
+        if line.len() > self.builder.max_line_len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"));
+        }
+
+        Ok(line)
+    }
+}
+
+impl<T: AsyncRead> Stream for Decoder<T> {
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<BytesMut>, io::Error> {
+        loop {
+            if let Some(line) = try!(self.decode_line()) {
+                return Ok(Async::Ready(Some(line)));
+            }
+
+            // The upstream has been shutdown, yield any unterminated line
+            if self.eof {
+                if self.buf.is_empty() {
+                    return Ok(Async::Ready(None));
+                }
+
+                let len = self.buf.len();
+                let line = self.buf.drain_to(len);
+                self.searched = 0;
+
+                return self.finish_line(line).map(|line| Async::Ready(Some(line)));
+            }
+
+            // Ensure the buffer has enough space
+            self.buf.reserve(READ_SIZE);
+
+            let read = try_ready!(self.inner.try_read_buf(&mut self.buf));
+
+            if read == 0 {
+                self.eof = true;
+            }
+        }
+    }
+}
+
+impl<T: Write> Write for Decoder<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: Sink> Sink for Decoder<T> {
+    type SinkItem = T::SinkItem;
+    type SinkError = T::SinkError;
+
+    fn start_send(&mut self, item: T::SinkItem)
+        -> StartSend<T::SinkItem, T::SinkError>
+    {
+        self.inner.start_send(item)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), T::SinkError> {
+        self.inner.poll_complete()
+    }
+}
+
+/*
+ *
+ * ===== impl Encoder =====
+ *
+ */
+
+impl<T, B: IntoBuf> Encoder<T, B> {
+    pub fn new(io: T) -> Encoder<T, B> {
+        Encoder {
+            inner: io,
+            state: WriteState::Ready,
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: AsyncWrite, B: IntoBuf> Encoder<T, B> {
+    fn write_data(&mut self) -> Poll<(), io::Error> {
+        loop {
+            let buf = match self.state {
+                WriteState::Data(ref mut buf) => buf,
+                _ => unreachable!(),
+            };
+
+            if !buf.has_remaining() {
+                return Ok(Async::Ready(()));
+            }
+
+            try_ready!(self.inner.try_write_buf(buf));
+        }
+    }
+}
+
+impl<T: AsyncWrite, B: IntoBuf> Sink for Encoder<T, B> {
+    type SinkItem = B;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: B) -> StartSend<B, io::Error> {
+        if !try!(self.poll_complete()).is_ready() {
+            return Ok(AsyncSink::NotReady(item));
+        }
+
+        self.state = WriteState::Data(item.into_buf());
+
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        loop {
+            match self.state {
+                WriteState::Ready => return Ok(Async::Ready(())),
+
+                // Currently writing the line
+                WriteState::Data(..) => {
+                    try_ready!(self.write_data());
+                    self.state = WriteState::Newline;
+                }
+
+                // Currently writing the line ending
+                WriteState::Newline => {
+                    if try_ready!(self.inner.try_write(b"\n")) == 0 {
+                        return Err(io::Error::new(io::ErrorKind::WriteZero, "zero-length write"));
+                    }
+
+                    self.state = WriteState::Ready;
+                }
+            }
+        }
+    }
+}
+
+impl<T: Read, B: IntoBuf> Read for Encoder<T, B> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<T: Stream, B: IntoBuf> Stream for Encoder<T, B> {
+    type Item = T::Item;
+    type Error = T::Error;
+
+    fn poll(&mut self) -> Poll<Option<T::Item>, T::Error> {
+        self.inner.poll()
+    }
+}
+
+/*
+ *
+ * ===== impl Builder =====
+ *
+ */
+
+impl Builder {
+    pub fn new() -> Builder {
+        Builder {
+            // Default max line length of 64KB
+            max_line_len: 64 * 1_024,
+
+            // Default to accepting both `\n` and `\r\n` line endings
+            strip_cr: true,
+        }
+    }
+
+    /// Sets the max line length, excluding the line ending
+    pub fn set_max_line_length(mut self, val: usize) -> Self {
+        self.max_line_len = val;
+        self
+    }
+
+    /// Sets whether a `\r` preceding the `\n` is stripped
+    ///
+    /// Defaults to `true`
+    pub fn set_strip_cr(mut self, val: bool) -> Self {
+        self.strip_cr = val;
+        self
+    }
+
+    /// Build the lines decoder
+    pub fn decoder<T>(self, io: T) -> Decoder<T> {
+        Decoder {
+            inner: io,
+            builder: self,
+            buf: ByteBuf::new(),
+            searched: 0,
+            eof: false,
+        }
+    }
+}
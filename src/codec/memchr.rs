@@ -0,0 +1,54 @@
+use byteorder::{ByteOrder, LittleEndian};
+
+// Bytes examined per step
+const WORD_LEN: usize = 8;
+
+const LO: u64 = 0x0101010101010101;
+const HI: u64 = 0x8080808080808080;
+
+/// Returns the index of the first occurrence of `needle` in `haystack`
+///
+/// Eight bytes are checked at a time, only falling back to looking at
+/// individual bytes within a word that holds the needle.
+pub fn memchr(needle: u8, haystack: &[u8]) -> Option<usize> {
+    let repeated = LO * needle as u64;
+    let mut pos = 0;
+
+    while pos + WORD_LEN <= haystack.len() {
+        let word = LittleEndian::read_uint(&haystack[pos..], WORD_LEN) ^ repeated;
+
+        // Non zero if any byte of the word is zero, ie. equal to the needle
+        if word.wrapping_sub(LO) & !word & HI != 0 {
+            break;
+        }
+
+        pos += WORD_LEN;
+    }
+
+    haystack[pos..].iter()
+        .position(|&b| b == needle)
+        .map(|i| pos + i)
+}
+
+/// Returns the index of the first occurrence of the non empty `needle` in
+/// `haystack`
+pub fn find(needle: &[u8], haystack: &[u8]) -> Option<usize> {
+    let mut pos = 0;
+
+    while haystack.len() - pos >= needle.len() {
+        match memchr(needle[0], &haystack[pos..haystack.len() - needle.len() + 1]) {
+            Some(i) => {
+                let start = pos + i;
+
+                if &haystack[start..start + needle.len()] == needle {
+                    return Some(start);
+                }
+
+                pos = start + 1;
+            }
+            None => return None,
+        }
+    }
+
+    None
+}
@@ -1 +1,10 @@
+pub mod delimited;
 pub mod length_delimited;
+pub mod lines;
+
+mod dispatch;
+mod framed;
+mod memchr;
+
+pub use self::dispatch::{DispatchDecoder, Registry};
+pub use self::framed::{Decode, Encode, FramedRead, FramedWrite};
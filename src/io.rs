@@ -1,7 +1,7 @@
-use futures::{Async, Poll};
+use futures::{Async, Future, Poll};
 use bytes::{Buf, BufMut};
 
-use std::io;
+use std::{cmp, io, mem, net};
 
 pub trait AsyncRead: io::Read {
     /// Pull some bytes from this source into the specified buffer, returning
@@ -90,6 +90,38 @@ pub trait AsyncWrite: io::Write {
             Err(e) => Err(e),
         }
     }
+
+    /// Shutdown the write side of the underlying IO once all data has been
+    /// written.
+    ///
+    /// `AsyncWrite` is implemented for every `std::io::Write`, so this cannot
+    /// be overridden and only flushes. Types whose write side can really be
+    /// shutdown implement `Shutdown`.
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.try_flush()
+    }
+}
+
+/// An `AsyncWrite` whose write side can be shutdown, such as a TCP stream.
+pub trait Shutdown: AsyncWrite {
+    /// Flush, then shutdown the write side of the underlying IO, so that the
+    /// peer reads EOF.
+    fn try_shutdown(&mut self) -> Poll<(), io::Error>;
+}
+
+/// An `AsyncRead` with an internal buffer, allowing the buffered bytes to be
+/// inspected before they are consumed.
+pub trait AsyncBufRead: AsyncRead {
+    /// Returns the contents of the internal buffer, filling it with more data
+    /// from the inner reader if it is empty.
+    ///
+    /// If the buffer is empty and no data can be read, `Ok(Async::NotReady)`
+    /// is returned. An empty slice means the inner reader has reached EOF.
+    fn poll_fill_buf(&mut self) -> Poll<&[u8], io::Error>;
+
+    /// Marks `amt` bytes of the internal buffer as consumed, so they are no
+    /// longer returned by `poll_fill_buf` or reads.
+    fn consume(&mut self, amt: usize);
 }
 
 impl<T: io::Read> AsyncRead for T {
@@ -97,3 +129,690 @@ impl<T: io::Read> AsyncRead for T {
 
 impl<T: io::Write> AsyncWrite for T {
 }
+
+impl Shutdown for net::TcpStream {
+    fn try_shutdown(&mut self) -> Poll<(), io::Error> {
+        try_ready!(self.try_flush());
+        try!(net::TcpStream::shutdown(self, net::Shutdown::Write));
+        Ok(Async::Ready(()))
+    }
+}
+
+impl Shutdown for ::tokio_core::net::TcpStream {
+    fn try_shutdown(&mut self) -> Poll<(), io::Error> {
+        try_ready!(self.try_flush());
+        try!(::tokio_core::net::TcpStream::shutdown(self, net::Shutdown::Write));
+        Ok(Async::Ready(()))
+    }
+}
+
+/// Adds buffering to an `AsyncRead`, implementing `AsyncBufRead`.
+pub struct BufReader<T> {
+    inner: T,
+
+    // Bytes of `buf` in the range `pos..cap` have not been consumed yet
+    pos: usize,
+    cap: usize,
+    buf: Box<[u8]>,
+}
+
+// Default size of the `BufReader` buffer
+const DEFAULT_BUF_SIZE: usize = 8 * 1_024;
+
+impl<T> BufReader<T> {
+    /// Creates a `BufReader` with a buffer of 8KB
+    pub fn new(inner: T) -> BufReader<T> {
+        BufReader::with_capacity(DEFAULT_BUF_SIZE, inner)
+    }
+
+    /// Creates a `BufReader` with a buffer of `capacity` bytes
+    pub fn with_capacity(capacity: usize, inner: T) -> BufReader<T> {
+        BufReader {
+            inner: inner,
+            pos: 0,
+            cap: 0,
+            buf: vec![0; capacity].into_boxed_slice(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Returns the inner reader. Any buffered data is lost.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: AsyncRead> AsyncBufRead for BufReader<T> {
+    fn poll_fill_buf(&mut self) -> Poll<&[u8], io::Error> {
+        if self.pos == self.cap {
+            self.cap = try_ready!(self.inner.try_read(&mut self.buf));
+            self.pos = 0;
+        }
+
+        Ok(Async::Ready(&self.buf[self.pos..self.cap]))
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = cmp::min(self.pos + amt, self.cap);
+    }
+}
+
+impl<T: io::Read> io::Read for BufReader<T> {
+    fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+        // Bypass the buffer for reads at least as large as it
+        if self.pos == self.cap && dst.len() >= self.buf.len() {
+            return self.inner.read(dst);
+        }
+
+        if self.pos == self.cap {
+            self.cap = try!(self.inner.read(&mut self.buf));
+            self.pos = 0;
+        }
+
+        let n = cmp::min(dst.len(), self.cap - self.pos);
+        dst[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+
+        Ok(n)
+    }
+}
+
+/// Create a future which reads exactly enough bytes to fill `buf`
+///
+/// The future resolves to the reader and the filled buffer. If the reader
+/// reaches EOF before the buffer is full, an `UnexpectedEof` error is
+/// returned.
+///
+/// This is a free function rather than an `AsyncRead` method to avoid
+/// clashing with `std::io::Read::read_exact`.
+pub fn read_exact<A: AsyncRead>(a: A, buf: Vec<u8>) -> ReadExact<A> {
+    ReadExact {
+        state: ReadExactState::Reading {
+            a: a,
+            buf: buf,
+            pos: 0,
+        },
+    }
+}
+
+/// A future which reads exactly enough bytes to fill a buffer, created by
+/// `read_exact`.
+pub struct ReadExact<A> {
+    state: ReadExactState<A>,
+}
+
+enum ReadExactState<A> {
+    Reading {
+        a: A,
+        buf: Vec<u8>,
+        pos: usize,
+    },
+    Empty,
+}
+
+impl<A: AsyncRead> Future for ReadExact<A> {
+    type Item = (A, Vec<u8>);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(A, Vec<u8>), io::Error> {
+        match self.state {
+            ReadExactState::Reading { ref mut a, ref mut buf, ref mut pos } => {
+                while *pos < buf.len() {
+                    let n = try_ready!(a.try_read(&mut buf[*pos..]));
+
+                    if n == 0 {
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "eof"));
+                    }
+
+                    *pos += n;
+                }
+            }
+            ReadExactState::Empty => panic!("poll a ReadExact after it's done"),
+        }
+
+        match mem::replace(&mut self.state, ReadExactState::Empty) {
+            ReadExactState::Reading { a, buf, .. } => Ok(Async::Ready((a, buf))),
+            ReadExactState::Empty => unreachable!(),
+        }
+    }
+}
+
+/// Create a future which reads all bytes from `a` until EOF, appending them
+/// to `buf`
+///
+/// The future resolves to the reader and the filled buffer. The buffer grows
+/// geometrically as data arrives.
+///
+/// This is a free function rather than an `AsyncRead` method to avoid
+/// clashing with `std::io::Read::read_to_end`.
+pub fn read_to_end<A: AsyncRead>(a: A, buf: Vec<u8>) -> ReadToEnd<A> {
+    ReadToEnd {
+        state: ReadToEndState::Reading {
+            a: a,
+            len: buf.len(),
+            buf: buf,
+        },
+    }
+}
+
+/// A future which reads all bytes until EOF, created by `read_to_end`.
+pub struct ReadToEnd<A> {
+    state: ReadToEndState<A>,
+}
+
+enum ReadToEndState<A> {
+    Reading {
+        a: A,
+        buf: Vec<u8>,
+        // Number of bytes of `buf` holding data, the rest is zeroed space
+        // for the next read
+        len: usize,
+    },
+    Empty,
+}
+
+// Minimum number of bytes by which `read_to_end` grows its buffer
+const MIN_READ_SIZE: usize = 32;
+
+impl<A: AsyncRead> Future for ReadToEnd<A> {
+    type Item = (A, Vec<u8>);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(A, Vec<u8>), io::Error> {
+        match self.state {
+            ReadToEndState::Reading { ref mut a, ref mut buf, ref mut len } => {
+                loop {
+                    // Double the buffer once it is full
+                    if *len == buf.len() {
+                        let grow = cmp::max(buf.len(), MIN_READ_SIZE);
+                        buf.resize(*len + grow, 0);
+                    }
+
+                    let n = try_ready!(a.try_read(&mut buf[*len..]));
+
+                    if n == 0 {
+                        buf.truncate(*len);
+                        break;
+                    }
+
+                    *len += n;
+                }
+            }
+            ReadToEndState::Empty => panic!("poll a ReadToEnd after it's done"),
+        }
+
+        match mem::replace(&mut self.state, ReadToEndState::Empty) {
+            ReadToEndState::Reading { a, buf, .. } => Ok(Async::Ready((a, buf))),
+            ReadToEndState::Empty => unreachable!(),
+        }
+    }
+}
+
+// Buffers shorter than this are copied together before being written
+const COALESCE_LEN: usize = 256;
+
+/// Write the contents of several buffers in order, returning the total
+/// number of bytes written across them.
+///
+/// A short leading buffer, such as a frame head, is copied together with the
+/// start of the following buffers so that they go out in a single write. A
+/// leading buffer of `COALESCE_LEN` bytes or more is written on its own.
+///
+/// This is a free function rather than an `AsyncWrite` method, as
+/// `AsyncWrite` is implemented for every `std::io::Write` and so could not
+/// be specialized for writers supporting vectored writes.
+pub fn try_write_bufs<A: AsyncWrite>(a: &mut A, bufs: &[&[u8]]) -> Poll<usize, io::Error> {
+    let mut bufs = bufs.iter().filter(|buf| !buf.is_empty());
+
+    let first = match bufs.next() {
+        Some(buf) => buf,
+        None => return Ok(Async::Ready(0)),
+    };
+
+    if first.len() >= COALESCE_LEN {
+        return a.try_write(first);
+    }
+
+    let mut scratch = [0; COALESCE_LEN];
+    let mut len = first.len();
+
+    scratch[..len].copy_from_slice(first);
+
+    for buf in bufs {
+        let n = cmp::min(buf.len(), COALESCE_LEN - len);
+        scratch[len..len + n].copy_from_slice(&buf[..n]);
+        len += n;
+
+        if len == COALESCE_LEN {
+            break;
+        }
+    }
+
+    a.try_write(&scratch[..len])
+}
+
+/// Create a future which writes the entire contents of `buf` to `a`
+///
+/// The future resolves to the writer and the drained buffer.
+///
+/// This is a free function rather than an `AsyncWrite` method to avoid
+/// clashing with `std::io::Write::write_all`.
+pub fn write_all<A: AsyncWrite, B: Buf>(a: A, buf: B) -> WriteAll<A, B> {
+    WriteAll {
+        state: WriteAllState::Writing {
+            a: a,
+            buf: buf,
+        },
+    }
+}
+
+/// A future which writes the entire contents of a buffer, created by
+/// `write_all`.
+pub struct WriteAll<A, B> {
+    state: WriteAllState<A, B>,
+}
+
+enum WriteAllState<A, B> {
+    Writing {
+        a: A,
+        buf: B,
+    },
+    Empty,
+}
+
+impl<A: AsyncWrite, B: Buf> Future for WriteAll<A, B> {
+    type Item = (A, B);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(A, B), io::Error> {
+        match self.state {
+            WriteAllState::Writing { ref mut a, ref mut buf } => {
+                while buf.has_remaining() {
+                    let n = try_ready!(a.try_write_buf(buf));
+
+                    if n == 0 {
+                        return Err(io::Error::new(io::ErrorKind::WriteZero, "zero-length write"));
+                    }
+                }
+            }
+            WriteAllState::Empty => panic!("poll a WriteAll after it's done"),
+        }
+
+        match mem::replace(&mut self.state, WriteAllState::Empty) {
+            WriteAllState::Writing { a, buf } => Ok(Async::Ready((a, buf))),
+            WriteAllState::Empty => unreachable!(),
+        }
+    }
+}
+
+/// Create a future which flushes `a`, resolving to it once the flush has
+/// completed
+///
+/// This is a free function rather than an `AsyncWrite` method to avoid
+/// clashing with `std::io::Write::flush`.
+pub fn flush<A: AsyncWrite>(a: A) -> Flush<A> {
+    Flush {
+        a: Some(a),
+    }
+}
+
+/// A future which flushes a writer, created by `flush`.
+pub struct Flush<A> {
+    a: Option<A>,
+}
+
+impl<A: AsyncWrite> Future for Flush<A> {
+    type Item = A;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<A, io::Error> {
+        match self.a {
+            Some(ref mut a) => try_ready!(a.try_flush()),
+            None => panic!("poll a Flush after it's done"),
+        }
+
+        Ok(Async::Ready(self.a.take().unwrap()))
+    }
+}
+
+/// Create a reader yielding at most `limit` bytes from `a`
+///
+/// Once `limit` bytes have been read, reads return 0 as if `a` had reached
+/// EOF. The rest of the stream is recovered with `Take::into_inner`.
+///
+/// This is a free function rather than an `AsyncRead` method to avoid
+/// clashing with `std::io::Read::take`.
+pub fn take<A: AsyncRead>(a: A, limit: u64) -> Take<A> {
+    Take {
+        inner: a,
+        limit: limit,
+    }
+}
+
+/// A reader yielding a limited number of bytes, created by `take`.
+pub struct Take<A> {
+    inner: A,
+
+    // Number of bytes that may still be read
+    limit: u64,
+}
+
+impl<A> Take<A> {
+    /// Returns the number of bytes that may still be read
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    pub fn get_ref(&self) -> &A {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut A {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> A {
+        self.inner
+    }
+}
+
+impl<A: io::Read> io::Read for Take<A> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.limit == 0 {
+            return Ok(0);
+        }
+
+        let max = cmp::min(buf.len() as u64, self.limit) as usize;
+        let n = try!(self.inner.read(&mut buf[..max]));
+
+        self.limit -= n as u64;
+        Ok(n)
+    }
+}
+
+/// Create a reader yielding all bytes from `first` until it reaches EOF, then
+/// all bytes from `second`
+///
+/// This allows bytes already read off a stream, for example while sniffing
+/// the protocol, to be put back in front of the rest of the stream.
+///
+/// This is a free function rather than an `AsyncRead` method to avoid
+/// clashing with `std::io::Read::chain`.
+pub fn chain<A: AsyncRead, B: AsyncRead>(first: A, second: B) -> Chain<A, B> {
+    Chain {
+        first: first,
+        second: second,
+        done_first: false,
+    }
+}
+
+/// A reader concatenating two readers, created by `chain`.
+pub struct Chain<A, B> {
+    first: A,
+    second: B,
+
+    // True once the first reader has returned EOF
+    done_first: bool,
+}
+
+impl<A, B> Chain<A, B> {
+    pub fn get_ref(&self) -> (&A, &B) {
+        (&self.first, &self.second)
+    }
+
+    pub fn get_mut(&mut self) -> (&mut A, &mut B) {
+        (&mut self.first, &mut self.second)
+    }
+
+    pub fn into_inner(self) -> (A, B) {
+        (self.first, self.second)
+    }
+}
+
+impl<A: io::Read, B: io::Read> io::Read for Chain<A, B> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.done_first {
+            match try!(self.first.read(buf)) {
+                0 if !buf.is_empty() => self.done_first = true,
+                n => return Ok(n),
+            }
+        }
+
+        self.second.read(buf)
+    }
+}
+
+/// A reader counting the bytes read through it.
+pub struct CountRead<R> {
+    inner: R,
+
+    // Total number of bytes read
+    read: u64,
+}
+
+impl<R> CountRead<R> {
+    pub fn new(inner: R) -> CountRead<R> {
+        CountRead {
+            inner: inner,
+            read: 0,
+        }
+    }
+
+    /// Returns the total number of bytes read so far
+    pub fn bytes_read(&self) -> u64 {
+        self.read
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: io::Read> io::Read for CountRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = try!(self.inner.read(buf));
+        self.read += n as u64;
+        Ok(n)
+    }
+}
+
+/// A writer counting the bytes written through it.
+pub struct CountWrite<W> {
+    inner: W,
+
+    // Total number of bytes written
+    written: u64,
+}
+
+impl<W> CountWrite<W> {
+    pub fn new(inner: W) -> CountWrite<W> {
+        CountWrite {
+            inner: inner,
+            written: 0,
+        }
+    }
+
+    /// Returns the total number of bytes written so far
+    pub fn bytes_written(&self) -> u64 {
+        self.written
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: io::Write> io::Write for CountWrite<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = try!(self.inner.write(buf));
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A reader returning at most a fixed number of bytes per read.
+///
+/// Every `read`, and so every `try_read` poll of the reader, is capped at
+/// `max_bytes_per_poll` bytes, as a slow peer delivering small packets would.
+/// This forces the consumer through its partial read paths.
+///
+/// The reader never blocks on its own and does not need to run within a
+/// task, so it may also be used with `std::io` functions. To limit how much
+/// a decoder reads per task poll, and yield once it is reached, see
+/// `length_delimited::Builder::set_read_chunk_limit`.
+pub struct Throttle<R> {
+    inner: R,
+
+    // Maximum number of bytes returned by a single read
+    max_bytes_per_poll: usize,
+}
+
+impl<R> Throttle<R> {
+    /// # Panics
+    ///
+    /// Panics if `max_bytes_per_poll` is 0.
+    pub fn new(inner: R, max_bytes_per_poll: usize) -> Throttle<R> {
+        assert!(max_bytes_per_poll > 0, "max bytes per poll must be greater than 0");
+
+        Throttle {
+            inner: inner,
+            max_bytes_per_poll: max_bytes_per_poll,
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: io::Read> io::Read for Throttle<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let max = cmp::min(buf.len(), self.max_bytes_per_poll);
+        self.inner.read(&mut buf[..max])
+    }
+}
+
+/// Create a future which copies all data from `reader` into `writer`
+///
+/// The writer is flushed once the reader reaches EOF, after which the future
+/// resolves to the total number of bytes copied.
+pub fn copy<R: AsyncRead, W: AsyncWrite>(reader: R, writer: W) -> Copy<R, W> {
+    copy_bounded(reader, writer, COPY_BUF_SIZE)
+}
+
+/// Like `copy`, but buffering at most `buf_size` bytes between the reader
+/// and the writer
+///
+/// Nothing more is read until the writer has accepted all buffered data, so
+/// a slow writer holds back a fast reader instead of growing the buffer.
+///
+/// # Panics
+///
+/// Panics if `buf_size` is 0.
+pub fn copy_bounded<R: AsyncRead, W: AsyncWrite>(reader: R, writer: W, buf_size: usize) -> Copy<R, W> {
+    assert!(buf_size > 0, "copy buffer size must be greater than 0");
+
+    Copy {
+        reader: reader,
+        writer: writer,
+        read_done: false,
+        amt: 0,
+        pos: 0,
+        cap: 0,
+        buf: vec![0; buf_size].into_boxed_slice(),
+    }
+}
+
+/// A future which copies all data from a reader into a writer, created by
+/// `copy` or `copy_bounded`.
+pub struct Copy<R, W> {
+    reader: R,
+    writer: W,
+
+    // True once the reader has returned EOF
+    read_done: bool,
+
+    // Total number of bytes copied
+    amt: u64,
+
+    // Bytes of `buf` in the range `pos..cap` still need to be written
+    pos: usize,
+    cap: usize,
+    buf: Box<[u8]>,
+}
+
+// Size of the scratch buffer used by `copy`
+const COPY_BUF_SIZE: usize = 8 * 1_024;
+
+impl<R: AsyncRead, W: AsyncWrite> Future for Copy<R, W> {
+    type Item = u64;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<u64, io::Error> {
+        loop {
+            // The buffer has been fully written, read some more data
+            if self.pos == self.cap && !self.read_done {
+                let n = try_ready!(self.reader.try_read(&mut self.buf));
+
+                if n == 0 {
+                    self.read_done = true;
+                } else {
+                    self.pos = 0;
+                    self.cap = n;
+                }
+            }
+
+            // Write out whatever is buffered
+            while self.pos < self.cap {
+                let n = try_ready!(self.writer.try_write(&self.buf[self.pos..self.cap]));
+
+                if n == 0 {
+                    return Err(io::Error::new(io::ErrorKind::WriteZero, "zero-length write"));
+                }
+
+                self.pos += n;
+                self.amt += n as u64;
+            }
+
+            if self.read_done {
+                try_ready!(self.writer.try_flush());
+                return Ok(Async::Ready(self.amt));
+            }
+        }
+    }
+}
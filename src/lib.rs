@@ -9,4 +9,5 @@ pub mod codec;
 
 mod io;
 
-pub use io::{AsyncRead, AsyncWrite};
+pub use io::{AsyncRead, AsyncBufRead, AsyncWrite, BufReader, Shutdown};
+pub use io::{chain, Chain, copy, copy_bounded, Copy, CountRead, CountWrite, flush, Flush, read_exact, ReadExact, read_to_end, ReadToEnd, take, Take, Throttle, try_write_bufs, write_all, WriteAll};
@@ -0,0 +1,121 @@
+extern crate futures;
+extern crate tokio_more;
+extern crate bytes;
+extern crate fixture_io;
+
+use tokio_more::codec::delimited::*;
+use futures::Stream;
+use bytes::BytesMut;
+use fixture_io::FixtureIo;
+use std::io;
+use std::time::Duration;
+
+/*
+ *
+ * ===== Decoder =====
+ *
+ */
+
+#[test]
+pub fn decode_null_terminated() {
+    let io = FixtureIo::empty()
+        .then_read(&b"hello\0world\0"[..]);
+
+    let io = Builder::new().decoder(io);
+
+    let frames = collect(io).unwrap();
+    assert_eq!(frames, bytes(&[b"hello", b"world"]));
+}
+
+#[test]
+pub fn decode_multi_byte_delimiter_split_across_reads() {
+    let io = FixtureIo::empty()
+        .then_read(&b"hello--"[..])
+        .then_wait(ms(50))
+        .then_read(&b"--world----"[..])
+        ;
+
+    let io = Builder::new()
+        .set_delimiter(b"----".to_vec())
+        .decoder(io);
+
+    let frames = collect(io).unwrap();
+    assert_eq!(frames, bytes(&[b"hello", b"world"]));
+}
+
+#[test]
+pub fn decode_many_records_large_buffer() {
+    // Records of varying length, containing partial matches of the delimiter
+    let records: Vec<Vec<u8>> = (0..2_000).map(|i| {
+        (0..i % 37).map(|j| if j % 5 == 4 { b'\r' } else { b'a' + (j % 26) as u8 }).collect()
+    }).collect();
+
+    let mut data = vec![];
+
+    for record in &records {
+        data.extend_from_slice(record);
+        data.extend_from_slice(b"\r\n");
+    }
+
+    let io = Builder::new()
+        .set_delimiter(b"\r\n".to_vec())
+        .decoder(io::Cursor::new(data));
+
+    let frames = collect(io).unwrap();
+    let frames: Vec<_> = frames.iter().map(|f| f.to_vec()).collect();
+    assert_eq!(frames, records);
+}
+
+#[test]
+pub fn decode_keep_delimiter() {
+    let io = FixtureIo::empty()
+        .then_read(&b"hello\r\n"[..])
+        .then_read(&b"world\r\ntrailing"[..])
+        ;
+
+    let io = Builder::new()
+        .set_delimiter(b"\r\n".to_vec())
+        .set_keep_delimiter(true)
+        .decoder(io);
+
+    let frames = collect(io).unwrap();
+    assert_eq!(frames, bytes(&[b"hello\r\n", b"world\r\n", b"trailing"]));
+}
+
+#[test]
+pub fn decode_max_frame_size_exceeded() {
+    let io = FixtureIo::empty()
+        .then_read(&b"abcdefghi\0"[..]);
+
+    let io = Builder::new().set_max_frame_length(8).decoder(io);
+
+    assert!(collect(io).is_err());
+}
+
+/*
+ *
+ * ===== Util =====
+ *
+ */
+
+fn collect<T>(io: T) -> io::Result<Vec<T::Item>>
+    where T: Stream<Item = BytesMut, Error = io::Error>
+{
+    let mut ret = vec![];
+
+    for v in io.wait() {
+        ret.push(try!(v));
+    }
+
+    Ok(ret)
+}
+
+fn bytes(elems: &[&[u8]]) -> Vec<BytesMut> {
+    elems.iter()
+        .map(|&e| e.into())
+        .collect()
+}
+
+fn ms(n: u64) -> Duration {
+    Duration::from_millis(n)
+}
@@ -0,0 +1,251 @@
+extern crate futures;
+extern crate tokio_more;
+extern crate bytes;
+extern crate fixture_io;
+
+use tokio_more::codec::*;
+use futures::{Future, Sink, Stream};
+use bytes::{Buf, BufMut, ByteBuf};
+use fixture_io::FixtureIo;
+use std::io;
+use std::time::Duration;
+
+/*
+ *
+ * ===== FramedRead =====
+ *
+ */
+
+#[test]
+pub fn framed_read_one_byte_per_frame() {
+    let io = FixtureIo::empty()
+        .then_read(&b"ab"[..])
+        .then_wait(ms(50))
+        .then_read(&b"c"[..])
+        ;
+
+    let io = FramedRead::new(io, OneByte);
+
+    let frames = collect(io).unwrap();
+    assert_eq!(frames, b"abc".to_vec());
+}
+
+#[test]
+pub fn framed_read_bytes_remaining_on_eof() {
+    let io = FixtureIo::empty()
+        .then_read(&b"abc"[..]);
+
+    let io = FramedRead::new(io, Pairs);
+
+    let err = collect(io).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+}
+
+#[test]
+pub fn framed_read_length_delimited() {
+    let io = FixtureIo::empty()
+        .then_read(&b"\x00\x03ab"[..])
+        .then_wait(ms(50))
+        .then_read(&b"c\x00\x04defg\x00"[..])
+        .then_wait(ms(50))
+        .then_read(&b"\x02hi"[..])
+        ;
+
+    let codec: length_delimited::Codec<Vec<u8>> = length_delimited::Builder::new()
+        .set_length_field_length(2)
+        .codec();
+
+    let frames = collect(FramedRead::new(io, codec)).unwrap();
+    let frames: Vec<_> = frames.iter().map(|frame| frame.to_vec()).collect();
+
+    assert_eq!(frames, vec![b"abc".to_vec(), b"defg".to_vec(), b"hi".to_vec()]);
+}
+
+#[test]
+pub fn framed_read_length_delimited_partial_frame() {
+    let io = FixtureIo::empty()
+        .then_read(&b"\x00\x03abc\x00\x04"[..]);
+
+    let codec: length_delimited::Codec<Vec<u8>> = length_delimited::Builder::new()
+        .set_length_field_length(2)
+        .codec();
+
+    let err = collect(FramedRead::new(io, codec)).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+}
+
+/*
+ *
+ * ===== FramedWrite =====
+ *
+ */
+
+#[test]
+pub fn framed_write_space_separated() {
+    let mut io = FixtureIo::empty()
+        .then_write(&b"abc "[..])
+        .then_wait(ms(50))
+        .then_write(&b"defg "[..])
+        ;
+
+    let rx = io.receiver();
+    let io = FramedWrite::new(io, Spaced);
+
+    let io = io.send(b"abc".to_vec()).wait().unwrap();
+    let io = io.send(b"defg".to_vec()).wait().unwrap();
+
+    drop(io);
+    rx.recv().unwrap();
+}
+
+#[test]
+pub fn framed_write_length_delimited() {
+    let codec = length_delimited::Builder::new()
+        .set_length_field_length(2)
+        .codec();
+
+    let io = FramedWrite::new(vec![], codec);
+
+    let io = io.send(&b"abc"[..]).wait().unwrap();
+    let io = io.send(&b"defg"[..]).wait().unwrap();
+
+    assert_eq!(&io.get_ref()[..], &b"\x00\x03abc\x00\x04defg"[..]);
+}
+
+/*
+ *
+ * ===== DispatchDecoder =====
+ *
+ */
+
+#[test]
+pub fn dispatch_by_tag() {
+    let io = FixtureIo::empty()
+        .then_read(&b"\x00\x00\x00\x06\x01hello"[..])
+        .then_wait(ms(50))
+        .then_read(&b"\x00\x00\x00\x03\x02\xff\x00"[..])
+        ;
+
+    let registry = Registry::new()
+        .register(1, Utf8)
+        .register(2, Raw);
+
+    let io = registry.decoder(length_delimited::Decoder::default(io));
+
+    let msgs = collect(io).unwrap();
+    assert_eq!(msgs, vec![Message::Text("hello".to_string()), Message::Raw(vec![0xff, 0x00])]);
+}
+
+#[test]
+pub fn dispatch_unknown_tag() {
+    let io = FixtureIo::empty()
+        .then_read(&b"\x00\x00\x00\x02\x03a"[..]);
+
+    let io = Registry::new()
+        .register(1, Utf8)
+        .decoder(length_delimited::Decoder::default(io));
+
+    let err = collect(io).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
+
+/*
+ *
+ * ===== Util =====
+ *
+ */
+
+// Yields every byte as a frame
+struct OneByte;
+
+impl Decode for OneByte {
+    type Item = u8;
+
+    fn decode(&mut self, buf: &mut ByteBuf) -> io::Result<Option<u8>> {
+        if buf.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(buf.drain_to(1)[0]))
+    }
+}
+
+// Yields every two bytes as a frame
+struct Pairs;
+
+impl Decode for Pairs {
+    type Item = Vec<u8>;
+
+    fn decode(&mut self, buf: &mut ByteBuf) -> io::Result<Option<Vec<u8>>> {
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+
+        Ok(Some(buf.drain_to(2)[..].to_vec()))
+    }
+}
+
+// Writes every item followed by a space
+struct Spaced;
+
+impl Encode for Spaced {
+    type Item = Vec<u8>;
+
+    fn encode(&mut self, item: Vec<u8>, buf: &mut ByteBuf) -> io::Result<()> {
+        buf.reserve(item.len() + 1);
+        buf.put_slice(&item);
+        buf.put_u8(b' ');
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Message {
+    Text(String),
+    Raw(Vec<u8>),
+}
+
+// Decodes the whole buffer as a UTF-8 string
+struct Utf8;
+
+impl Decode for Utf8 {
+    type Item = Message;
+
+    fn decode(&mut self, buf: &mut ByteBuf) -> io::Result<Option<Message>> {
+        let len = buf.len();
+        let text = try!(String::from_utf8(buf.drain_to(len)[..].to_vec())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)));
+
+        Ok(Some(Message::Text(text)))
+    }
+}
+
+// Yields the whole buffer as is
+struct Raw;
+
+impl Decode for Raw {
+    type Item = Message;
+
+    fn decode(&mut self, buf: &mut ByteBuf) -> io::Result<Option<Message>> {
+        let raw = buf.bytes().to_vec();
+        buf.clear();
+
+        Ok(Some(Message::Raw(raw)))
+    }
+}
+
+fn collect<T>(io: T) -> io::Result<Vec<T::Item>>
+    where T: Stream<Error = io::Error>
+{
+    let mut ret = vec![];
+
+    for v in io.wait() {
+        ret.push(try!(v));
+    }
+
+    Ok(ret)
+}
+
+fn ms(n: u64) -> Duration {
+    Duration::from_millis(n)
+}
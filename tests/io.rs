@@ -0,0 +1,448 @@
+extern crate futures;
+extern crate tokio_more;
+extern crate bytes;
+extern crate fixture_io;
+
+use tokio_more::*;
+use tokio_more::codec::length_delimited::{Builder, Decoder};
+use futures::{future, task, Async, Future, Sink, Stream};
+use bytes::{Buf, IntoBuf};
+use fixture_io::FixtureIo;
+use std::cell::{Cell, RefCell};
+use std::{cmp, io};
+use std::rc::Rc;
+use std::time::Duration;
+
+/*
+ *
+ * ===== read_exact =====
+ *
+ */
+
+#[test]
+pub fn read_exact_multi_packet() {
+    let io = FixtureIo::empty()
+        .then_read(&b"abc"[..])
+        .then_wait(ms(50))
+        .then_read(&b"defg"[..])
+        .then_read(&b"hi"[..])
+        ;
+
+    let (_, buf) = read_exact(io, vec![0; 9]).wait().unwrap();
+    assert_eq!(&buf[..], b"abcdefghi");
+}
+
+#[test]
+pub fn read_exact_leaves_remaining_data() {
+    let io = FixtureIo::empty()
+        .then_read(&b"abcdefghi"[..]);
+
+    let (io, buf) = read_exact(io, vec![0; 4]).wait().unwrap();
+    assert_eq!(&buf[..], b"abcd");
+
+    let (_, buf) = read_exact(io, vec![0; 5]).wait().unwrap();
+    assert_eq!(&buf[..], b"efghi");
+}
+
+#[test]
+pub fn read_exact_unexpected_eof() {
+    let io = FixtureIo::empty()
+        .then_read(&b"abc"[..])
+        .then_wait(ms(50))
+        ;
+
+    let err = read_exact(io, vec![0; 9]).wait().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+}
+
+/*
+ *
+ * ===== read_to_end =====
+ *
+ */
+
+#[test]
+pub fn read_to_end_multi_packet() {
+    let data: Vec<u8> = (0..100).collect();
+
+    let io = FixtureIo::empty()
+        .then_read(&data[..10])
+        .then_wait(ms(20))
+        .then_read(&data[10..50])
+        .then_wait(ms(20))
+        .then_read(&data[50..])
+        ;
+
+    let (_, buf) = read_to_end(io, b"head".to_vec()).wait().unwrap();
+    assert_eq!(&buf[..4], b"head");
+    assert_eq!(&buf[4..], &data[..]);
+}
+
+/*
+ *
+ * ===== BufReader =====
+ *
+ */
+
+#[test]
+pub fn buf_reader_peek_then_consume() {
+    let io = FixtureIo::empty()
+        .then_read(&b"abcdef"[..])
+        .then_read(&b"ghi"[..])
+        ;
+
+    let mut rd = BufReader::with_capacity(4, io);
+
+    assert_eq!(rd.poll_fill_buf().unwrap(), Async::Ready(&b"abcd"[..]));
+
+    // Peeking again does not consume
+    assert_eq!(rd.poll_fill_buf().unwrap(), Async::Ready(&b"abcd"[..]));
+
+    rd.consume(3);
+    assert_eq!(rd.poll_fill_buf().unwrap(), Async::Ready(&b"d"[..]));
+
+    // Once the buffer is drained, it is refilled
+    rd.consume(1);
+    assert_eq!(rd.poll_fill_buf().unwrap(), Async::Ready(&b"ef"[..]));
+
+    // Reads are served from the buffer first
+    let (_, buf) = read_exact(rd, vec![0; 5]).wait().unwrap();
+    assert_eq!(&buf[..], b"efghi");
+}
+
+/*
+ *
+ * ===== take =====
+ *
+ */
+
+#[test]
+pub fn take_stops_at_limit() {
+    let io = FixtureIo::empty()
+        .then_read(&b"abc"[..])
+        .then_wait(ms(50))
+        .then_read(&b"defghi"[..])
+        ;
+
+    let (mut io, buf) = read_exact(take(io, 4), vec![0; 4]).wait().unwrap();
+    assert_eq!(&buf[..], b"abcd");
+    assert_eq!(io.limit(), 0);
+
+    // Reading past the limit looks like EOF
+    assert_eq!(io.try_read(&mut [0; 4]).unwrap(), Async::Ready(0));
+
+    let (_, buf) = read_exact(io.into_inner(), vec![0; 5]).wait().unwrap();
+    assert_eq!(&buf[..], b"efghi");
+}
+
+/*
+ *
+ * ===== chain =====
+ *
+ */
+
+#[test]
+pub fn chain_buffer_then_io() {
+    let io = FixtureIo::empty()
+        .then_wait(ms(50))
+        .then_read(&b"defg"[..])
+        .then_read(&b"hi"[..])
+        ;
+
+    let rd = chain(io::Cursor::new(b"abc".to_vec()), io);
+
+    let (rd, buf) = read_exact(rd, vec![0; 9]).wait().unwrap();
+    assert_eq!(&buf[..], b"abcdefghi");
+
+    let (cursor, _) = rd.into_inner();
+    assert_eq!(cursor.position(), 3);
+}
+
+/*
+ *
+ * ===== write_all =====
+ *
+ */
+
+#[test]
+pub fn write_all_partial_writes() {
+    let mut io = FixtureIo::empty()
+        .then_write(&b"abc"[..])
+        .then_write(&b"de"[..])
+        .then_wait(ms(50))
+        .then_write(&b"fghi"[..])
+        ;
+
+    let rx = io.receiver();
+    let (io, buf) = write_all(io, (&b"abcdefghi"[..]).into_buf()).wait().unwrap();
+    assert!(!buf.has_remaining());
+
+    drop(io);
+    rx.recv().unwrap();
+}
+
+/*
+ *
+ * ===== flush =====
+ *
+ */
+
+#[test]
+pub fn flush_after_would_block() {
+    let io = write_all(SlowFlush { attempts: 0 }, (&b"abc"[..]).into_buf())
+        .and_then(|(io, _)| flush(io));
+
+    let io = io.wait().unwrap();
+    assert_eq!(io.attempts, 2);
+}
+
+/*
+ *
+ * ===== try_write_bufs =====
+ *
+ */
+
+#[test]
+pub fn try_write_bufs_coalesces_short_buffers() {
+    let mut io = vec![];
+
+    let n = try_write_bufs(&mut io, &[b"", b"abc", b"def"]).unwrap();
+    assert_eq!(n, Async::Ready(6));
+    assert_eq!(&io[..], b"abcdef");
+
+    let n = try_write_bufs(&mut io, &[b"", b""]).unwrap();
+    assert_eq!(n, Async::Ready(0));
+}
+
+#[test]
+pub fn try_write_bufs_writes_long_buffer_alone() {
+    let long = vec![b'a'; 1_024];
+    let mut io = vec![];
+
+    let n = try_write_bufs(&mut io, &[b"abc", &long[..]]).unwrap();
+    assert_eq!(n, Async::Ready(256));
+    assert_eq!(&io[..3], b"abc");
+
+    io.clear();
+
+    let n = try_write_bufs(&mut io, &[&long[..], b"abc"]).unwrap();
+    assert_eq!(n, Async::Ready(1_024));
+    assert_eq!(io, long);
+}
+
+/*
+ *
+ * ===== copy =====
+ *
+ */
+
+#[test]
+pub fn copy_several_kilobytes() {
+    let data: Vec<u8> = (0..4_096).map(|i| i as u8).collect();
+
+    let rd = FixtureIo::empty()
+        .then_read(&data[..1_024])
+        .then_wait(ms(20))
+        .then_read(&data[1_024..2_500])
+        .then_read(&data[2_500..3_000])
+        .then_wait(ms(20))
+        .then_read(&data[3_000..])
+        ;
+
+    let mut wr = FixtureIo::empty()
+        .then_write(&data[..]);
+
+    let rx = wr.receiver();
+    let amt = copy(rd, wr).wait().unwrap();
+
+    assert_eq!(amt, 4_096);
+    rx.recv().unwrap();
+}
+
+#[test]
+pub fn copy_bounded_slow_writer() {
+    let data: Vec<u8> = (0..1_000).map(|i| i as u8).collect();
+
+    let max_read = Rc::new(Cell::new(0));
+    let written = Rc::new(RefCell::new(vec![]));
+
+    let rd = MaxRead {
+        inner: io::Cursor::new(data.clone()),
+        max: max_read.clone(),
+    };
+
+    let wr = Trickle {
+        data: written.clone(),
+        would_block: false,
+    };
+
+    let amt = copy_bounded(rd, wr, 64).wait().unwrap();
+
+    assert_eq!(amt, 1_000);
+    assert_eq!(max_read.get(), 64);
+    assert_eq!(*written.borrow(), data);
+}
+
+/*
+ *
+ * ===== CountRead / CountWrite =====
+ *
+ */
+
+#[test]
+pub fn count_framed_wire_bytes() {
+    let builder = Builder::new().set_length_field_length(2);
+
+    let io = builder.clone().encoder(CountWrite::new(vec![]));
+    let io = io.send(&b"abc"[..]).wait().unwrap();
+    let io = io.send(&b"hello world"[..]).wait().unwrap();
+
+    // Two 2 byte heads along with the payloads
+    assert_eq!(io.get_ref().bytes_written(), 18);
+
+    let data = io.into_inner().into_inner();
+    let mut io = builder.decoder(CountRead::new(io::Cursor::new(data)));
+
+    let frames = io.by_ref().collect().wait().unwrap();
+    assert_eq!(frames.len(), 2);
+    assert_eq!(io.get_ref().bytes_read(), 18);
+}
+
+/*
+ *
+ * ===== Throttle =====
+ *
+ */
+
+#[test]
+pub fn throttle_splits_large_packet() {
+    let io = FixtureIo::empty()
+        .then_read(&b"\x00\x00\x00\x14abcdefghijklmnopqrst"[..]);
+
+    let reads = Rc::new(Cell::new(0));
+    let io = CountReads { inner: Throttle::new(io, 6), reads: reads.clone() };
+
+    let mut io = Decoder::default(io);
+
+    in_task(|| {
+        match io.poll().unwrap() {
+            Async::Ready(Some(frame)) => assert_eq!(&frame[..], b"abcdefghijklmnopqrst"),
+            _ => panic!("frame not decoded"),
+        }
+    });
+
+    // The head and payload are read 6 bytes at a time
+    assert_eq!(io.last_poll_read_bytes(), 24);
+    assert_eq!(reads.get(), 4);
+}
+
+#[test]
+pub fn throttle_outside_task() {
+    let mut io = Throttle::new(&b"abcdefghij"[..], 4);
+    let mut buf = [0; 16];
+
+    assert_eq!(io::Read::read(&mut io, &mut buf).unwrap(), 4);
+    assert_eq!(io::Read::read(&mut io, &mut buf).unwrap(), 4);
+
+    let mut rest = vec![];
+    io::copy(&mut io, &mut rest).unwrap();
+    assert_eq!(rest, b"ij");
+}
+
+/*
+ *
+ * ===== Util =====
+ *
+ */
+
+// A reader counting the reads made on it
+struct CountReads<R> {
+    inner: R,
+    reads: Rc<Cell<usize>>,
+}
+
+impl<R: io::Read> io::Read for CountReads<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = try!(self.inner.read(buf));
+
+        if n > 0 {
+            self.reads.set(self.reads.get() + 1);
+        }
+
+        Ok(n)
+    }
+}
+
+// A writer whose first flush attempt would block
+struct SlowFlush {
+    attempts: usize,
+}
+
+impl io::Write for SlowFlush {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.attempts += 1;
+
+        if self.attempts == 1 {
+            task::park().unpark();
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "would block"));
+        }
+
+        Ok(())
+    }
+}
+
+// A reader recording the largest buffer it was asked to fill
+struct MaxRead {
+    inner: io::Cursor<Vec<u8>>,
+    max: Rc<Cell<usize>>,
+}
+
+impl io::Read for MaxRead {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.len() > self.max.get() {
+            self.max.set(buf.len());
+        }
+
+        self.inner.read(buf)
+    }
+}
+
+// A writer accepting at most 5 bytes per write, and blocking every other
+// write
+struct Trickle {
+    data: Rc<RefCell<Vec<u8>>>,
+    would_block: bool,
+}
+
+impl io::Write for Trickle {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.would_block = !self.would_block;
+
+        if self.would_block {
+            task::park().unpark();
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "would block"));
+        }
+
+        let n = cmp::min(buf.len(), 5);
+        self.data.borrow_mut().extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn in_task<F, R>(f: F) -> R
+    where F: FnOnce() -> R,
+{
+    future::lazy(|| Ok::<R, ()>(f())).wait().unwrap()
+}
+
+fn ms(n: u64) -> Duration {
+    Duration::from_millis(n)
+}
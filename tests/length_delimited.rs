@@ -2,13 +2,18 @@ extern crate futures;
 extern crate tokio_more;
 extern crate bytes;
 extern crate fixture_io;
+extern crate tokio_core;
 
 use tokio_more::codec::length_delimited::*;
-use futures::{Stream, Sink, Future};
+use futures::{future, stream, Async, AsyncSink, Stream, Sink, Future};
 use bytes::BytesMut;
 use fixture_io::FixtureIo;
-use std::io;
-use std::time::Duration;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::Core;
 
 /*
  *
@@ -48,6 +53,160 @@ pub fn decode_single_frame_one_packet_le() {
     assert_eq!(chunks, bytes(&[b"abcdefghi"]));
 }
 
+#[test]
+pub fn decode_wide_length_field() {
+    let io = FixtureIo::empty()
+        .then_read(&b"\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x03abc"[..])
+        ;
+
+    let io = Builder::new().set_length_field_length(12).decoder(io);
+
+    let chunks = collect(io).unwrap();
+    assert_eq!(chunks, bytes(&[b"abc"]));
+
+    let io = FixtureIo::empty()
+        .then_read(&b"\x03\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00abc"[..])
+        ;
+
+    let io = Builder::new()
+        .set_length_field_length(12)
+        .set_byte_order(ByteOrder::LittleEndian)
+        .decoder(io);
+
+    let chunks = collect(io).unwrap();
+    assert_eq!(chunks, bytes(&[b"abc"]));
+}
+
+#[test]
+pub fn decode_wide_length_field_too_large() {
+    let io = FixtureIo::empty()
+        .then_read(&b"\x00\x00\x00\x01\x00\x00\x00\x00\x00\x00\x00\x03abc"[..])
+        ;
+
+    let io = Builder::new().set_length_field_length(12).decoder(io);
+
+    let err = collect(io).unwrap_err();
+
+    match Error::from_io(&err) {
+        Some(&Error::FrameTooLarge { .. }) => {}
+        e => panic!("unexpected error; {:?}", e),
+    }
+}
+
+#[test]
+#[cfg(target_pointer_width = "32")]
+pub fn decode_length_exceeds_usize() {
+    let io = FixtureIo::empty()
+        .then_read(&b"\x00\x00\x00\x01\x00\x00\x00\x00abc"[..])
+        ;
+
+    let io = Builder::new()
+        .set_length_field_length(8)
+        .set_max_frame_length(::std::usize::MAX)
+        .decoder(io);
+
+    let err = collect(io).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+pub fn decode_initial_buffer_capacity() {
+    let mut data: Vec<u8> = vec![];
+    data.extend_from_slice(b"\x00\x00\x00\x40");
+    data.extend_from_slice(&[b'a'; 64]);
+
+    let io = Builder::new()
+        .set_initial_buffer_capacity(68)
+        .decoder(io::Cursor::new(data));
+
+    let chunks = collect(io).unwrap();
+    assert_eq!(chunks, bytes(&[&[b'a'; 64]]));
+}
+
+#[test]
+pub fn decode_with_prefilled_buffer() {
+    let mut buf = BytesMut::with_capacity(64);
+    buf.extend_from_slice(b"\x00\x00\x00\x03abc\x00\x00");
+
+    let io = FixtureIo::empty()
+        .then_read(&b"\x00\x02de"[..]);
+
+    let mut io = Builder::new().decoder_with_buffer(io, buf);
+
+    in_task(|| {
+        assert_eq!(io.poll().unwrap(), Async::Ready(Some(BytesMut::from(&b"abc"[..]))));
+        assert_eq!(io.last_poll_read_bytes(), 0);
+    });
+
+    let chunks = collect(io).unwrap();
+    assert_eq!(chunks, bytes(&[b"de"]));
+}
+
+#[test]
+pub fn decode_registered_formats() {
+    let io = FixtureIo::empty()
+        .then_read(&b"\x01\x00\x03abc"[..])
+        .then_read(&b"\x02\x00\x00\x00\x04defg"[..])
+        .then_read(&b"\x03\x00"[..])
+        ;
+
+    let io = Builder::new()
+        .register_format(1, FormatSpec::new().set_length_field_length(2))
+        .register_format(2, FormatSpec::new().set_length_field_length(4))
+        .decoder(io);
+
+    let mut frames = io.wait();
+
+    assert_eq!(frames.next().unwrap().unwrap(), BytesMut::from(&b"abc"[..]));
+    assert_eq!(frames.next().unwrap().unwrap(), BytesMut::from(&b"defg"[..]));
+
+    let err = frames.next().unwrap().unwrap_err();
+
+    match Error::from_io(&err) {
+        Some(&Error::UnknownFormat(3)) => {}
+        e => panic!("unexpected error; {:?}", e),
+    }
+}
+
+#[test]
+pub fn decode_initial_skip_until() {
+    let io = FixtureIo::empty()
+        .then_read(&b"SSH-2.0-Ser"[..])
+        .then_wait(ms(50))
+        .then_read(&b"ver_1.0\r\n\x00\x00\x00\x03abc"[..])
+        .then_read(&b"\x00\x00\x00\x02\r\n"[..])
+        ;
+
+    let io = Builder::new()
+        .set_initial_skip_until(b"\r\n".to_vec())
+        .decoder(io);
+
+    // The marker is only skipped before the first frame
+    let chunks = collect(io).unwrap();
+    assert_eq!(chunks, bytes(&[b"abc", b"\r\n"]));
+}
+
+#[test]
+pub fn decode_initial_skip_until_split_marker() {
+    // The marker arrives a byte at a time, with a partial match before it
+    let io = FixtureIo::empty()
+        .then_read(&b"banner\r"[..])
+        .then_wait(ms(20))
+        .then_read(&b"\r"[..])
+        .then_wait(ms(20))
+        .then_read(&b"\n"[..])
+        .then_wait(ms(20))
+        .then_read(&b"\n\x00\x00\x00\x03abc"[..])
+        ;
+
+    let io = Builder::new()
+        .set_initial_skip_until(b"\r\n\n".to_vec())
+        .decoder(io);
+
+    let chunks = collect(io).unwrap();
+    assert_eq!(chunks, bytes(&[b"abc"]));
+}
+
 #[test]
 pub fn decode_single_multi_frame_one_packet() {
     let mut data: Vec<u8> = vec![];
@@ -64,6 +223,108 @@ pub fn decode_single_multi_frame_one_packet() {
     assert_eq!(chunks, bytes(&[b"abcdefghi", b"123", b"hello world"]));
 }
 
+#[test]
+pub fn decode_all_in_memory() {
+    let mut data: Vec<u8> = vec![];
+    data.extend_from_slice(b"\x00\x00\x00\x09abcdefghi");
+    data.extend_from_slice(b"\x00\x00\x00\x03123");
+    data.extend_from_slice(b"\x00\x00\x00\x0bhello world");
+
+    let builder = Builder::new();
+
+    let chunks = builder.decode_all(io::Cursor::new(&data[..])).unwrap();
+    assert_eq!(chunks, bytes(&[b"abcdefghi", b"123", b"hello world"]));
+
+    // A trailing partial frame
+    let err = builder.decode_all(io::Cursor::new(&data[..data.len() - 1])).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+}
+
+#[test]
+pub fn decode_all_does_not_wait() {
+    let data = b"\x00\x00\x00\x09abcdefghi\x00\x00\x00\x03123";
+
+    let builder = Builder::new().set_read_chunk_limit(4);
+
+    let chunks = builder.decode_all(io::Cursor::new(&data[..])).unwrap();
+    assert_eq!(chunks, bytes(&[b"abcdefghi", b"123"]));
+
+    // Another decoder holds the memory budget
+    let budget = MemoryBudget::new(8);
+
+    let other = FixtureIo::empty()
+        .then_read(&b"\x00\x00\x00\x06abc"[..])
+        .then_wait(ms(50))
+        ;
+
+    let mut other = Builder::new().set_memory_budget(budget.clone()).decoder(other);
+
+    in_task(|| {
+        assert!(other.poll().unwrap().is_not_ready());
+    });
+
+    let builder = Builder::new().set_memory_budget(budget);
+
+    let err = builder.decode_all(io::Cursor::new(&data[..])).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+}
+
+#[test]
+pub fn decode_many_small_frames() {
+    let frames: Vec<Vec<u8>> = (0..10_000)
+        .map(|i| i.to_string().into_bytes())
+        .collect();
+
+    let mut data: Vec<u8> = vec![];
+
+    for frame in &frames {
+        data.extend_from_slice(&[0, 0, 0, frame.len() as u8]);
+        data.extend_from_slice(frame);
+    }
+
+    // Deliver the frames in packets that do not line up with frame boundaries
+    let mut io = FixtureIo::empty();
+
+    for packet in data.chunks(1_000) {
+        io = io.then_read(packet);
+    }
+
+    let io = Decoder::default(io);
+
+    let chunks = collect(io).unwrap();
+    assert_eq!(chunks.len(), 10_000);
+
+    for (chunk, frame) in chunks.iter().zip(frames.iter()) {
+        assert_eq!(&chunk[..], &frame[..]);
+    }
+}
+
+#[test]
+pub fn decode_one_frame_per_poll() {
+    let mut data: Vec<u8> = vec![];
+    data.extend_from_slice(b"\x00\x00\x00\x01a");
+    data.extend_from_slice(b"\x00\x00\x00\x01b");
+    data.extend_from_slice(b"\x00\x00\x00\x01c");
+
+    let io = FixtureIo::empty()
+        .then_read(data)
+        .then_wait(ms(50))
+        ;
+
+    let mut io = Decoder::default(io);
+
+    in_task(|| {
+        for frame in &[b"a", b"b", b"c"] {
+            match io.poll().unwrap() {
+                Async::Ready(Some(chunk)) => assert_eq!(&chunk[..], &frame[..]),
+                v => panic!("unexpected value; {:?}", v),
+            }
+        }
+
+        assert!(io.poll().unwrap().is_not_ready());
+    });
+}
+
 #[test]
 pub fn single_frame_multi_packet() {
     let io = FixtureIo::empty()
@@ -140,7 +401,8 @@ pub fn incomplete_head() {
 
     let io = Decoder::default(io);
 
-    assert!(collect(io).is_err());
+    let err = collect(io).unwrap_err();
+    assert_unexpected_eof(&err);
 }
 
 #[test]
@@ -154,7 +416,28 @@ pub fn incomplete_head_multi() {
 
     let io = Decoder::default(io);
 
-    assert!(collect(io).is_err());
+    let err = collect(io).unwrap_err();
+    assert_unexpected_eof(&err);
+}
+
+#[test]
+pub fn decode_length_field_offset_one_byte_reads() {
+    let data = b"\xca\xfe\x00\x00\x00\x03abc";
+
+    // The head arrives one byte at a time, so the buffered bytes pass the
+    // length field length before the whole head is buffered
+    let mut io = FixtureIo::empty();
+
+    for &b in data.iter() {
+        io = io.then_read(vec![b]);
+    }
+
+    let io = Builder::new()
+        .set_length_field_offset(2)
+        .decoder(io);
+
+    let chunks = collect(io).unwrap();
+    assert_eq!(chunks, bytes(&[b"abc"]));
 }
 
 #[test]
@@ -168,117 +451,2269 @@ pub fn incomplete_payload() {
 
     let io = Decoder::default(io);
 
-    assert!(collect(io).is_err());
+    let err = collect(io).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    assert_truncated_payload(&err, b"abcd", 9);
 }
 
+#[test]
+pub fn incomplete_head_allow_trailing() {
+    let io = FixtureIo::empty()
+        .then_read(&b"\x00\x00\x00\x03123\x00\x00"[..])
+        ;
+
+    let io = Builder::new().set_allow_trailing(true).decoder(io);
+
+    let chunks = collect(io).unwrap();
+    assert_eq!(chunks, bytes(&[b"123"]));
+}
 
 #[test]
-pub fn decode_max_frame_size_exceeded() {
+pub fn incomplete_payload_allow_trailing() {
     let io = FixtureIo::empty()
-        .then_read(&b"\x00\x00\x00\x09abcdefghi"[..]);
+        .then_read(&b"\x00\x00\x00\x03123\x00\x00\x00\x09ab"[..])
+        .then_wait(ms(50))
+        .then_read(&b"cd"[..])
+        .then_wait(ms(50))
+        ;
 
-    let io = Builder::new().set_max_frame_length(8).decoder(io);
+    let io = Builder::new().set_allow_trailing(true).decoder(io);
 
-    assert!(collect(io).is_err());
+    let chunks = collect(io).unwrap();
+    assert_eq!(chunks, bytes(&[b"123"]));
 }
 
-/*
- *
- * ===== Encoder =====
- *
- */
+#[test]
+pub fn incomplete_payload_after_frame() {
+    let io = FixtureIo::empty()
+        .then_read(&b"\x00\x00\x00\x03123\x00\x00\x00\x09ab"[..])
+        ;
+
+    let err = collect(Decoder::default(io)).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    assert_truncated_payload(&err, b"ab", 9);
+}
 
 #[test]
-pub fn encode_nothing_yields_nothing() {
-    let mut io = FixtureIo::empty();
-    let rx = io.receiver();
-    let io: Encoder<FixtureIo, &'static [u8]> = Encoder::default(io);
+pub fn decode_max_frame_size_exceeded() {
+    let io = FixtureIo::empty()
+        .then_read(&b"\x00\x00\x00\x09abcdefghi"[..]);
 
-    drop(io);
-    rx.recv().unwrap();
+    let io = Builder::new().set_max_frame_length(8).decoder(io);
+
+    let err = collect(io).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+    match Error::from_io(&err) {
+        Some(&Error::FrameTooLarge { len: 9, max: 8 }) => {}
+        e => panic!("unexpected error; {:?}", e),
+    }
 }
 
 #[test]
-pub fn encode_single_frame_one_packet() {
-    let mut io = FixtureIo::empty()
-        .then_write(&b"\x00\x00\x00\x09abcdefghi"[..]);
+pub fn inter_frame_gap_round_trip() {
+    let builder = Builder::new()
+        .set_length_field_length(2)
+        .set_inter_frame_gap(b"\r\n".to_vec());
 
-    let rx = io.receiver();
-    let io = Encoder::default(io);
-    let io = io.send(&b"abcdefghi"[..]).wait().unwrap();
+    let io = builder.clone().encoder(vec![]);
+    let io = io.send(&b"abc"[..]).wait().unwrap();
+    let io = io.send(&b"de"[..]).wait().unwrap();
+    let io = io.send(&b"fghi"[..]).wait().unwrap();
 
-    drop(io);
-    rx.recv().unwrap();
+    let data = io.into_inner();
+    assert_eq!(&data[..], &b"\x00\x03abc\r\n\x00\x02de\r\n\x00\x04fghi\r\n"[..]);
+
+    let io = FixtureIo::empty()
+        .then_read(&data[..6])
+        .then_wait(ms(20))
+        .then_read(&data[6..])
+        ;
+
+    let chunks = collect(builder.clone().decoder(io)).unwrap();
+    assert_eq!(chunks, bytes(&[b"abc", b"de", b"fghi"]));
+
+    // The gap after the last frame may be left out
+    let io = io::Cursor::new(&data[..data.len() - 2]);
+    let chunks = collect(builder.decoder(io)).unwrap();
+    assert_eq!(chunks, bytes(&[b"abc", b"de", b"fghi"]));
 }
 
 #[test]
-pub fn encode_single_frame_one_packet_le() {
-    let mut io = FixtureIo::empty()
-        .then_write(&b"\x09\x00\x00\x00abcdefghi"[..]);
+pub fn decode_empty_frames() {
+    let data = &b"\x00\x00\x00\x00\x00\x00\x00\x03abc"[..];
 
-    let rx = io.receiver();
-    let builder = Builder::new().set_byte_order(ByteOrder::LittleEndian);
-    let io = builder.encoder(io);
-    let io = io.send(&b"abcdefghi"[..]).wait().unwrap();
+    let chunks = collect(Decoder::default(io::Cursor::new(data))).unwrap();
+    assert_eq!(chunks, bytes(&[b"", b"abc"]));
 
-    drop(io);
-    rx.recv().unwrap();
+    let io = Builder::new()
+        .set_allow_empty_frames(false)
+        .decoder(io::Cursor::new(data));
+
+    let err = collect(io).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
 }
 
 #[test]
-pub fn encode_single_multi_frame_one_packet() {
-    let mut data: Vec<u8> = vec![];
-    data.extend_from_slice(b"\x00\x00\x00\x09abcdefghi");
-    data.extend_from_slice(b"\x00\x00\x00\x03123");
-    data.extend_from_slice(b"\x00\x00\x00\x0bhello world");
+pub fn decode_lower_max_frame_size() {
+    let io = FixtureIo::empty()
+        .then_read(&b"\x00\x00\x00\x09abcdefghi\x00\x00\x00\x09jklmnopqr"[..]);
 
-    let mut io = FixtureIo::empty()
-        .then_write(data);
+    let mut io = Builder::new()
+        .set_initial_buffer_capacity(64)
+        .decoder(io);
 
-    let rx = io.receiver();
-    let io = Encoder::default(io);
+    in_task(|| {
+        assert_eq!(io.poll().unwrap(), Async::Ready(Some(BytesMut::from(&b"abcdefghi"[..]))));
+    });
 
-    let io = io.send(&b"abcdefghi"[..]).wait().unwrap();
-    let io = io.send(&b"123"[..]).wait().unwrap();
-    let io = io.send(&b"hello world"[..]).wait().unwrap();
+    // The next head is already buffered
+    io.set_max_frame_length(8);
 
-    drop(io);
-    rx.recv().unwrap();
+    let err = collect(io).unwrap_err();
+
+    match Error::from_io(&err) {
+        Some(&Error::FrameTooLarge { len: 9, max: 8 }) => {}
+        e => panic!("unexpected error; {:?}", e),
+    }
 }
 
 #[test]
-pub fn encode_max_frame_size_exceeded() {
-    let mut io = FixtureIo::empty()
-        .then_write(&b"\x00\x00\x00\x09abcdefghi"[..]);
+pub fn decode_max_frame_size_after_adjustment() {
+    let io = FixtureIo::empty()
+        .then_read(&b"\x00\x00\x00\x08abcdefghij"[..]);
 
-    let rx = io.receiver();
-    let io = Builder::new().set_max_frame_length(8).encoder(io);
-    let io = io.send(&b"abcdefghi"[..]).wait();
-    assert!(io.is_err());
+    let io = Builder::new()
+        .set_max_frame_length(8)
+        .set_length_adjustment(2)
+        .decoder(io);
+
+    let err = collect(io).unwrap_err();
+
+    match Error::from_io(&err) {
+        Some(&Error::FrameTooLarge { len: 10, max: 8 }) => {}
+        e => panic!("unexpected error; {:?}", e),
+    }
 }
 
-/*
- *
- * ===== Util =====
- *
- */
+#[test]
+pub fn decode_length_adjustment_overflow() {
+    let io = FixtureIo::empty()
+        .then_read(&b"\x00\x00\x00\x02ab"[..]);
 
-fn collect<T>(io: T) -> io::Result<Vec<T::Item>>
-    where T: Stream<Item = BytesMut, Error = io::Error>
-{
-    let mut ret = vec![];
+    let io = Builder::new().set_length_adjustment(-3).decoder(io);
 
-    for v in io.wait() {
-        ret.push(try!(v));
+    let err = collect(io).unwrap_err();
+
+    match Error::from_io(&err) {
+        Some(&Error::LengthOverflow) => {}
+        e => panic!("unexpected error; {:?}", e),
     }
+}
 
-    Ok(ret)
+#[test]
+pub fn decode_on_error_skip_then_abort() {
+    let io = FixtureIo::empty()
+        .then_read(&b"\xFF\x00\x00\x00\x02ab"[..])
+        .then_read(&b"\x00\x00\x00\x09abcdefghi"[..])
+        ;
+
+    let mut errors = 0;
+
+    let mut io = Builder::new()
+        .set_max_frame_length(8)
+        .on_error(move |err| {
+            match Error::from_io(err) {
+                Some(&Error::FrameTooLarge { .. }) => {}
+                e => panic!("unexpected error; {:?}", e),
+            }
+
+            errors += 1;
+
+            if errors == 1 {
+                ErrorAction::Skip
+            } else {
+                ErrorAction::Abort
+            }
+        })
+        .decoder(io);
+
+    in_task(|| {
+        // The first head is skipped a byte at a time until the stream resyncs
+        match io.poll().unwrap() {
+            Async::Ready(Some(frame)) => assert_eq!(frame, BytesMut::from(&b"ab"[..])),
+            v => panic!("unexpected value; {:?}", v),
+        }
+
+        // The second error aborts the stream
+        assert!(io.poll().is_err());
+        assert_eq!(io.poll().unwrap(), Async::Ready(None));
+    });
 }
 
-fn bytes(elems: &[&[u8]]) -> Vec<BytesMut> {
-    elems.iter()
-        .map(|&e| e.into())
-        .collect()
+#[test]
+pub fn decode_retry_keeps_delta_length() {
+    let io = FixtureIo::empty()
+        .then_read(&b"\x00\x00\x00\x03abc\x00\x00\x00\x06defghijkl"[..]);
+
+    let mut io = Builder::new()
+        .set_delta_length(true)
+        .set_max_frame_length(8)
+        .on_error(|_| ErrorAction::Retry)
+        .decoder(io);
+
+    in_task(|| {
+        match io.poll().unwrap() {
+            Async::Ready(Some(frame)) => assert_eq!(frame, BytesMut::from(&b"abc"[..])),
+            v => panic!("unexpected value; {:?}", v),
+        }
+
+        // The second frame is 3 + 6 bytes long
+        let err = io.poll().unwrap_err();
+
+        match Error::from_io(&err) {
+            Some(&Error::FrameTooLarge { len: 9, max: 8 }) => {}
+            e => panic!("unexpected error; {:?}", e),
+        }
+    });
+
+    // The head is decoded again, relative to the same previous length
+    io.set_max_frame_length(9);
+
+    let chunks = collect(io).unwrap();
+    assert_eq!(chunks, bytes(&[b"defghijkl"]));
+}
+
+#[test]
+pub fn decode_reset_after_error() {
+    let io = FixtureIo::empty()
+        .then_read(&b"\x00\x00\x00\x09abcdefghi"[..])
+        .then_wait(ms(50))
+        .then_read(&b"\x00\x00\x00\x03abc"[..])
+        ;
+
+    // Room to buffer the whole oversized frame
+    let mut io = Builder::new()
+        .set_max_frame_length(8)
+        .set_initial_buffer_capacity(64)
+        .decoder(io);
+
+    let err = in_task(|| io.poll().unwrap_err());
+
+    match Error::from_io(&err) {
+        Some(&Error::FrameTooLarge { len: 9, max: 8 }) => {}
+        e => panic!("unexpected error; {:?}", e),
+    }
+
+    io.reset();
+    assert_eq!(io.buffered_len(), 0);
+
+    let chunks = collect(io).unwrap();
+    assert_eq!(chunks, bytes(&[b"abc"]));
+}
+
+#[test]
+pub fn decode_cancel_large_frame() {
+    let budget = MemoryBudget::new(1_024 * 1_024);
+
+    let io = FixtureIo::empty()
+        .then_read(&b"\x00\x10\x00\x00abc"[..])
+        .then_wait(ms(50))
+        ;
+
+    let mut io = Builder::new().set_memory_budget(budget.clone()).decoder(io);
+
+    // Nothing to cancel before a frame head is read
+    assert!(io.cancel().is_none());
+
+    in_task(|| {
+        assert!(io.poll().unwrap().is_not_ready());
+    });
+
+    assert!(io.buffer_capacity() >= 1_024 * 1_024);
+    assert_eq!(budget.used(), 1_024 * 1_024);
+
+    let partial = io.cancel().unwrap();
+    assert_eq!(&partial[..], b"abc");
+
+    assert!(io.buffer_capacity() < 1_024);
+    assert_eq!(io.buffered_len(), 0);
+    assert_eq!(budget.used(), 0);
+    assert_eq!(io.state(), DecoderState::AwaitingHeader);
+}
+
+#[test]
+pub fn decode_resync_to() {
+    let io = FixtureIo::empty()
+        .then_read(&b"\xff\x00\x00\x00\x03abc"[..]);
+
+    let mut io = Builder::new().set_max_frame_length(8).decoder(io);
+
+    assert!(in_task(|| io.poll()).is_err());
+
+    // Skip the garbage byte
+    io.resync_to(1);
+
+    let chunks = collect(io).unwrap();
+    assert_eq!(chunks, bytes(&[b"abc"]));
+}
+
+#[test]
+pub fn decode_transactional_rollback() {
+    let io = FixtureIo::empty()
+        .then_read(&b"\x00\x00\x00\x03abc"[..])
+        .then_wait(ms(50))
+        .then_read(&b"\x00\x00\x00\x02de"[..])
+        ;
+
+    let mut io = Decoder::default(io);
+
+    in_task(|| {
+        let (frame, txn) = match io.poll_transactional().unwrap() {
+            Async::Ready(Some(v)) => v,
+            _ => panic!("expected a frame"),
+        };
+
+        assert_eq!(frame, BytesMut::from(&b"abc"[..]));
+        txn.rollback(frame);
+
+        // The same frame is yielded again
+        let (frame, txn) = match io.poll_transactional().unwrap() {
+            Async::Ready(Some(v)) => v,
+            _ => panic!("expected a frame"),
+        };
+
+        assert_eq!(frame, BytesMut::from(&b"abc"[..]));
+        txn.commit();
+    });
+
+    let chunks = collect(io).unwrap();
+    assert_eq!(chunks, bytes(&[b"de"]));
+}
+
+#[test]
+pub fn decode_state_transitions() {
+    let io = FixtureIo::empty()
+        .then_read(&b"\x00\x00\x00\x09abc"[..])
+        .then_wait(ms(50))
+        .then_read(&b"defghi"[..])
+        ;
+
+    let mut io = Decoder::default(io);
+    assert_eq!(io.state(), DecoderState::AwaitingHeader);
+
+    in_task(|| {
+        assert!(io.poll().unwrap().is_not_ready());
+    });
+
+    assert_eq!(io.state(), DecoderState::AwaitingPayload { len: 9, received: 3 });
+
+    let chunks = collect(io).unwrap();
+    assert_eq!(chunks, bytes(&[b"abcdefghi"]));
+}
+
+#[test]
+pub fn decode_on_io() {
+    fn read(bytes: usize) -> IoEvent {
+        IoEvent { kind: IoKind::Read, bytes: bytes, would_block: false }
+    }
+
+    let would_block = IoEvent { kind: IoKind::Read, bytes: 0, would_block: true };
+
+    // One packet
+    let io = FixtureIo::empty()
+        .then_read(&b"\x00\x00\x00\x09abcdefghi"[..])
+        ;
+
+    let events = Arc::new(Mutex::new(vec![]));
+    let events2 = events.clone();
+
+    let io = Builder::new()
+        .set_initial_buffer_capacity(64)
+        .on_io(move |event| events2.lock().unwrap().push(event))
+        .decoder(io);
+
+    let chunks = collect(io).unwrap();
+    assert_eq!(chunks, bytes(&[b"abcdefghi"]));
+
+    assert_eq!(*events.lock().unwrap(), vec![read(13), read(0)]);
+
+    // Multiple packets
+    let io = FixtureIo::empty()
+        .then_read(&b"\x00\x00"[..])
+        .then_read(&b"\x00\x09abc"[..])
+        .then_wait(ms(50))
+        .then_read(&b"defghi"[..])
+        ;
+
+    let events = Arc::new(Mutex::new(vec![]));
+    let events2 = events.clone();
+
+    let io = Builder::new()
+        .set_initial_buffer_capacity(64)
+        .on_io(move |event| events2.lock().unwrap().push(event))
+        .decoder(io);
+
+    let chunks = collect(io).unwrap();
+    assert_eq!(chunks, bytes(&[b"abcdefghi"]));
+
+    assert_eq!(*events.lock().unwrap(),
+               vec![read(2), read(5), would_block, read(6), read(0)]);
+}
+
+#[test]
+pub fn decode_on_progress() {
+    fn progress(head: usize, payload: usize, ready: bool) -> Progress {
+        Progress { head: head, payload: payload, ready: ready }
+    }
+
+    let io = FixtureIo::empty()
+        .then_read(&b"\x00\x00"[..])
+        .then_read(&b"\x00\x09abc"[..])
+        .then_wait(ms(50))
+        .then_read(&b"defghi"[..])
+        ;
+
+    let polls = Arc::new(Mutex::new(vec![]));
+    let polls2 = polls.clone();
+
+    let io = Builder::new()
+        .set_initial_buffer_capacity(64)
+        .on_progress(move |p| polls2.lock().unwrap().push(p))
+        .decoder(io);
+
+    let chunks = collect(io).unwrap();
+    assert_eq!(chunks, bytes(&[b"abcdefghi"]));
+
+    // The first poll buffers the head and part of the payload before
+    // blocking, the second completes the frame, the last one reaches EOF
+    assert_eq!(*polls.lock().unwrap(),
+               vec![progress(7, 0, false), progress(0, 6, true), progress(0, 0, true)]);
+}
+
+#[test]
+pub fn decode_byte_rate_limit() {
+    let mut data = b"\x00\x00\x4e\x20".to_vec();
+    data.extend((0..20_000).map(|i| i as u8));
+
+    let mut core = Core::new().unwrap();
+
+    // The bucket holds 5,000 bytes, the remaining 15,004 take 300ms
+    let io = Decoder::default(io::Cursor::new(data.clone()))
+        .byte_rate_limit(50_000, &core.handle());
+
+    let start = Instant::now();
+    let chunks = core.run(io.collect()).unwrap();
+    let elapsed = start.elapsed();
+
+    assert_eq!(chunks, bytes(&[&data[4..]]));
+    assert!(elapsed >= ms(250), "elapsed={:?}", elapsed);
+    assert!(elapsed < ms(2_000), "elapsed={:?}", elapsed);
+}
+
+#[test]
+pub fn decode_adaptive_buffer() {
+    let big = vec![b'x'; 1_000];
+
+    let mut data = b"\x00\x00\x03\xe8".to_vec();
+    data.extend_from_slice(&big);
+    data.extend_from_slice(b"\x00\x00\x00\x04abcd\x00\x00\x00\x01e");
+
+    let io = FixtureIo::empty()
+        .then_read(&data[..10])
+        .then_wait(ms(20))
+        .then_read(&data[10..1_006])
+        .then_wait(ms(20))
+        .then_read(&data[1_006..])
+        ;
+
+    let mut io = Builder::new().set_adaptive_buffer(true).decoder(io);
+    assert_eq!(io.frame_size_estimate(), Some(0));
+
+    assert_eq!(future::poll_fn(|| io.poll()).wait().unwrap().unwrap(), BytesMut::from(&big[..]));
+    assert_eq!(io.frame_size_estimate(), Some(1_004));
+
+    assert_eq!(future::poll_fn(|| io.poll()).wait().unwrap().unwrap(), BytesMut::from(&b"abcd"[..]));
+    assert_eq!(io.frame_size_estimate(), Some(879));
+
+    assert_eq!(future::poll_fn(|| io.poll()).wait().unwrap().unwrap(), BytesMut::from(&b"e"[..]));
+    assert_eq!(io.frame_size_estimate(), Some(769));
+
+    assert!(future::poll_fn(|| io.poll()).wait().unwrap().is_none());
+
+    assert_eq!(Decoder::default(io::empty()).frame_size_estimate(), None);
+}
+
+#[test]
+pub fn decode_endian_confidence() {
+    let io = FixtureIo::empty()
+        .then_read(&b"\x00\x03abc\x00\x05defgh\x00\x01i"[..]);
+
+    let mut io = Builder::new().set_length_field_length(2).decoder(io);
+    assert_eq!(io.endian_confidence(), 1.0);
+
+    while let Some(_) = future::poll_fn(|| io.poll()).wait().unwrap() {}
+    assert_eq!(io.endian_confidence(), 1.0);
+
+    // Little endian lengths of 1 and 2 read as big endian lengths of 256 and
+    // 512, which still frame the data
+    let mut data = b"\x01\x00".to_vec();
+    data.extend_from_slice(&[b'a'; 256]);
+    data.extend_from_slice(b"\x02\x00");
+    data.extend_from_slice(&[b'b'; 512]);
+    data.extend_from_slice(b"\x00\x01c");
+
+    let mut io = Builder::new().set_length_field_length(2).decoder(io::Cursor::new(data));
+
+    while let Some(_) = future::poll_fn(|| io.poll()).wait().unwrap() {}
+    assert!(io.endian_confidence() < 0.5, "confidence={}", io.endian_confidence());
+}
+
+#[test]
+pub fn decode_poll_head_len() {
+    let io = FixtureIo::empty()
+        .then_read(&b"\x00\x00\x00\x09abc"[..])
+        .then_wait(ms(20))
+        .then_read(&b"defghi\x00\x00\x00\x02jk"[..])
+        ;
+
+    let mut io = Decoder::default(io);
+
+    in_task(|| {
+        assert_eq!(io.poll_head_len().unwrap(), Async::Ready(Some(9)));
+
+        // Peeking again does not consume the frame
+        assert_eq!(io.poll_head_len().unwrap(), Async::Ready(Some(9)));
+    });
+
+    let chunks = collect(io).unwrap();
+    assert_eq!(chunks, bytes(&[b"abcdefghi", b"jk"]));
+}
+
+#[test]
+pub fn decode_try_next_frame() {
+    let io = FixtureIo::empty()
+        .then_read(&b"\x00\x00\x00\x03abc\x00\x00\x00\x02de\x00\x00\x00\x01f\x00\x00"[..]);
+
+    let mut io = Builder::new()
+        .set_initial_buffer_capacity(64)
+        .decoder(io);
+
+    // Nothing has been buffered yet
+    assert!(io.try_next_frame().unwrap().is_none());
+
+    // Buffer the packet without consuming a frame
+    in_task(|| {
+        assert_eq!(io.poll_head_len().unwrap(), Async::Ready(Some(3)));
+    });
+
+    assert_eq!(&io.try_next_frame().unwrap().unwrap()[..], b"abc");
+    assert_eq!(&io.try_next_frame().unwrap().unwrap()[..], b"de");
+    assert_eq!(&io.try_next_frame().unwrap().unwrap()[..], b"f");
+
+    // The last head is incomplete
+    assert!(io.try_next_frame().unwrap().is_none());
+    assert_eq!(io.buffered_len(), 2);
+}
+
+#[test]
+pub fn decode_buffered_len() {
+    let io = FixtureIo::empty()
+        .then_read(&b"\x00\x00\x00\x09abc"[..])
+        .then_wait(ms(50))
+        .then_read(&b"defghi"[..])
+        ;
+
+    let mut io = Decoder::default(io);
+    assert_eq!(io.buffered_len(), 0);
+    assert_eq!(io.current_frame_len(), None);
+
+    in_task(|| {
+        assert!(io.poll().unwrap().is_not_ready());
+    });
+
+    assert_eq!(io.buffered_len(), 3);
+    assert_eq!(io.current_frame_len(), Some(9));
+}
+
+#[test]
+pub fn decode_poll_into_scratch() {
+    let mut data = b"\x00\x00\x00\x03abc".to_vec();
+    data.extend_from_slice(b"\x00\x00\x00\x41");
+    data.extend_from_slice(&[b'x'; 65]);
+    data.extend_from_slice(b"\x00\x00\x00\x04defg");
+
+    let mut io = Decoder::default(io::Cursor::new(data));
+    let mut scratch = [0; 64];
+
+    in_task(|| {
+        assert_eq!(io.poll_into(&mut scratch).unwrap(), Async::Ready(Some(&b"abc"[..])));
+
+        // The frame does not fit in the scratch buffer
+        let err = io.poll_into(&mut scratch).unwrap_err();
+
+        match Error::from_io(&err) {
+            Some(&Error::FrameTooLarge { len: 65, max: 64 }) => {}
+            e => panic!("unexpected error; {:?}", e),
+        }
+
+        // Decoding resumes with the next frame
+        assert_eq!(io.poll_into(&mut scratch).unwrap(), Async::Ready(Some(&b"defg"[..])));
+        assert_eq!(io.poll_into(&mut scratch).unwrap(), Async::Ready(None));
+    });
+}
+
+#[test]
+pub fn decode_num_skip_within_head() {
+    // The length field is part of the payload and counted by it
+    let builder = Builder::new()
+        .set_length_field_length(2)
+        .set_num_skip(0);
+
+    let io = FixtureIo::empty()
+        .then_read(&b"\x00\x05abc\x00\x02"[..]);
+
+    let chunks = collect(builder.clone().decoder(io)).unwrap();
+    assert_eq!(chunks, bytes(&[b"\x00\x05abc", b"\x00\x02"]));
+
+    assert_eq!(builder.validate(), Err(ConfigError::NumSkipWithinHead { num_skip: 0, head_len: 2 }));
+}
+
+#[test]
+pub fn decode_length_field_from_end() {
+    // [magic:2][flags:1][len:2]
+    let builder = Builder::new()
+        .set_length_field_length(2)
+        .set_num_skip(5)
+        .set_length_field_from_end(0);
+
+    let io = FixtureIo::empty()
+        .then_read(&b"\xca\xfe\x01\x00\x03abc"[..])
+        .then_wait(ms(20))
+        .then_read(&b"\xca\xfe\x00\x00\x02de"[..])
+        ;
+
+    let chunks = collect(builder.decoder(io)).unwrap();
+    assert_eq!(chunks, bytes(&[b"abc", b"de"]));
+}
+
+#[test]
+pub fn validate_num_skip_smaller_than_head() {
+    assert_eq!(Builder::new().validate(), Ok(()));
+    assert_eq!(Builder::new().set_num_skip(6).validate(), Ok(()));
+
+    // Probably meant to skip the whole head
+    let builder = Builder::new()
+        .set_length_field_offset(2)
+        .set_length_field_length(2)
+        .set_num_skip(2);
+
+    assert_eq!(builder.validate(), Err(ConfigError::NumSkipWithinHead { num_skip: 2, head_len: 4 }));
+}
+
+#[test]
+pub fn try_decoder_checks_length_field_fits_head() {
+    // [flags:2][len:2]
+    let builder = Builder::new()
+        .set_length_field_offset(2)
+        .set_length_field_length(2)
+        .set_num_skip(4);
+
+    let io = FixtureIo::empty()
+        .then_read(&b"\x00\x01\x00\x03abc"[..]);
+
+    let chunks = collect(builder.clone().try_decoder(io).unwrap()).unwrap();
+    assert_eq!(chunks, bytes(&[b"abc"]));
+
+    let io = builder.try_encoder(vec![]).unwrap();
+    let io = io.send(&b"abc"[..]).wait().unwrap();
+    assert_eq!(&io.get_ref()[..], &b"\x00\x00\x00\x03abc"[..]);
+
+    // The length field runs past the 3 byte head
+    let builder = Builder::new()
+        .set_length_field_offset(2)
+        .set_length_field_length(2)
+        .set_num_skip(3);
+
+    let err = ConfigError::NumSkipWithinHead { num_skip: 3, head_len: 4 };
+
+    assert_eq!(builder.clone().try_decoder(io::empty()).err(), Some(err.clone()));
+    assert_eq!(builder.try_encoder::<_, &[u8]>(Vec::<u8>::new()).err(), Some(err));
+}
+
+#[test]
+pub fn validate_nonce_after_length_field() {
+    let builder = Builder::new()
+        .set_length_field_length(2)
+        .set_nonce_field(2, 4);
+
+    assert_eq!(builder.validate(), Ok(()));
+
+    // The nonce would overwrite the low byte of the length
+    let builder = Builder::new()
+        .set_length_field_length(2)
+        .set_nonce_field(1, 4);
+
+    assert_eq!(builder.validate(), Err(ConfigError::NonceOverlapsLength { nonce_offset: 1, length_end: 2 }));
+    assert!(builder.encoder(vec![]).send(&b"abc"[..]).wait().is_err());
+}
+
+#[test]
+pub fn builder_clone_is_independent() {
+    let base = Builder::new().set_length_field_length(2);
+    let small = base.clone().set_max_frame_length(2);
+
+    assert!(format!("{:?}", base).contains("max_frame_len: 8388608"));
+    assert!(format!("{:?}", small).contains("max_frame_len: 2"));
+
+    let data = &b"\x00\x03abc"[..];
+
+    // The original keeps the default max frame length
+    let chunks = collect(base.decoder(io::Cursor::new(data))).unwrap();
+    assert_eq!(chunks, bytes(&[b"abc"]));
+
+    assert!(collect(small.decoder(io::Cursor::new(data))).is_err());
+}
+
+#[test]
+pub fn decoder_builder_round_trip() {
+    let decoder = Builder::new()
+        .set_length_field_length(2)
+        .set_byte_order(ByteOrder::LittleEndian)
+        .decoder(io::empty());
+
+    let io = decoder.builder().encoder(vec![]);
+    let io = io.send(&b"abc"[..]).wait().unwrap();
+
+    let data = io.into_inner();
+    assert_eq!(&data[..], &b"\x03\x00abc"[..]);
+
+    let chunks = collect(decoder.builder().decoder(io::Cursor::new(data))).unwrap();
+    assert_eq!(chunks, bytes(&[b"abc"]));
+}
+
+#[test]
+pub fn decode_last_poll_read_bytes() {
+    let io = FixtureIo::empty()
+        .then_read(&b"\x00\x00\x00\x03abc\x00\x00\x00\x02de"[..])
+        .then_wait(ms(50))
+        .then_read(&b"\x00\x00\x00\x01f"[..])
+        ;
+
+    // Room for the whole packet in a single read
+    let mut io = Builder::new().set_initial_buffer_capacity(64).decoder(io);
+    assert_eq!(io.last_poll_read_bytes(), 0);
+
+    in_task(|| {
+        assert_eq!(io.poll().unwrap(), Async::Ready(Some(BytesMut::from(&b"abc"[..]))));
+        assert_eq!(io.last_poll_read_bytes(), 13);
+
+        // Served from the buffer
+        assert_eq!(io.poll().unwrap(), Async::Ready(Some(BytesMut::from(&b"de"[..]))));
+        assert_eq!(io.last_poll_read_bytes(), 0);
+    });
+}
+
+#[test]
+pub fn decode_read_chunk_limit() {
+    let io = FixtureIo::empty()
+        .then_read(&b"\x00\x00\x00\x03abc\x00\x00\x00\x03def\x00\x00\x00\x03ghi"[..]);
+
+    let mut io = Builder::new()
+        .set_initial_buffer_capacity(64)
+        .set_read_chunk_limit(8)
+        .decoder(io);
+
+    in_task(|| {
+        assert_eq!(io.poll().unwrap(), Async::Ready(Some(BytesMut::from(&b"abc"[..]))));
+        assert_eq!(io.last_poll_read_bytes(), 8);
+        assert_eq!(io.buffered_len(), 1);
+    });
+
+    let chunks = collect(io).unwrap();
+    assert_eq!(chunks, bytes(&[b"def", b"ghi"]));
+}
+
+#[test]
+pub fn decode_length_counts_lines() {
+    let io = FixtureIo::empty()
+        .then_read(&b"\x00\x00\x00\x03one\ntw"[..])
+        .then_wait(ms(50))
+        .then_read(&b"o\nthree\n\x00\x00\x00\x01four\n"[..])
+        ;
+
+    let mut io = Builder::new().set_length_counts_lines(true).decoder(io);
+
+    in_task(|| {
+        assert!(io.poll().unwrap().is_not_ready());
+    });
+
+    // Progress is counted in lines
+    assert_eq!(io.current_frame_len(), Some(3));
+    assert_eq!(io.state(), DecoderState::AwaitingPayload { len: 3, received: 1 });
+
+    let chunks = collect(io).unwrap();
+    assert_eq!(chunks, bytes(&[b"one\ntwo\nthree\n", b"four\n"]));
+}
+
+#[test]
+pub fn length_ascii_round_trip() {
+    let builder = Builder::new().set_length_ascii(b':');
+
+    let io = builder.clone().encoder(vec![]);
+    let io = io.send(&b"abcdefghijkl"[..]).wait().unwrap();
+    let io = io.send(&b"xyz"[..]).wait().unwrap();
+
+    let data = io.into_inner();
+    assert_eq!(&data[..], &b"12:abcdefghijkl3:xyz"[..]);
+
+    let io = FixtureIo::empty()
+        .then_read(&b"1"[..])
+        .then_wait(ms(20))
+        .then_read(&b"2:abcdef"[..])
+        .then_wait(ms(20))
+        .then_read(&b"ghijkl3:xyz"[..])
+        ;
+
+    let chunks = collect(builder.clone().decoder(io)).unwrap();
+    assert_eq!(chunks, bytes(&[b"abcdefghijkl", b"xyz"]));
+
+    let io = FixtureIo::empty()
+        .then_read(&b"1x:abc"[..]);
+
+    let err = collect(builder.decoder(io)).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+pub fn decode_length_includes_header() {
+    let io = FixtureIo::empty()
+        .then_read(&b"\x00\x00\x00\x0dabcdefghi\x00\x00\x00\x04"[..]);
+
+    let io = Builder::new().set_length_includes_header(true).decoder(io);
+
+    let chunks = collect(io).unwrap();
+    assert_eq!(chunks, bytes(&[b"abcdefghi", b""]));
+}
+
+#[test]
+pub fn decode_length_includes_header_too_small() {
+    let io = FixtureIo::empty()
+        .then_read(&b"\x00\x00\x00\x02ab"[..]);
+
+    let io = Builder::new().set_length_includes_header(true).decoder(io);
+
+    let err = collect(io).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+pub fn decode_shared_memory_budget() {
+    let budget = MemoryBudget::new(8);
+
+    let a = FixtureIo::empty()
+        .then_read(&b"\x00\x00\x00\x06abc"[..])
+        .then_wait(ms(50))
+        .then_read(&b"def"[..])
+        ;
+
+    let b = FixtureIo::empty()
+        .then_read(&b"\x00\x00\x00\x05hello"[..]);
+
+    let mut a = Builder::new().set_memory_budget(budget.clone()).decoder(a);
+    let mut b = Builder::new().set_memory_budget(budget.clone()).decoder(b);
+
+    in_task(|| {
+        assert!(a.poll().unwrap().is_not_ready());
+        assert_eq!(budget.used(), 6);
+
+        // `a` holds most of the budget, so `b` may not read its payload
+        assert!(b.poll().unwrap().is_not_ready());
+        assert_eq!(budget.used(), 6);
+    });
+
+    let chunks = collect(a).unwrap();
+    assert_eq!(chunks, bytes(&[b"abcdef"]));
+    assert_eq!(budget.used(), 0);
+
+    let chunks = collect(b).unwrap();
+    assert_eq!(chunks, bytes(&[b"hello"]));
+    assert_eq!(budget.used(), 0);
+}
+
+#[test]
+pub fn decode_with_wire_len() {
+    let io = FixtureIo::empty()
+        .then_read(&b"\x00\x00\x00\x09abcdefghi\x00\x00"[..])
+        .then_read(&b"\x00\x03123"[..])
+        ;
+
+    let io = Decoder::default(io).with_wire_len();
+
+    let frames: Vec<_> = io.wait().map(|f| f.unwrap()).collect();
+    assert_eq!(frames, vec![
+        (13, BytesMut::from(&b"abcdefghi"[..])),
+        (7, BytesMut::from(&b"123"[..])),
+    ]);
+}
+
+#[test]
+pub fn decode_frames_with_headers() {
+    // A flags byte precedes the length field
+    let io = FixtureIo::empty()
+        .then_read(&b"\x01\x00\x03abc\x80\x00"[..])
+        .then_wait(ms(20))
+        .then_read(&b"\x02de"[..])
+        ;
+
+    let io = Builder::new()
+        .set_length_field_offset(1)
+        .set_length_field_length(2)
+        .decoder(io)
+        .into_frames_with_headers();
+
+    let frames = io.collect().wait().unwrap();
+    assert_eq!(frames.len(), 2);
+
+    assert_eq!(&frames[0].0[..], b"\x01\x00\x03");
+    assert_eq!(&frames[0].1[..], b"abc");
+
+    assert_eq!(frames[1].0[0] & 0x80, 0x80);
+    assert_eq!(&frames[1].1[..], b"de");
+}
+
+#[test]
+pub fn decode_tagged_frames() {
+    // A control frame between two data frames
+    let io = FixtureIo::empty()
+        .then_read(&b"\x01\x00\x00\x00\x03abc\x02\x00\x00"[..])
+        .then_wait(ms(20))
+        .then_read(&b"\x00\x00\x01\x00\x00\x00\x02de"[..])
+        ;
+
+    let io = Builder::new()
+        .set_tag_field(0, 1)
+        .set_length_field_offset(1)
+        .decoder(io)
+        .into_tagged_frames();
+
+    let frames = io.collect().wait().unwrap();
+    assert_eq!(frames.len(), 3);
+
+    assert_eq!(frames[0].0, 1);
+    assert_eq!(&frames[0].1[..], b"abc");
+
+    assert_eq!(frames[1].0, 2);
+    assert!(frames[1].1.is_empty());
+
+    assert_eq!(frames[2].0, 1);
+    assert_eq!(&frames[2].1[..], b"de");
+}
+
+#[test]
+pub fn decode_frozen_bytes() {
+    let io = FixtureIo::empty()
+        .then_read(&b"\x00\x00\x00\x09abcdefghi"[..]);
+
+    let mut io = Builder::new().decoder_bytes(io);
+
+    let frame = io.poll().unwrap();
+    let frame = match frame {
+        Async::Ready(Some(frame)) => frame,
+        _ => panic!("expected a frame"),
+    };
+
+    let other = frame.clone();
+    assert_eq!(frame, &b"abcdefghi"[..]);
+    assert_eq!(other, &b"abcdefghi"[..]);
+
+    // Clones share the same data
+    assert_eq!(frame.as_ptr(), other.as_ptr());
+}
+
+#[test]
+pub fn decode_map_frames() {
+    let io = FixtureIo::empty()
+        .then_read(&b"\x00\x00\x00\x0212\x00\x00\x00\x03345"[..])
+        .then_read(&b"\x00\x00\x00\x024x\x00\x00\x00\x0167"[..]);
+
+    let mut io = Decoder::default(io).map_frames(parse_digits).wait();
+
+    assert_eq!(io.next().unwrap().unwrap(), 12);
+    assert_eq!(io.next().unwrap().unwrap(), 345);
+
+    let err = io.next().unwrap().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+    // The stream ends after a parse error
+    assert!(io.next().is_none());
+}
+
+#[test]
+pub fn decode_shared_stream() {
+    let io = FixtureIo::empty()
+        .then_read(&b"\x00\x00\x00\x011\x00\x00\x00\x012"[..])
+        .then_wait(ms(20))
+        .then_read(&b"\x00\x00\x00\x013\x00\x00"[..])
+        .then_wait(ms(20))
+        .then_read(&b"\x00\x014\x00\x00\x00\x015"[..])
+        .then_wait(ms(20))
+        .then_read(&b"\x00\x00\x00\x016"[..])
+        ;
+
+    let frames = Decoder::default(io).into_shared_stream();
+
+    let workers: Vec<_> = (0..2).map(|_| {
+        let frames = frames.clone();
+        thread::spawn(move || collect(frames).unwrap())
+    }).collect();
+
+    drop(frames);
+
+    let mut all: Vec<Vec<u8>> = vec![];
+
+    for worker in workers {
+        all.extend(worker.join().unwrap().iter().map(|frame| frame.to_vec()));
+    }
+
+    all.sort();
+    assert_eq!(all, vec![b"1", b"2", b"3", b"4", b"5", b"6"]);
+}
+
+/*
+ *
+ * ===== Encoder =====
+ *
+ */
+
+#[test]
+pub fn encode_nothing_yields_nothing() {
+    let mut io = FixtureIo::empty();
+    let rx = io.receiver();
+    let io: Encoder<FixtureIo, &'static [u8]> = Encoder::default(io);
+
+    drop(io);
+    rx.recv().unwrap();
+}
+
+#[test]
+pub fn encode_single_frame_one_packet() {
+    let mut io = FixtureIo::empty()
+        .then_write(&b"\x00\x00\x00\x09abcdefghi"[..]);
+
+    let rx = io.receiver();
+    let io = Encoder::default(io);
+    let io = io.send(&b"abcdefghi"[..]).wait().unwrap();
+
+    drop(io);
+    rx.recv().unwrap();
+}
+
+#[test]
+pub fn encode_single_frame_one_packet_le() {
+    let mut io = FixtureIo::empty()
+        .then_write(&b"\x09\x00\x00\x00abcdefghi"[..]);
+
+    let rx = io.receiver();
+    let builder = Builder::new().set_byte_order(ByteOrder::LittleEndian);
+    let io = builder.encoder(io);
+    let io = io.send(&b"abcdefghi"[..]).wait().unwrap();
+
+    drop(io);
+    rx.recv().unwrap();
+}
+
+#[test]
+pub fn encode_single_multi_frame_one_packet() {
+    let mut data: Vec<u8> = vec![];
+    data.extend_from_slice(b"\x00\x00\x00\x09abcdefghi");
+    data.extend_from_slice(b"\x00\x00\x00\x03123");
+    data.extend_from_slice(b"\x00\x00\x00\x0bhello world");
+
+    let mut io = FixtureIo::empty()
+        .then_write(data);
+
+    let rx = io.receiver();
+    let io = Encoder::default(io);
+
+    let io = io.send(&b"abcdefghi"[..]).wait().unwrap();
+    let io = io.send(&b"123"[..]).wait().unwrap();
+    let io = io.send(&b"hello world"[..]).wait().unwrap();
+
+    drop(io);
+    rx.recv().unwrap();
+}
+
+#[test]
+pub fn encode_max_frame_size_exceeded() {
+    let mut io = FixtureIo::empty()
+        .then_write(&b"\x00\x00\x00\x09abcdefghi"[..]);
+
+    let rx = io.receiver();
+    let io = Builder::new().set_max_frame_length(8).encoder(io);
+    let io = io.send(&b"abcdefghi"[..]).wait();
+    assert!(io.is_err());
+}
+
+#[test]
+pub fn encode_empty_frames() {
+    let io = Encoder::default(vec![]);
+    let io = io.send(&b""[..]).wait().unwrap();
+    let io = io.send(&b"abc"[..]).wait().unwrap();
+
+    assert_eq!(&io.into_inner()[..], &b"\x00\x00\x00\x00\x00\x00\x00\x03abc"[..]);
+
+    let io = Builder::new()
+        .set_allow_empty_frames(false)
+        .encoder(vec![]);
+
+    match io.send(&b""[..]).wait() {
+        Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput),
+        Ok(_) => panic!("empty frame encoded"),
+    }
+}
+
+#[test]
+pub fn encode_message_oriented() {
+    let io = Builder::new()
+        .set_length_field_length(2)
+        .set_message_oriented(true)
+        .encoder(Datagrams { sent: vec![] });
+
+    let io = io.send(&b"abc"[..]).wait().unwrap();
+    let io = io.send(&b"hello"[..]).wait().unwrap();
+
+    assert_eq!(io.get_ref().sent, vec![b"\x00\x03abc".to_vec(), b"\x00\x05hello".to_vec()]);
+
+    // Otherwise a long payload may be split across writes
+    let payload = vec![b'a'; 300];
+
+    let io = Builder::new()
+        .set_length_field_length(2)
+        .encoder(Datagrams { sent: vec![] });
+
+    let io = io.send(&payload[..]).wait().unwrap();
+
+    let sent = &io.get_ref().sent;
+    assert_eq!(sent.len(), 2);
+    assert_eq!(&sent[0][..2], b"\x01\x2c");
+    assert_eq!(sent[0].len() + sent[1].len(), 302);
+}
+
+#[test]
+pub fn encode_passthrough() {
+    let mut io = FixtureIo::empty()
+        .then_write(&b"\x00\x00\x00\x03abc\x00\x00\x00\x02de"[..]);
+
+    let rx = io.receiver();
+    let io = Builder::new().set_passthrough(true).encoder(io);
+
+    let io = io.send(&b"\x00\x00\x00\x03abc"[..]).wait().unwrap();
+    let io = io.send(&b"\x00\x00\x00\x02de"[..]).wait().unwrap();
+
+    drop(io);
+    rx.recv().unwrap();
+
+    // Items are still limited to the max frame length
+    let io = Builder::new()
+        .set_passthrough(true)
+        .set_max_frame_length(6)
+        .encoder(vec![]);
+
+    assert!(io.send(&b"\x00\x00\x00\x03abc"[..]).wait().is_err());
+}
+
+#[test]
+pub fn native_endian_round_trip() {
+    let builder = Builder::new().set_byte_order(ByteOrder::NativeEndian);
+    let io = builder.encoder(vec![]);
+
+    let io = io.send(&b"abcdefghi"[..]).wait().unwrap();
+    let io = io.send(&b"123"[..]).wait().unwrap();
+
+    let data = io.into_inner();
+
+    let builder = Builder::new().set_byte_order(ByteOrder::NativeEndian);
+    let io = builder.decoder(io::Cursor::new(data));
+
+    let chunks = collect(io).unwrap();
+    assert_eq!(chunks, bytes(&[b"abcdefghi", b"123"]));
+}
+
+#[test]
+pub fn encode_length_includes_header() {
+    let mut io = FixtureIo::empty()
+        .then_write(&b"\x00\x00\x00\x0dabcdefghi"[..]);
+
+    let rx = io.receiver();
+    let io = Builder::new().set_length_includes_header(true).encoder(io);
+    let io = io.send(&b"abcdefghi"[..]).wait().unwrap();
+
+    drop(io);
+    rx.recv().unwrap();
+}
+
+#[test]
+pub fn content_hash_identical_payloads() {
+    let builder = Builder::new().set_content_hash(4);
+    let io = builder.clone().encoder(vec![]);
+
+    let io = io.send(&b"abcdefghi"[..]).wait().unwrap();
+    let io = io.send(&b"abcdefghi"[..]).wait().unwrap();
+    let io = io.send(&b"123"[..]).wait().unwrap();
+
+    let data = io.into_inner();
+
+    // Each frame is a 4 byte length, a 4 byte hash, then the payload
+    assert_eq!(&data[0..4], b"\x00\x00\x00\x09");
+    assert_eq!(&data[4..8], &data[21..25]);
+    assert!(&data[4..8] != &data[38..42]);
+
+    let mut io = builder.decoder(io::Cursor::new(data.clone()));
+    let mut hashes = vec![];
+
+    in_task(|| {
+        for _ in 0..3 {
+            assert!(io.poll().unwrap().is_ready());
+            hashes.push(io.content_hash().unwrap());
+        }
+    });
+
+    assert_eq!(hashes[0], hashes[1]);
+    assert!(hashes[0] != hashes[2]);
+}
+
+#[test]
+pub fn nonce_with_extended_length() {
+    let builder = Builder::new()
+        .set_length_field_length(1)
+        .set_extended_length(0xff, 2)
+        .set_nonce_field(1, 2);
+
+    let long = vec![b'x'; 300];
+
+    let io = builder.clone().encoder(vec![]);
+    let io = io.send(&b"abc"[..]).wait().unwrap();
+    let io = io.send(&long[..]).wait().unwrap();
+
+    let data = io.into_inner();
+    assert_eq!(&data[..6], b"\x03\x00\x00abc");
+
+    // The nonce follows the extended length
+    assert_eq!(&data[6..11], b"\xff\x01\x2c\x00\x01");
+
+    let chunks = collect(builder.decoder(io::Cursor::new(data))).unwrap();
+    assert_eq!(chunks, bytes(&[&b"abc"[..], &long[..]]));
+}
+
+#[test]
+pub fn nonce_rejects_replayed_frame() {
+    let builder = Builder::new().set_nonce_field(6, 2);
+
+    let io = builder.clone().encoder(vec![]);
+    let io = io.send(&b"abc"[..]).wait().unwrap();
+    let io = io.send(&b"defg"[..]).wait().unwrap();
+
+    let mut data = io.into_inner();
+    assert_eq!(&data[..11], b"\x00\x00\x00\x03\x00\x00\x00\x00abc");
+    assert_eq!(&data[11..], b"\x00\x00\x00\x04\x00\x00\x00\x01defg");
+
+    // Replay the second frame
+    let replay = data[11..].to_vec();
+    data.extend_from_slice(&replay);
+
+    let mut io = builder.decoder(io::Cursor::new(data)).wait();
+
+    assert_eq!(io.next().unwrap().unwrap(), BytesMut::from(&b"abc"[..]));
+    assert_eq!(io.next().unwrap().unwrap(), BytesMut::from(&b"defg"[..]));
+
+    let err = io.next().unwrap().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+    match Error::from_io(&err) {
+        Some(&Error::Replay { nonce: 1, last: 1 }) => {}
+        e => panic!("unexpected error; {:?}", e),
+    }
+}
+
+#[test]
+pub fn encode_flush_between_frames() {
+    let io = io::BufWriter::new(vec![]);
+    let io = Builder::new().set_flush_between_frames(true).encoder(io);
+
+    let io = io.send(&b"abcdefghi"[..]).wait().unwrap();
+    assert_eq!(&io.get_ref().get_ref()[..], &b"\x00\x00\x00\x09abcdefghi"[..]);
+
+    let io = io.send(&b"123"[..]).wait().unwrap();
+    assert_eq!(&io.get_ref().get_ref()[..], &b"\x00\x00\x00\x09abcdefghi\x00\x00\x00\x03123"[..]);
+}
+
+#[test]
+pub fn encode_without_flush_between_frames() {
+    let io = io::BufWriter::new(vec![]);
+    let io = Builder::new().encoder(io);
+
+    let io = io.send(&b"abcdefghi"[..]).wait().unwrap();
+    assert!(io.get_ref().get_ref().is_empty());
+}
+
+#[test]
+pub fn encode_flush() {
+    let io = io::BufWriter::new(vec![]);
+    let io = Builder::new().encoder(io);
+
+    // Written to the upstream, but still buffered there
+    let io = io.send(&b"abc"[..]).wait().unwrap();
+    assert!(io.get_ref().get_ref().is_empty());
+
+    let io = io.flush().wait().unwrap();
+    assert_eq!(&io.get_ref().get_ref()[..], &b"\x00\x00\x00\x03abc"[..]);
+}
+
+#[test]
+pub fn encode_length_field_offset() {
+    let builder = Builder::new()
+        .set_length_field_offset(2)
+        .set_length_field_length(2);
+
+    let io = builder.clone().encoder(vec![]);
+    let io = io.send(&b"abc"[..]).wait().unwrap();
+
+    let data = io.into_inner();
+    assert_eq!(&data[..], &b"\x00\x00\x00\x03abc"[..]);
+
+    let chunks = collect(builder.decoder(io::Cursor::new(data))).unwrap();
+    assert_eq!(chunks, bytes(&[b"abc"]));
+
+    // A fixed size header, with bytes following the length field
+    let builder = Builder::new()
+        .set_length_field_length(2)
+        .set_length_field_from_end(1)
+        .set_num_skip(5);
+
+    let io = builder.encoder(vec![]);
+    let io = io.send(&b"abc"[..]).wait().unwrap();
+    assert_eq!(&io.into_inner()[..], &b"\x00\x00\x00\x03\x00abc"[..]);
+}
+
+#[test]
+pub fn encode_length_field_prefix() {
+    let builder = Builder::new()
+        .set_length_field_offset(3)
+        .set_length_field_length(2)
+        .set_length_field_prefix(b"\xca\xfe");
+
+    let io = builder.clone().encoder(vec![]);
+    let io = io.send(&b"abc"[..]).wait().unwrap();
+    let io = io.send(&b"defgh"[..]).wait().unwrap();
+
+    let data = io.into_inner();
+    assert_eq!(&data[..], &b"\xca\xfe\x00\x00\x03abc\xca\xfe\x00\x00\x05defgh"[..]);
+
+    let chunks = collect(builder.decoder(io::Cursor::new(data))).unwrap();
+    assert_eq!(chunks, bytes(&[b"abc", b"defgh"]));
+}
+
+#[test]
+pub fn encode_wide_length_field() {
+    let io = Builder::new().set_length_field_length(12).encoder(vec![]);
+
+    let io = io.send(&b"abc"[..]).wait().unwrap();
+    assert_eq!(&io.get_ref()[..], &b"\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x03abc"[..]);
+
+    let io = Builder::new()
+        .set_length_field_length(12)
+        .set_byte_order(ByteOrder::LittleEndian)
+        .encoder(vec![]);
+
+    let io = io.send(&b"abc"[..]).wait().unwrap();
+    assert_eq!(&io.get_ref()[..], &b"\x03\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00abc"[..]);
+}
+
+#[test]
+pub fn encode_on_io() {
+    let events = Arc::new(Mutex::new(vec![]));
+    let events2 = events.clone();
+
+    let io = Builder::new()
+        .on_io(move |event| events2.lock().unwrap().push(event))
+        .encoder(vec![]);
+
+    let io = io.send(&b"abc"[..]).wait().unwrap();
+    assert_eq!(&io.get_ref()[..], &b"\x00\x00\x00\x03abc"[..]);
+
+    let events: Vec<_> = events.lock().unwrap().iter().map(|e| (e.kind, e.bytes)).collect();
+    assert_eq!(events, vec![(IoKind::Write, 7)]);
+}
+
+#[test]
+pub fn encode_partial_writes() {
+    let mut io = FixtureIo::empty()
+        .then_write(&b"\x00\x00"[..])
+        .then_write(&b"\x00\x03a"[..])
+        .then_wait(ms(50))
+        .then_write(&b"bc"[..])
+        ;
+
+    let rx = io.receiver();
+    let io = Encoder::default(io);
+    let io = io.send(&b"abc"[..]).wait().unwrap();
+
+    drop(io);
+    rx.recv().unwrap();
+}
+
+#[test]
+pub fn encode_one_byte_writes() {
+    let expect = b"\x00\x00\x00\x05hello\x00\x00\x00\x02hi";
+
+    let mut io = FixtureIo::empty();
+
+    // Accept a single byte per write, blocking halfway through each head
+    for (i, &b) in expect.iter().enumerate() {
+        if i == 2 || i == 11 {
+            io = io.then_wait(ms(20));
+        }
+
+        io = io.then_write(vec![b]);
+    }
+
+    let rx = io.receiver();
+    let io = Encoder::default(io);
+    let io = io.send(&b"hello"[..]).wait().unwrap();
+    let io = io.send(&b"hi"[..]).wait().unwrap();
+
+    drop(io);
+    rx.recv().unwrap();
+}
+
+#[test]
+pub fn encode_max_pending_frames() {
+    let events = Arc::new(Mutex::new(vec![]));
+    let events2 = events.clone();
+
+    let mut io = FixtureIo::empty()
+        .then_wait(ms(50))
+        .then_write(&b"\x00\x00\x00\x03abc\x00\x00\x00\x03def\x00\x00\x00\x03ghi"[..]);
+
+    let rx = io.receiver();
+
+    let mut io = Builder::new()
+        .set_max_pending_frames(2)
+        .on_io(move |event| events2.lock().unwrap().push(event))
+        .encoder(io);
+
+    in_task(|| {
+        assert_eq!(io.start_send(&b"abc"[..]).unwrap(), AsyncSink::Ready);
+
+        // Frames are still accepted while the first one is being written
+        assert!(!io.is_ready());
+        assert_eq!(io.start_send(&b"def"[..]).unwrap(), AsyncSink::Ready);
+        assert_eq!(io.start_send(&b"ghi"[..]).unwrap(), AsyncSink::Ready);
+        assert_eq!(io.pending_bytes(), 21);
+
+        // The queue is full
+        assert_eq!(io.start_send(&b"jkl"[..]).unwrap(), AsyncSink::NotReady(&b"jkl"[..]));
+    });
+
+    future::poll_fn(|| io.poll_complete()).wait().unwrap();
+
+    // The queued frames are written together
+    let events: Vec<_> = events.lock().unwrap().iter()
+        .filter(|e| !e.would_block)
+        .map(|e| e.bytes)
+        .collect();
+
+    // The first frame's head and payload go out in a single write
+    assert_eq!(events, vec![7, 14]);
+
+    drop(io);
+    rx.recv().unwrap();
+}
+
+#[test]
+pub fn encode_max_pending_frames_while_flushing() {
+    let data = Arc::new(Mutex::new(vec![]));
+
+    let io = SlowFlush {
+        data: data.clone(),
+        flushes: 0,
+    };
+
+    let mut io = Builder::new()
+        .set_flush_between_frames(true)
+        .set_max_pending_frames(2)
+        .encoder(io);
+
+    in_task(|| {
+        assert_eq!(io.start_send(&b"abc"[..]).unwrap(), AsyncSink::Ready);
+
+        // The frame is written, but the flush would block
+        assert!(io.poll_complete().unwrap().is_not_ready());
+
+        // Frames are not queued behind a frame being flushed
+        assert_eq!(io.start_send(&b"def"[..]).unwrap(), AsyncSink::NotReady(&b"def"[..]));
+    });
+
+    let io = io.send(&b"def"[..]).wait().unwrap();
+    assert!(io.is_ready());
+
+    assert_eq!(&data.lock().unwrap()[..], &b"\x00\x00\x00\x03abc\x00\x00\x00\x03def"[..]);
+}
+
+#[test]
+pub fn encode_shutdown_writes_pending_frame() {
+    let io = io::BufWriter::new(vec![]);
+    let mut io = Encoder::default(io);
+
+    in_task(|| {
+        assert_eq!(io.start_send(&b"abc"[..]).unwrap(), AsyncSink::Ready);
+    });
+
+    assert!(io.get_ref().get_ref().is_empty());
+
+    future::poll_fn(|| io.shutdown()).wait().unwrap();
+    assert_eq!(&io.get_ref().get_ref()[..], &b"\x00\x00\x00\x03abc"[..]);
+}
+
+#[test]
+pub fn encode_shutdown_write_closes_tcp_stream() {
+    let listener = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let sock = ::std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (mut peer, _) = listener.accept().unwrap();
+
+    let mut io = Encoder::default(sock);
+
+    in_task(|| {
+        assert_eq!(io.start_send(&b"abc"[..]).unwrap(), AsyncSink::Ready);
+    });
+
+    future::poll_fn(|| io.shutdown_write()).wait().unwrap();
+
+    // The peer reads EOF after the frame, while the encoder is still open
+    let mut data = vec![];
+    io::Read::read_to_end(&mut peer, &mut data).unwrap();
+    assert_eq!(&data[..], &b"\x00\x00\x00\x03abc"[..]);
+}
+
+#[test]
+pub fn encode_send_acked() {
+    let io = io::BufWriter::new(vec![]);
+    let mut io = Encoder::default(io);
+
+    let mut ack = in_task(|| io.send_acked(&b"abc"[..]).unwrap().ok().unwrap());
+
+    in_task(|| {
+        assert!(ack.poll().unwrap().is_not_ready());
+    });
+
+    assert!(io.get_ref().get_ref().is_empty());
+
+    future::poll_fn(|| io.poll_complete()).wait().unwrap();
+    assert_eq!(&io.get_ref().get_ref()[..], &b"\x00\x00\x00\x03abc"[..]);
+
+    ack.wait().unwrap();
+}
+
+#[test]
+pub fn encode_pad_to() {
+    let io = Builder::new()
+        .set_pad_to(16)
+        .set_pad_byte(b'x')
+        .encoder(vec![]);
+
+    let io = io.send(&b"abc"[..]).wait().unwrap();
+    let data = io.into_inner();
+
+    assert_eq!(&data[..], &b"\x00\x00\x00\x03abcxxxxxxxxxxxxx"[..]);
+
+    let io = Builder::new().set_pad_to(16).decoder(io::Cursor::new(data));
+
+    let chunks = collect(io).unwrap();
+    assert_eq!(chunks, bytes(&[b"abc"]));
+}
+
+#[test]
+pub fn encode_pad_to_frame_too_big() {
+    let io = Builder::new().set_pad_to(2).encoder(vec![]);
+    let err = io.send(&b"abc"[..]).wait().err().unwrap();
+
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+pub fn delta_length_round_trip() {
+    let io = Builder::new().set_delta_length(true).encoder(vec![]);
+
+    let io = io.send(&b"0123456789"[..]).wait().unwrap();
+    let io = io.send(&b"0123456789ab"[..]).wait().unwrap();
+    let io = io.send(&b"012345678"[..]).wait().unwrap();
+
+    let data = io.into_inner();
+
+    assert_eq!(&data[0..4], b"\x00\x00\x00\x0a");
+    assert_eq!(&data[14..18], b"\x00\x00\x00\x02");
+    assert_eq!(&data[30..34], b"\xff\xff\xff\xfd");
+
+    let io = Builder::new().set_delta_length(true).decoder(io::Cursor::new(data));
+
+    let chunks = collect(io).unwrap();
+    assert_eq!(chunks, bytes(&[b"0123456789", b"0123456789ab", b"012345678"]));
+}
+
+#[test]
+pub fn delta_length_rejected_frame() {
+    // A 4 bit delta in the low bits of the first byte
+    let builder = Builder::new()
+        .set_delta_length(true)
+        .set_length_field_bits(4, 4);
+
+    let mut io = builder.clone().encoder(vec![]);
+    let long = vec![b'x'; 40];
+
+    in_task(|| {
+        assert_eq!(io.start_send(&b"abc"[..]).unwrap(), AsyncSink::Ready);
+        assert!(io.poll_complete().unwrap().is_ready());
+
+        // The delta does not fit in the length field
+        assert!(io.start_send(&long[..]).is_err());
+
+        // The next delta is still relative to the last frame sent
+        assert_eq!(io.start_send(&b"defgh"[..]).unwrap(), AsyncSink::Ready);
+        assert!(io.poll_complete().unwrap().is_ready());
+    });
+
+    let data = io.into_inner();
+    assert_eq!(&data[..], &b"\x03abc\x02defgh"[..]);
+
+    let chunks = collect(builder.decoder(io::Cursor::new(data))).unwrap();
+    assert_eq!(chunks, bytes(&[b"abc", b"defgh"]));
+}
+
+#[test]
+pub fn encode_poll_complete_counted() {
+    let mut io = FixtureIo::empty()
+        .then_write(&b"\x00\x00"[..])
+        .then_wait(ms(50))
+        .then_write(&b"\x00\x09abcdefghi"[..])
+        ;
+
+    let rx = io.receiver();
+    let mut io = Encoder::default(io);
+
+    in_task(|| {
+        assert_eq!(io.start_send(&b"abcdefghi"[..]).unwrap(), AsyncSink::Ready);
+    });
+
+    let n = future::poll_fn(|| io.poll_complete_counted()).wait().unwrap();
+    assert_eq!(n, 13);
+
+    let n = in_task(|| io.poll_complete_counted().unwrap());
+    assert_eq!(n, Async::Ready(0));
+
+    drop(io);
+    rx.recv().unwrap();
+}
+
+#[test]
+pub fn encode_pending_write_bytes() {
+    let mut io = FixtureIo::empty()
+        .then_wait(ms(50))
+        .then_write(&b"\x00\x00\x00\x09abcdefghi\x00\x00\x00"[..])
+        ;
+
+    let rx = io.receiver();
+    let mut io = Builder::new().set_pad_to(12).encoder(io);
+    assert_eq!(io.pending_write_bytes(), 0);
+
+    // Nothing is accepted by the upstream yet
+    in_task(|| {
+        assert_eq!(io.start_send(&b"abcdefghi"[..]).unwrap(), AsyncSink::Ready);
+        assert!(!io.poll_complete().unwrap().is_ready());
+    });
+
+    assert_eq!(io.pending_write_bytes(), 16);
+
+    let _ = future::poll_fn(|| io.poll_complete()).wait();
+    assert_eq!(io.pending_write_bytes(), 0);
+
+    drop(io);
+    rx.recv().unwrap();
+}
+
+#[test]
+pub fn encode_is_ready_slow_upstream() {
+    let payload = vec![b'x'; 96];
+
+    let mut io = FixtureIo::empty()
+        .then_write(&b"\x00\x00\x00\x60"[..])
+        .then_wait(ms(20))
+        .then_write(&payload[..32])
+        .then_wait(ms(20))
+        .then_write(&payload[32..])
+        ;
+
+    let rx = io.receiver();
+    let mut io = Encoder::default(io);
+    assert!(io.is_ready());
+
+    let mut pending = vec![];
+
+    in_task(|| {
+        assert_eq!(io.start_send(&payload[..]).unwrap(), AsyncSink::Ready);
+        assert!(!io.is_ready());
+        pending.push(io.pending_bytes());
+    });
+
+    let _ = future::poll_fn(|| {
+        let res = io.poll_complete();
+        pending.push(io.pending_bytes());
+        res
+    }).wait().unwrap();
+
+    assert!(io.is_ready());
+
+    // Every poll writes what the upstream accepts
+    pending.dedup();
+    assert_eq!(pending, vec![100, 96, 64, 0]);
+
+    drop(io);
+    rx.recv().unwrap();
+}
+
+#[test]
+pub fn extended_length_round_trip() {
+    let builder = Builder::new()
+        .set_length_field_length(1)
+        .set_extended_length(0xff, 4);
+
+    let short = vec![b'a'; 254];
+    let long = vec![b'b'; 300];
+
+    let io = builder.clone().encoder(vec![]);
+    let io = io.send(&short[..]).wait().unwrap();
+    let io = io.send(&long[..]).wait().unwrap();
+
+    let data = io.into_inner();
+
+    // Below the threshold, only the primary field is used
+    assert_eq!(data[0], 254);
+
+    // Above the threshold, the sentinel is followed by the real length
+    assert_eq!(&data[255..260], b"\xff\x00\x00\x01\x2c");
+
+    let io = FixtureIo::empty()
+        .then_read(&data[..256])
+        .then_wait(ms(50))
+        .then_read(&data[256..])
+        ;
+
+    let io = builder.decoder(io);
+
+    let chunks = collect(io).unwrap();
+    assert_eq!(chunks, bytes(&[&short[..], &long[..]]));
+}
+
+#[test]
+pub fn extended_length_includes_header_round_trip() {
+    let builder = Builder::new()
+        .set_length_field_length(1)
+        .set_extended_length(0xff, 4)
+        .set_length_includes_header(true);
+
+    let short = vec![b'a'; 200];
+    let long = vec![b'b'; 300];
+
+    let io = builder.clone().encoder(vec![]);
+    let io = io.send(&short[..]).wait().unwrap();
+    let io = io.send(&long[..]).wait().unwrap();
+
+    let data = io.into_inner();
+
+    // The length counts the length field and the extended field
+    assert_eq!(data[0], 201);
+    assert_eq!(&data[201..206], b"\xff\x00\x00\x01\x31");
+
+    let io = builder.decoder(io::Cursor::new(data));
+
+    let chunks = collect(io).unwrap();
+    assert_eq!(chunks, bytes(&[&short[..], &long[..]]));
+}
+
+#[test]
+pub fn bitfield_length_round_trip() {
+    // A 12 bit length straddling the first two bytes, surrounded by 3 high
+    // and 1 low flag bits
+    let builder = Builder::new()
+        .set_bitfield_length(0, 3, 12)
+        .set_head_prefix(b"\xa0\x01");
+
+    let payload = vec![b'a'; 0x123];
+
+    let io = builder.clone().encoder(vec![]);
+    let io = io.send(&payload[..]).wait().unwrap();
+
+    let data = io.into_inner();
+    assert_eq!(&data[..2], b"\xa2\x47");
+    assert_eq!(data.len(), 2 + payload.len());
+
+    let io = FixtureIo::empty()
+        .then_read(&data[..1])
+        .then_wait(ms(50))
+        .then_read(&data[1..])
+        ;
+
+    let io = builder.clone().decoder(io);
+
+    let chunks = collect(io).unwrap();
+    assert_eq!(chunks, bytes(&[&payload[..]]));
+
+    // Lengths that do not fit in the bitfield are rejected
+    let too_big = vec![b'a'; 0x1000];
+    let io = builder.encoder(vec![]).send(&too_big[..]).wait();
+    assert!(io.is_err());
+}
+
+#[test]
+pub fn bracketed_length_round_trip() {
+    let builder = Builder::new()
+        .set_length_field_length(2)
+        .set_bracketed_length(true);
+
+    let io = builder.clone().encoder(vec![]);
+    let io = io.send(&b"abc"[..]).wait().unwrap();
+    let io = io.send(&b"defgh"[..]).wait().unwrap();
+
+    let data = io.into_inner();
+    assert_eq!(&data[..], &b"\x00\x03abc\x00\x03\x00\x05defgh\x00\x05"[..]);
+
+    let io = FixtureIo::empty()
+        .then_read(&data[..6])
+        .then_wait(ms(50))
+        .then_read(&data[6..])
+        ;
+
+    let chunks = collect(builder.decoder(io)).unwrap();
+    assert_eq!(chunks, bytes(&[b"abc", b"defgh"]));
+}
+
+#[test]
+pub fn bracketed_length_mismatch() {
+    let io = FixtureIo::empty()
+        .then_read(&b"\x00\x03abc\x00\x03\x00\x05defgh\x00\x04\x00\x02ij\x00\x02"[..]);
+
+    let mut io = Builder::new()
+        .set_length_field_length(2)
+        .set_bracketed_length(true)
+        .decoder(io)
+        .wait();
+
+    assert_eq!(io.next().unwrap().unwrap(), BytesMut::from(&b"abc"[..]));
+
+    let err = io.next().unwrap().unwrap_err();
+
+    match Error::from_io(&err) {
+        Some(&Error::LengthMismatch) => {}
+        e => panic!("unexpected error; {:?}", e),
+    }
+
+    // The next frame is decoded from its own head
+    assert_eq!(io.next().unwrap().unwrap(), BytesMut::from(&b"ij"[..]));
+    assert!(io.next().is_none());
+}
+
+#[test]
+pub fn crc32_trailer_round_trip() {
+    let builder = Builder::new()
+        .set_length_field_length(2)
+        .set_crc32_trailer(true);
+
+    let io = builder.clone().encoder(vec![]);
+    let io = io.send(&b"123456789"[..]).wait().unwrap();
+    let io = io.send(&b""[..]).wait().unwrap();
+
+    let data = io.into_inner();
+    assert_eq!(&data[..], &b"\x00\x09123456789\xcb\xf4\x39\x26\x00\x00\x00\x00\x00\x00"[..]);
+
+    let io = FixtureIo::empty()
+        .then_read(&data[..12])
+        .then_wait(ms(50))
+        .then_read(&data[12..])
+        ;
+
+    let chunks = collect(builder.decoder(io)).unwrap();
+    assert_eq!(chunks, bytes(&[b"123456789", b""]));
+}
+
+#[test]
+pub fn crc32_trailer_mixed_byte_order() {
+    let builder = Builder::new()
+        .set_length_field_length(2)
+        .set_crc32_trailer(true)
+        .set_trailer_byte_order(ByteOrder::LittleEndian);
+
+    let io = builder.clone().encoder(vec![]);
+    let io = io.send(&b"123456789"[..]).wait().unwrap();
+
+    // Big endian length, little endian trailer
+    let data = io.into_inner();
+    assert_eq!(&data[..], &b"\x00\x09123456789\x26\x39\xf4\xcb"[..]);
+
+    let chunks = collect(builder.decoder(io::Cursor::new(data))).unwrap();
+    assert_eq!(chunks, bytes(&[b"123456789"]));
+}
+
+#[test]
+pub fn crc32_trailer_mismatch() {
+    let io = FixtureIo::empty()
+        .then_read(&b"\x00\x09123456789\xcb\xf4\x39\x27\x00\x03abc\x35\x24\x41\xc2"[..]);
+
+    let budget = MemoryBudget::new(1_024);
+
+    let mut io = Builder::new()
+        .set_length_field_length(2)
+        .set_crc32_trailer(true)
+        .set_memory_budget(budget.clone())
+        .decoder(io)
+        .wait();
+
+    let err = io.next().unwrap().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+    match Error::from_io(&err) {
+        Some(&Error::TrailerMismatch) => {}
+        e => panic!("unexpected error; {:?}", e),
+    }
+
+    // The rejected frame is skipped, along with its charge
+    assert_eq!(budget.used(), 0);
+    assert_eq!(io.next().unwrap().unwrap(), BytesMut::from(&b"abc"[..]));
+    assert!(io.next().is_none());
+}
+
+#[test]
+pub fn length_field_bits_with_flags() {
+    // 4 high flag bits followed by a 12 bit length
+    let builder = Builder::new()
+        .set_length_field_bits(4, 12)
+        .set_head_prefix(b"\xa0\x00");
+
+    let long = vec![b'x'; 0x123];
+
+    let io = builder.clone().encoder(vec![]);
+    let io = io.send(&b"abc"[..]).wait().unwrap();
+    let io = io.send(&long[..]).wait().unwrap();
+
+    let data = io.into_inner();
+    assert_eq!(&data[..5], b"\xa0\x03abc");
+    assert_eq!(&data[5..7], b"\xa1\x23");
+
+    // The flags do not affect the decoded length
+    let io = FixtureIo::empty()
+        .then_read(&b"\x50\x02de"[..])
+        .then_wait(ms(50))
+        .then_read(&b"\xf0\x01f"[..])
+        ;
+
+    let chunks = collect(builder.decoder(io)).unwrap();
+    assert_eq!(chunks, bytes(&[b"de", b"f"]));
+}
+
+/*
+ *
+ * ===== Round trip =====
+ *
+ */
+
+#[test]
+#[cfg(feature = "testing")]
+pub fn roundtrip_big_endian() {
+    let builder = Builder::new().set_byte_order(ByteOrder::BigEndian);
+    testing::roundtrip(&builder, &[b"abcdefghi", b"", b"123", b"hello world"]);
+}
+
+#[test]
+#[cfg(feature = "testing")]
+pub fn roundtrip_little_endian() {
+    let builder = Builder::new()
+        .set_byte_order(ByteOrder::LittleEndian)
+        .set_length_field_length(2);
+
+    testing::roundtrip(&builder, &[b"abcdefghi", b"", b"123", b"hello world"]);
+}
+
+/*
+ *
+ * ===== Framed =====
+ *
+ */
+
+#[test]
+pub fn framed_read_then_write() {
+    let mut io = FixtureIo::empty()
+        .then_read(&b"\x00\x00\x00\x09abcdefghi"[..])
+        .then_write(&b"\x00\x00\x00\x03123"[..]);
+
+    let rx = io.receiver();
+    let io = Framed::default(io);
+
+    let (frame, io) = io.into_future().wait().map_err(|(e, _)| e).unwrap();
+    assert_eq!(frame.unwrap(), BytesMut::from(&b"abcdefghi"[..]));
+
+    let io = io.send(&b"123"[..]).wait().unwrap();
+
+    drop(io);
+    rx.recv().unwrap();
+}
+
+#[test]
+pub fn framed_split_halves() {
+    let written = Arc::new(Mutex::new(vec![]));
+
+    let io = Duplex {
+        rd: io::Cursor::new(b"\x00\x00\x00\x09abcdefghi\x00\x00\x00\x03123".to_vec()),
+        wr: written.clone(),
+    };
+
+    let (rd, wr) = Framed::default(io).split();
+
+    let reader = thread::spawn(move || {
+        let mut frames = vec![];
+        let mut rd = rd;
+
+        loop {
+            let (frame, next) = rd.into_future().wait().map_err(|(e, _)| e).unwrap();
+
+            match frame {
+                Some(frame) => frames.push(frame),
+                None => return (frames, next),
+            }
+
+            rd = next;
+        }
+    });
+
+    let writer = thread::spawn(move || {
+        let wr = wr.send(&b"hello"[..]).wait().unwrap();
+        wr.send(&b"world"[..]).wait().unwrap()
+    });
+
+    let (frames, rd) = reader.join().unwrap();
+    let wr = writer.join().unwrap();
+
+    assert_eq!(frames, bytes(&[b"abcdefghi", b"123"]));
+    assert_eq!(&written.lock().unwrap()[..], &b"\x00\x00\x00\x05hello\x00\x00\x00\x05world"[..]);
+
+    // The halves recombine into the original I/O
+    let io = Framed::unsplit(rd, wr).into_inner();
+    assert_eq!(io.rd.position(), 20);
+}
+
+#[test]
+pub fn framed_tcp_echo() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    // Echo the first frame back to the client
+    let server = listener.incoming().into_future()
+        .map_err(|(e, _)| e)
+        .and_then(|(sock, _)| {
+            let (sock, _) = sock.unwrap();
+            Builder::new().framed(sock).into_future().map_err(|(e, _)| e)
+        })
+        .and_then(|(frame, io)| io.send(frame.unwrap()));
+
+    let client = TcpStream::connect(&addr, &handle)
+        .and_then(|sock| Builder::new().framed(sock).send(BytesMut::from(&b"hello"[..])))
+        .and_then(|io| io.into_future().map_err(|(e, _)| e));
+
+    let (_, (frame, _)) = core.run(server.join(client)).unwrap();
+    assert_eq!(frame, Some(BytesMut::from(&b"hello"[..])));
+}
+
+#[test]
+pub fn reconnecting_resumes_on_new_connection() {
+    // The first connection drops in the middle of the second frame
+    let first = FixtureIo::empty()
+        .then_read(&b"\x00\x00\x00\x03abc\x00\x00\x00\x04de"[..]);
+
+    let second = FixtureIo::empty()
+        .then_read(&b"\x00\x00\x00\x04defg"[..])
+        .then_wait(ms(50))
+        .then_read(&b"\x00\x00\x00\x02hi"[..]);
+
+    let mut conns = vec![first, second].into_iter();
+    let mut connects = 0;
+
+    let io: Reconnecting<_, _, _, Vec<u8>> = reconnecting(move || {
+        connects += 1;
+        assert!(connects <= 2, "too many connection attempts");
+        future::ok(conns.next().unwrap())
+    }, Builder::new());
+
+    let chunks = collect(io).unwrap();
+    assert_eq!(chunks, bytes(&[b"abc", b"defg", b"hi"]));
+}
+
+#[test]
+pub fn round_robin_spreads_frames_evenly() {
+    let mut io = round_robin(vec![vec![], vec![], vec![]], Builder::new().set_length_field_length(1));
+
+    for frame in &[b"a", b"b", b"c", b"d", b"e", b"f"] {
+        io = io.send(&frame[..]).wait().unwrap();
+    }
+
+    let writers = io.into_inner();
+    assert_eq!(writers, vec![b"\x01a\x01d".to_vec(), b"\x01b\x01e".to_vec(), b"\x01c\x01f".to_vec()]);
+}
+
+#[test]
+pub fn write_stream_frames_every_payload() {
+    let mut io = FixtureIo::empty()
+        .then_write(&b"\x00\x03abc\x00"[..])
+        .then_wait(ms(20))
+        .then_write(&b"\x04defg\x00\x02hi"[..])
+        ;
+
+    let rx = io.receiver();
+
+    let payloads = stream::iter(vec![
+        Ok(BytesMut::from(&b"abc"[..])),
+        Ok(BytesMut::from(&b"defg"[..])),
+        Ok(BytesMut::from(&b"hi"[..])),
+    ]);
+
+    let builder = Builder::new().set_length_field_length(2);
+    let (_, io) = write_stream(payloads, io, builder).wait().unwrap();
+
+    drop(io);
+    rx.recv().unwrap();
+}
+
+/*
+ *
+ * ===== Util =====
+ *
+ */
+
+fn collect<T>(io: T) -> io::Result<Vec<T::Item>>
+    where T: Stream<Item = BytesMut, Error = io::Error>
+{
+    let mut ret = vec![];
+
+    for v in io.wait() {
+        ret.push(try!(v));
+    }
+
+    Ok(ret)
+}
+
+fn bytes(elems: &[&[u8]]) -> Vec<BytesMut> {
+    elems.iter()
+        .map(|&e| e.into())
+        .collect()
+}
+
+// An in-memory I/O object reading from a fixed buffer and recording writes
+// Records every write as a separate message
+struct Datagrams {
+    sent: Vec<Vec<u8>>,
+}
+
+impl Write for Datagrams {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sent.push(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// A writer whose first two flushes would block
+struct SlowFlush {
+    data: Arc<Mutex<Vec<u8>>>,
+    flushes: usize,
+}
+
+impl Write for SlowFlush {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.data.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flushes += 1;
+
+        if self.flushes <= 2 {
+            ::futures::task::park().unpark();
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "would block"));
+        }
+
+        Ok(())
+    }
+}
+
+struct Duplex {
+    rd: io::Cursor<Vec<u8>>,
+    wr: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Read for Duplex {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.rd.read(buf)
+    }
+}
+
+impl Write for Duplex {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.wr.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn assert_unexpected_eof(err: &io::Error) {
+    match Error::from_io(err) {
+        Some(&Error::UnexpectedEof) => {}
+        e => panic!("unexpected error; {:?}", e),
+    }
+}
+
+fn parse_digits(frame: BytesMut) -> io::Result<u64> {
+    let mut n = 0;
+
+    for &b in frame.iter() {
+        if b < b'0' || b > b'9' {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a digit"));
+        }
+
+        n = n * 10 + (b - b'0') as u64;
+    }
+
+    Ok(n)
+}
+
+fn assert_truncated_payload(err: &io::Error, data: &[u8], len: usize) {
+    match Error::from_io(err) {
+        Some(&Error::TruncatedPayload { ref received, expected }) => {
+            assert_eq!(&received[..], data);
+            assert_eq!(expected, len);
+        }
+        e => panic!("unexpected error; {:?}", e),
+    }
+}
+
+// Run `f` from within a task so that the I/O may be polled directly
+fn in_task<F, R>(f: F) -> R
+    where F: FnOnce() -> R,
+{
+    future::lazy(|| Ok::<R, ()>(f())).wait().unwrap()
 }
 
 fn ms(n: u64) -> Duration {
@@ -0,0 +1,192 @@
+extern crate futures;
+extern crate tokio_more;
+extern crate bytes;
+extern crate fixture_io;
+
+use tokio_more::codec::lines::*;
+use futures::{Stream, Sink, Future};
+use bytes::BytesMut;
+use fixture_io::FixtureIo;
+use std::io;
+use std::time::Duration;
+
+/*
+ *
+ * ===== Decoder =====
+ *
+ */
+
+#[test]
+pub fn decode_empty_io_yields_nothing() {
+    let io = FixtureIo::empty();
+    let io = Decoder::default(io);
+
+    let lines = collect(io).unwrap();
+    assert_eq!(lines, bytes(&[]));
+}
+
+#[test]
+pub fn decode_lines_one_packet() {
+    let io = FixtureIo::empty()
+        .then_read(&b"hello\nworld\n\n"[..]);
+
+    let io = Decoder::default(io);
+
+    let lines = collect(io).unwrap();
+    assert_eq!(lines, bytes(&[b"hello", b"world", b""]));
+}
+
+#[test]
+pub fn decode_lines_multi_packet() {
+    let io = FixtureIo::empty()
+        .then_read(&b"hel"[..])
+        .then_wait(ms(50))
+        .then_read(&b"lo\nwor"[..])
+        .then_read(&b"ld\n"[..])
+        ;
+
+    let io = Decoder::default(io);
+
+    let lines = collect(io).unwrap();
+    assert_eq!(lines, bytes(&[b"hello", b"world"]));
+}
+
+#[test]
+pub fn decode_unterminated_line_at_eof() {
+    let io = FixtureIo::empty()
+        .then_read(&b"hello\nwor"[..])
+        .then_wait(ms(50))
+        .then_read(&b"ld"[..])
+        ;
+
+    let io = Decoder::default(io);
+
+    let lines = collect(io).unwrap();
+    assert_eq!(lines, bytes(&[b"hello", b"world"]));
+}
+
+#[test]
+pub fn decode_crlf() {
+    let io = FixtureIo::empty()
+        .then_read(&b"hello\r"[..])
+        .then_wait(ms(50))
+        .then_read(&b"\nworld\r\n"[..])
+        ;
+
+    let io = Decoder::default(io);
+
+    let lines = collect(io).unwrap();
+    assert_eq!(lines, bytes(&[b"hello", b"world"]));
+}
+
+#[test]
+pub fn decode_crlf_not_stripped() {
+    let io = FixtureIo::empty()
+        .then_read(&b"hello\r\nworld\n"[..]);
+
+    let io = Builder::new().set_strip_cr(false).decoder(io);
+
+    let lines = collect(io).unwrap();
+    assert_eq!(lines, bytes(&[b"hello\r", b"world"]));
+}
+
+#[test]
+pub fn decode_max_line_length_exceeded() {
+    let io = FixtureIo::empty()
+        .then_read(&b"abc\nabcdefghi"[..])
+        .then_wait(ms(50))
+        .then_read(&b"\n"[..])
+        ;
+
+    let mut io = Builder::new().set_max_line_length(8).decoder(io).wait();
+
+    assert_eq!(io.next().unwrap().unwrap(), BytesMut::from(&b"abc"[..]));
+
+    let err = io.next().unwrap().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+pub fn decode_max_line_length_crlf_split() {
+    // The `\r` arrives before the newline, and is not counted once stripped
+    let io = FixtureIo::empty()
+        .then_read(&b"abcdefgh\r"[..])
+        .then_wait(ms(50))
+        .then_read(&b"\n"[..])
+        ;
+
+    let io = Builder::new().set_max_line_length(8).decoder(io);
+
+    let lines = collect(io).unwrap();
+    assert_eq!(lines, bytes(&[b"abcdefgh"]));
+}
+
+#[test]
+pub fn decode_unterminated_line_at_eof_stripped_and_checked() {
+    let io = FixtureIo::empty()
+        .then_read(&b"abc\r\nabcdefgh\r"[..]);
+
+    let io = Builder::new().set_max_line_length(8).decoder(io);
+
+    let lines = collect(io).unwrap();
+    assert_eq!(lines, bytes(&[b"abc", b"abcdefgh"]));
+
+    let io = FixtureIo::empty()
+        .then_read(&b"abc\nabcd"[..]);
+
+    let mut io = Builder::new().set_max_line_length(3).decoder(io).wait();
+
+    assert_eq!(io.next().unwrap().unwrap(), BytesMut::from(&b"abc"[..]));
+
+    let err = io.next().unwrap().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
+
+/*
+ *
+ * ===== Encoder =====
+ *
+ */
+
+#[test]
+pub fn encode_lines() {
+    let mut io = FixtureIo::empty()
+        .then_write(&b"hello\nworld\n"[..]);
+
+    let rx = io.receiver();
+    let io = Encoder::new(io);
+
+    let io = io.send(&b"hello"[..]).wait().unwrap();
+    let io = io.send(&b"world"[..]).wait().unwrap();
+
+    drop(io);
+    rx.recv().unwrap();
+}
+
+/*
+ *
+ * ===== Util =====
+ *
+ */
+
+fn collect<T>(io: T) -> io::Result<Vec<T::Item>>
+    where T: Stream<Item = BytesMut, Error = io::Error>
+{
+    let mut ret = vec![];
+
+    for v in io.wait() {
+        ret.push(try!(v));
+    }
+
+    Ok(ret)
+}
+
+fn bytes(elems: &[&[u8]]) -> Vec<BytesMut> {
+    elems.iter()
+        .map(|&e| e.into())
+        .collect()
+}
+
+fn ms(n: u64) -> Duration {
+    Duration::from_millis(n)
+}